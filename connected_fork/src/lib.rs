@@ -8,6 +8,8 @@
 
 // Types
 use std::os::fd::RawFd;
+#[cfg(not(target_os = "illumos"))]
+use std::{mem, ptr};
 
 // Traits
 use std::os::fd::FromRawFd;
@@ -16,14 +18,27 @@ use std::os::fd::AsRawFd;
 // Macros
 use errors::define_error_enum;
 
+#[cfg(target_os = "illumos")]
 pub struct RecvFd(illumos::stropts_h::strrecvfd);
 
+#[cfg(target_os = "illumos")]
 impl AsRawFd for RecvFd {
     fn as_raw_fd(&self) -> RawFd {
         self.0.fd
     }
 }
 
+/// Elsewhere, descriptors arrive as `SCM_RIGHTS` control messages, which carry no credentials.
+#[cfg(not(target_os = "illumos"))]
+pub struct RecvFd(RawFd);
+
+#[cfg(not(target_os = "illumos"))]
+impl AsRawFd for RecvFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
 pub struct PipeEnd {
     fd: RawFd
 }
@@ -76,6 +91,7 @@ impl PipeEnd {
         }
     }
 
+    #[cfg(target_os = "illumos")]
     pub fn send_fd(&mut self, fd: RawFd) -> Result<(), SendFdError> {
         match unsafe{ libc::ioctl(self.fd, libc::I_SENDFD, fd) } {
             0 => Ok(()),
@@ -89,6 +105,7 @@ impl PipeEnd {
         }
     }
 
+    #[cfg(target_os = "illumos")]
     pub fn recv_fd(&mut self) -> Result<RecvFd, RecvFdError> {
         let mut received: Vec<illumos::stropts_h::strrecvfd> = Vec::with_capacity(1);
         match unsafe{ libc::ioctl(self.fd, libc::I_RECVFD, received.as_mut_ptr()) } {
@@ -107,6 +124,86 @@ impl PipeEnd {
             }
         }
     }
+
+    /// Send a descriptor over a UNIX domain socket.
+    ///
+    /// There is no `I_SENDFD` outside of illumos, so we pass the descriptor as an `SCM_RIGHTS`
+    /// control message alongside a single byte of padding. Errors are mapped onto their STREAMS
+    /// equivalents so that callers need not care which platform they are on.
+    #[cfg(not(target_os = "illumos"))]
+    pub fn send_fd(&mut self, fd: RawFd) -> Result<(), SendFdError> {
+        let mut padding = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: padding.as_mut_ptr() as *mut libc::c_void,
+            iov_len: padding.len()
+        };
+        let space = unsafe{ libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+        let mut control = vec![0u8; space];
+
+        let mut message: libc::msghdr = unsafe{ mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = space as _;
+
+        unsafe{
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(header) as *mut RawFd, fd);
+        }
+
+        match unsafe{ libc::sendmsg(self.fd, &message, libc::MSG_NOSIGNAL) } {
+            1 => Ok(()),
+            _ => match illumos::errno() {
+                libc::EAGAIN => Err(SendFdError::EAGAIN),
+                libc::EBADF => Err(SendFdError::EBADF),
+                libc::EINVAL => Err(SendFdError::EINVAL),
+                _ => Err(SendFdError::ENXIO)
+            }
+        }
+    }
+
+    /// Receive a descriptor sent by [`PipeEnd::send_fd`].
+    #[cfg(not(target_os = "illumos"))]
+    pub fn recv_fd(&mut self) -> Result<RecvFd, RecvFdError> {
+        let mut padding = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: padding.as_mut_ptr() as *mut libc::c_void,
+            iov_len: padding.len()
+        };
+        let space = unsafe{ libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+        let mut control = vec![0u8; space];
+
+        let mut message: libc::msghdr = unsafe{ mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = space as _;
+
+        match unsafe{ libc::recvmsg(self.fd, &mut message, libc::MSG_CMSG_CLOEXEC) } {
+            -1 => match illumos::errno() {
+                libc::EAGAIN => Err(RecvFdError::EAGAIN),
+                libc::EFAULT => Err(RecvFdError::EFAULT),
+                libc::EMFILE => Err(RecvFdError::EMFILE),
+                _ => Err(RecvFdError::ENXIO)
+            },
+            // The other end hung up
+            0 => Err(RecvFdError::ENXIO),
+            _ => {
+                if message.msg_flags & libc::MSG_CTRUNC != 0 {
+                    return Err(RecvFdError::EOVERFLOW);
+                }
+                let header = unsafe{ libc::CMSG_FIRSTHDR(&message) };
+                if header.is_null() || unsafe{ (*header).cmsg_type } != libc::SCM_RIGHTS {
+                    return Err(RecvFdError::EBADMSG);
+                }
+                let fd = unsafe{ ptr::read_unaligned(libc::CMSG_DATA(header) as *const RawFd) };
+                Ok(RecvFd(fd))
+            }
+        }
+    }
 }
 
 impl Drop for PipeEnd {
@@ -127,9 +224,17 @@ pub enum PipeOpenError {
 }
 
 
+/// Create a bidirectional channel between a parent and its future child.
+///
+/// On illumos, pipes are STREAMS and can carry descriptors. Elsewhere we need a UNIX domain socket
+/// pair for that.
 pub fn pipe() -> Result<(PipeEnd,PipeEnd), PipeOpenError> {
     let mut fds: Vec<RawFd> = vec![0; 2];
-    match unsafe{ libc::pipe(fds.as_mut_ptr()) } {
+    #[cfg(target_os = "illumos")]
+    let result = unsafe{ libc::pipe(fds.as_mut_ptr()) };
+    #[cfg(not(target_os = "illumos"))]
+    let result = unsafe{ libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) };
+    match result {
         0 => {
             let child = unsafe{ PipeEnd::from_raw_fd(fds[0]) };
             let parent = unsafe{ PipeEnd::from_raw_fd(fds[1]) };
@@ -178,6 +283,50 @@ impl Fork {
     }
 }
 
+#[derive(Debug,PartialEq)]
+pub enum SetCredsError {
+    EPERM,
+    EINVAL,
+    /// The new credentials did not stick, or root could be regained afterwards
+    Reversible
+}
+
+impl SetCredsError {
+    fn from_errno() -> Self {
+        match illumos::errno() {
+            libc::EPERM => Self::EPERM,
+            _ => Self::EINVAL
+        }
+    }
+}
+
+/// Give up every privilege except those of `uid` and `gid`.
+///
+/// Supplementary groups go first, while we are still allowed to drop them, then the group, and
+/// only then the user. Afterwards, the real and effective ids are checked, and if we started out
+/// as root, we make sure that we cannot become root again.
+pub fn set_creds(uid: libc::uid_t, gid: libc::gid_t) -> Result<(), SetCredsError> {
+    let was_root = unsafe{ libc::geteuid() } == 0;
+    if was_root && unsafe{ libc::setgroups(1, &gid) } != 0 {
+        return Err(SetCredsError::from_errno());
+    }
+    if unsafe{ libc::setgid(gid) } != 0 {
+        return Err(SetCredsError::from_errno());
+    }
+    if unsafe{ libc::setuid(uid) } != 0 {
+        return Err(SetCredsError::from_errno());
+    }
+
+    let stuck = unsafe{
+        libc::getuid() == uid && libc::geteuid() == uid && libc::getgid() == gid && libc::getegid() == gid
+    };
+    let regained = was_root && uid != 0 && unsafe{ libc::setuid(0) } == 0;
+    match stuck && !regained {
+        true => Ok(()),
+        false => Err(SetCredsError::Reversible)
+    }
+}
+
 define_error_enum!(
     pub enum ConnectedForkError {
         PipeClose(PipeCloseError),
@@ -194,6 +343,10 @@ pub enum ConnectedFork {
 }
 
 impl ConnectedFork {
+    /// Fork a child which runs as `uid` and `gid`, connected to its parent by a [`pipe`].
+    ///
+    /// If the child cannot take on those credentials (see [`set_creds`]), it exits immediately
+    /// rather than carry on with the parent's.
     pub fn with_creds(uid: libc::uid_t, gid: libc::gid_t) -> Result<Self, ConnectedForkError> {
         let (parent, child) = pipe()?;
         match Fork::new()? {
@@ -203,9 +356,11 @@ impl ConnectedFork {
             },
             Fork::Child => {
                 drop(child);
-                if unsafe{ libc::setgid(gid) } != 0 { std::process::exit(illumos::errno()); }
-                if unsafe{ libc::setuid(uid) } != 0 { std::process::exit(illumos::errno()); }
-                Ok(Self::Child(parent))
+                match set_creds(uid, gid) {
+                    Ok(()) => Ok(Self::Child(parent)),
+                    Err(SetCredsError::EINVAL) => std::process::exit(libc::EINVAL),
+                    Err(_) => std::process::exit(libc::EPERM)
+                }
             }
         }
     }
@@ -255,4 +410,37 @@ mod tests {
 
         assert_eq!(&contents, "Hello, World!");
     }
+
+    #[test]
+    fn children_cannot_regain_root() {
+        if unsafe{ libc::geteuid() } != 0 {
+            return;
+        }
+        match ConnectedFork::with_creds(65534, 65534).unwrap() {
+            ConnectedFork::Child(_) => {
+                let regained = unsafe{ libc::setuid(0) } == 0 || unsafe{ libc::setgid(0) } == 0;
+                unsafe{ libc::_exit(regained as i32) };
+            },
+            ConnectedFork::Parent(pid, _) => {
+                let mut status = 0;
+                unsafe{ libc::waitpid(pid, &mut status, 0) };
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn keeping_our_own_creds_is_allowed() {
+        let uid = unsafe{ libc::getuid() };
+        let gid = unsafe{ libc::getgid() };
+        match ConnectedFork::with_creds(uid, gid).unwrap() {
+            ConnectedFork::Child(_) => unsafe{ libc::_exit(0) },
+            ConnectedFork::Parent(pid, _) => {
+                let mut status = 0;
+                unsafe{ libc::waitpid(pid, &mut status, 0) };
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+            }
+        }
+    }
 }
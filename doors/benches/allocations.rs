@@ -0,0 +1,100 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Count the allocations behind each kind of door call
+//!
+//! Every call is placed against two servers: one which builds a fresh `Vec` for its response, and
+//! one which answers with a `&'static` slice. The server runs in this process too, so the counts
+//! cover both ends of the call. Run it with `cargo bench -p doors`.
+
+// Types
+use doors::buffer::BufPool;
+use doors::{Client, Request, Response, Server};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The system allocator, but counting.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// A door procedure, and what to call it.
+type Named = (&'static str, fn(Request) -> Response);
+
+const CALLS: usize = 10_000;
+static PAYLOAD: [u8; 1024] = [0x2a; 1024];
+
+/// Place `CALLS` calls, and report how much each one allocated on average.
+fn measure(name: &str, mut call: impl FnMut()) {
+    // Let buffers and server threads settle before counting
+    for _ in 0..100 {
+        call();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = BYTES.load(Ordering::Relaxed);
+    for _ in 0..CALLS {
+        call();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let bytes = BYTES.load(Ordering::Relaxed) - bytes;
+    println!("{:<28} {:>8.2} allocations {:>10.1} bytes per call",
+        name, allocations as f64 / CALLS as f64, bytes as f64 / CALLS as f64);
+}
+
+fn main() {
+    let mut path = std::env::temp_dir();
+    path.push("doors_bench.6e0b94");
+    let path = path.to_str().unwrap().to_owned();
+
+    let servers: [Named; 2] = [
+        ("owned", |_| Response::new(PAYLOAD.to_vec())),
+        ("static", |_| Response::new(&PAYLOAD))
+    ];
+    for (kind, procedure) in servers {
+        let _server = Server::from_fn(&path, procedure).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        measure(&format!("call, {}", kind), || {
+            let (_, response) = client.call(vec![], b"").unwrap();
+            assert_eq!(response.len(), PAYLOAD.len());
+        });
+
+        let mut buffer = Vec::new();
+        measure(&format!("call_with_buffer, {}", kind), || {
+            client.call_with_buffer(vec![], b"", &mut buffer).unwrap();
+            assert_eq!(buffer.len(), PAYLOAD.len());
+        });
+
+        let mut pool = BufPool::default();
+        measure(&format!("call_into, {}", kind), || {
+            let (_, response) = client.call_into(vec![], b"", &mut pool).unwrap();
+            assert_eq!(response.len(), PAYLOAD.len());
+        });
+    }
+}
@@ -4,7 +4,6 @@ use errors::define_error_enum;
 
 // Traits
 use clap::Parser;
-use std::os::fd::FromRawFd;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -26,15 +25,15 @@ define_error_enum!(
 fn main() -> Result<(),MainError> {
     let cli = Cli::parse();
     let door_path = cli.door.unwrap_or(path::Path::new("/var/run/lsasd.door").to_path_buf());
-    let door_path_str = door_path.to_str().ok_or(io::Error::new(io::ErrorKind::Other, "invalid door path"))?;
+    let door_path_str = door_path.to_str().ok_or(io::Error::other("invalid door path"))?;
     let lsas_client = doors::Client::new(door_path_str)?;
-    let (desc, output) = lsas_client.call(vec![], b"alice")?;
-    if desc.len() == 0 {
+    let (mut desc, output) = lsas_client.call(vec![], b"alice")?;
+    if desc.is_empty() {
         eprintln!("error: {:?}", output);
         return Ok(());
     }
-    let ls_client = unsafe{ doors::Client::from_raw_fd(desc[0]) };
-    let (_desc, output) = ls_client.call(vec![], &vec![])?;
+    let ls_client = doors::Client::from(desc.remove(0));
+    let (_desc, output) = ls_client.call(vec![], &[])?;
     let output = String::from_utf8(output)?;
     println!("Contents of /home/alice: {}", output);
 
@@ -3,42 +3,31 @@ use std::env;
 use std::fs;
 use std::io;
 use std::path;
-use std::os::fd::RawFd;
+use std::os::fd::OwnedFd;
 use std::sync;
 use connected_fork::ConnectedFork;
 use doors::derive_server_procedure;
+use doors::{Descriptor, Request, Response, Server};
 use errors::define_error_enum;
 
 // Traits
 use clap::Parser;
 use doors::ServerProcedure;
 use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
 
-static mut DOOR_MAP: sync::RwLock<Option<HashMap<libc::uid_t,RawFd>>> = sync::RwLock::new(None);
-static DOOR_LOCK: sync::Once = sync::Once::new();
-
-
-fn su(_fds: &[RawFd], username: &[u8]) -> (Vec<RawFd>, Vec<u8>) {
-    DOOR_LOCK.call_once(|| {
-        let h = HashMap::new();
-        unsafe{ DOOR_MAP = sync::RwLock::new(Some(h)) }
-    });
-    let username = String::from_utf8(username.to_vec()).unwrap();
+/// Hand out a door to `ls` as the named user, forking a new server for them if need be.
+///
+/// `doors` maps each uid to the door of the server we have already forked for it.
+fn su(doors: &sync::RwLock<HashMap<libc::uid_t,OwnedFd>>, request: Request) -> Response {
+    let username = String::from_utf8(request.data.to_vec()).unwrap();
     let uid = match username.as_str() {
         "alice" => 102,
         "bob" => 103,
         _ => panic!(),
     };
-    match unsafe{
-        let maybe_map = DOOR_MAP.read().unwrap();
-        let map = maybe_map.as_ref().unwrap();
-        let x = map.get(&uid);
-        if x.is_none() {
-            None
-        } else {
-            Some(x.unwrap().clone())
-        }
-    } {
+    let existing = doors.read().unwrap().get(&uid).map(|door| door.try_clone().unwrap());
+    match existing {
         None => match ConnectedFork::with_creds(uid as libc::uid_t, uid as libc::uid_t).unwrap() {
             ConnectedFork::Child(mut parent) => {
                 // Child
@@ -53,30 +42,23 @@ fn su(_fds: &[RawFd], username: &[u8]) -> (Vec<RawFd>, Vec<u8>) {
             ConnectedFork::Parent(_pid, mut child) => {
                 // Parent
                 let creds = child.recv_fd().unwrap();
-                let fd = creds.as_raw_fd();
-                unsafe{
-                    let mut map = DOOR_MAP.write().unwrap();
-                    map.as_mut().expect("DMI").insert(uid, fd);
-                };
-                let fd2 = unsafe{ libc::dup(fd) };
-                (vec![fd2], vec![])
+                let door = unsafe{ OwnedFd::from_raw_fd(creds.as_raw_fd()) };
+                let copy = door.try_clone().unwrap();
+                doors.write().unwrap().insert(uid, door);
+                Response{ descriptors: vec![Descriptor::Release(copy)], ..Response::default() }
             }
         },
-        Some(door_fd) => {
-            let fd2 = unsafe{ libc::dup(door_fd) };
-            (vec![fd2], vec![])
-        }
+        Some(copy) => Response{ descriptors: vec![Descriptor::Release(copy)], ..Response::default() }
     }
 }
-derive_server_procedure!(su as Su);
 
-fn ls(_fds: &[RawFd], _data: &[u8]) -> (Vec<RawFd>, Vec<u8>) {
+fn ls(_fds: Vec<OwnedFd>, _data: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
     let mut entries = fs::read_dir(".").unwrap()
         .map(|res| res.map(|e| e.path()))
         .collect::<Result<Vec<_>, io::Error>>().unwrap();
     entries.sort();
     let strings = entries.iter()
-        .map(|e| e.to_str().ok_or(io::Error::new(io::ErrorKind::Other, "utf8error")))
+        .map(|e| e.to_str().ok_or(io::Error::other("utf8error")))
         .collect::<Result<Vec<&str>, io::Error>>().unwrap();
     let response = strings.join("\n");
     (vec![], response.into_bytes())
@@ -102,8 +84,9 @@ fn main() -> Result<(),MainError> {
     let cli = Cli::parse();
     let door_path = cli.door.unwrap_or(path::Path::new("/var/run/lsasd.door").to_path_buf());
     println!("LsasD is booting up!");
-    let door_path_str = door_path.to_str().ok_or(io::Error::new(io::ErrorKind::Other, "invalid door path"))?;
+    let door_path_str = door_path.to_str().ok_or(io::Error::other("invalid door path"))?;
     unsafe{ libc::daemon(1,1) };
-    let su_server = Su::install(door_path_str)?;
+    let doors = sync::RwLock::new(HashMap::new());
+    let su_server = Server::from_fn(door_path_str, move |request| su(&doors, request))?;
     su_server.park(); // No return from here
 }
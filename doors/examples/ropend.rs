@@ -1,17 +1,18 @@
 use std::io;
 use std::fs::File;
 use std::path;
-use std::os::fd::RawFd;
+use std::os::fd::OwnedFd;
 
+use doors::Descriptor;
 use doors::derive_server_procedure;
 use errors::define_error_enum;
 
 // Traits
 use clap::Parser;
 use doors::ServerProcedure;
-use std::os::fd::IntoRawFd;
+use std::os::fd::AsRawFd;
 
-fn open(_fds: &[RawFd], data: &[u8]) -> (Vec<RawFd>, Vec<u8>) {
+fn open(_fds: Vec<OwnedFd>, data: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
     match String::from_utf8(data.to_vec()) {
         Err(e) => (vec![], format!("ROpenD: {:?}", e).into_bytes()),
         Ok(file_path) => {
@@ -19,9 +20,8 @@ fn open(_fds: &[RawFd], data: &[u8]) -> (Vec<RawFd>, Vec<u8>) {
             match File::open(&file_path) {
                 Err(e) => (vec![], format!("ROpenD: {:?}", e).into_bytes()),
                 Ok(f) => {
-                    let raw = f.into_raw_fd();
-                    println!("Descriptor number: {}", raw);
-                    (vec![raw], vec![])
+                    println!("Descriptor number: {}", f.as_raw_fd());
+                    (vec![Descriptor::release(f)], vec![])
                 }
             }
         }
@@ -48,7 +48,7 @@ fn main() -> Result<(),MainError> {
     let cli = Cli::parse();
     let door_path = cli.door.unwrap_or(path::Path::new("/var/run/ropend.door").to_path_buf());
     println!("ROpenD is booting up!");
-    let door_path_str = door_path.to_str().ok_or(io::Error::new(io::ErrorKind::Other, "invalid door path"))?;
+    let door_path_str = door_path.to_str().ok_or(io::Error::other("invalid door path"))?;
     // unsafe{ libc::daemon(0,0) };
     let open_server = Open::install(door_path_str)?;
     open_server.park(); // No return from here
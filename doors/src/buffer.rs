@@ -0,0 +1,321 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Buffers for door calls which would rather not copy
+//!
+//! Both ends of a door call can avoid allocating (and copying) a fresh buffer for every response:
+//!
+//! * A client can place calls with [`ClientRef::call_into`](crate::ClientRef::call_into), which
+//!   reads each response into a buffer borrowed from a [`BufPool`]. The buffer goes back to the
+//!   pool when the [`Buf`] is dropped. On illumos, a response which is too big for the buffer is
+//!   read straight out of the memory the kernel mapped for it.
+//! * A server procedure can answer with any [`Body`]: an owned `Vec<u8>`, a `&'static` slice, or
+//!   a shared one, like a slice of an arena or a file mapped into memory with [`Mmap`].
+//!
+//! ```
+//! use doors::{Request, Response, Server};
+//! use doors::buffer::BufPool;
+//!
+//! static GREETING: &[u8] = b"Hello, whoever you are!";
+//! let _server = Server::from_fn("portunusd_test.3e19d7", |_: Request| Response::new(GREETING)).unwrap();
+//!
+//! let client = doors::Client::new("portunusd_test.3e19d7").unwrap();
+//! let mut pool = BufPool::default();
+//! let (_descriptors, greeting) = client.call_into(vec![], b"", &mut pool).unwrap();
+//! assert_eq!(&*greeting, GREETING);
+//! ```
+
+// Types
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::ptr;
+use std::sync::Arc;
+
+// Traits
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+
+
+/// How much room a new buffer has for a response.
+///
+/// Bigger responses are fine; they just cost a reallocation (or, on illumos, a mapping).
+pub const BUFFER_CAPACITY: usize = 4096;
+
+
+/// Spare buffers for [`ClientRef::call_into`](crate::ClientRef::call_into).
+///
+/// Buffers keep whatever capacity they have grown to, so after a few calls, responses no bigger
+/// than the ones which came before them are read without allocating at all.
+#[derive(Debug)]
+pub struct BufPool {
+    spare: Vec<Vec<u8>>,
+    capacity: usize
+}
+
+impl BufPool {
+    /// A pool whose new buffers have room for `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self{ spare: vec![], capacity }
+    }
+
+    /// A spare buffer, or a new one if there are none.
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        self.spare.pop().unwrap_or_else(|| Vec::with_capacity(self.capacity))
+    }
+
+    /// Put a buffer back, for the next call to use.
+    fn give(&mut self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        self.spare.push(buffer);
+    }
+}
+
+impl Default for BufPool {
+    fn default() -> Self {
+        Self::new(BUFFER_CAPACITY)
+    }
+}
+
+
+/// A response read by [`ClientRef::call_into`](crate::ClientRef::call_into).
+///
+/// It derefs to the response's bytes, and goes back to its pool when dropped.
+pub struct Buf<'a> {
+    pool: &'a mut BufPool,
+    buffer: Vec<u8>,
+    landing: Landing
+}
+
+impl<'a> Buf<'a> {
+    pub(crate) fn new(pool: &'a mut BufPool, buffer: Vec<u8>, landing: Landing) -> Self {
+        Self{ pool, buffer, landing }
+    }
+}
+
+impl Deref for Buf<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.landing {
+            Landing::Buffer => &self.buffer,
+            #[cfg(target_os = "illumos")]
+            Landing::Mapped(mapping) => mapping
+        }
+    }
+}
+
+impl fmt::Debug for Buf<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Buf").field("len", &self.len()).finish()
+    }
+}
+
+impl Drop for Buf<'_> {
+    fn drop(&mut self) {
+        self.pool.give(std::mem::take(&mut self.buffer));
+    }
+}
+
+
+/// Where the bytes of a response ended up.
+pub(crate) enum Landing {
+    /// In the buffer the caller passed in.
+    Buffer,
+    /// In memory the kernel mapped, since the caller's buffer was too small.
+    #[cfg(target_os = "illumos")]
+    Mapped(Mmap)
+}
+
+impl Landing {
+    /// Make sure the response is in `buffer`, copying it there if need be.
+    #[cfg_attr(not(target_os = "illumos"), allow(unused_variables, clippy::ptr_arg))]
+    pub(crate) fn copy_into(self, buffer: &mut Vec<u8>) {
+        match self {
+            Landing::Buffer => {},
+            #[cfg(target_os = "illumos")]
+            Landing::Mapped(mapping) => buffer.extend_from_slice(&mapping)
+        }
+    }
+}
+
+
+/// The bytes a server procedure sends back to its caller.
+///
+/// Only an `Owned` body costs an allocation. The others let a procedure answer with bytes it
+/// already has, without copying them first.
+pub enum Body {
+    Owned(Vec<u8>),
+    Static(&'static [u8]),
+    /// Bytes which somebody else is holding on to, like an arena, or an [`Mmap`].
+    Shared(Arc<dyn AsRef<[u8]> + Send + Sync>)
+}
+
+impl Deref for Body {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Body::Owned(bytes) => bytes,
+            Body::Static(bytes) => bytes,
+            Body::Shared(bytes) => (**bytes).as_ref()
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Static(&[])
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Body::Owned(_) => "Owned",
+            Body::Static(_) => "Static",
+            Body::Shared(_) => "Shared"
+        };
+        f.debug_struct(kind).field("len", &self.len()).finish()
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Owned(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Owned(text.into_bytes())
+    }
+}
+
+impl From<&'static [u8]> for Body {
+    fn from(bytes: &'static [u8]) -> Self {
+        Body::Static(bytes)
+    }
+}
+
+impl<const N: usize> From<&'static [u8; N]> for Body {
+    fn from(bytes: &'static [u8; N]) -> Self {
+        Body::Static(bytes)
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(text: &'static str) -> Self {
+        Body::Static(text.as_bytes())
+    }
+}
+
+impl From<Mmap> for Body {
+    fn from(mapping: Mmap) -> Self {
+        Body::Shared(Arc::new(mapping))
+    }
+}
+
+
+/// Memory mapped with `mmap(2)`, and unmapped when dropped.
+pub struct Mmap {
+    base: *mut libc::c_void,
+    size: usize,
+    data: *const u8,
+    length: usize
+}
+
+// The memory is read-only, and belongs to nobody else.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map all of `file` into memory, read-only.
+    ///
+    /// Writing to the file while it is mapped changes the mapped bytes too, so this is best kept
+    /// to files which don't change.
+    pub fn open(file: &File) -> io::Result<Self> {
+        let size = file.metadata()?.len() as usize;
+        if size == 0 {
+            // mmap refuses to map nothing at all
+            return Ok(Self{ base: ptr::null_mut(), size, data: ptr::NonNull::dangling().as_ptr(), length: 0 });
+        }
+        let base = unsafe{
+            libc::mmap(ptr::null_mut(), size, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self{ base, size, data: base as *const u8, length: size })
+    }
+
+    /// Take over a mapping of `size` bytes at `base`, whose interesting part is the `length` bytes
+    /// at `data`.
+    ///
+    /// # Safety
+    ///
+    /// The mapping must be readable, nobody else may unmap it, and `data` must lie within it.
+    #[cfg(target_os = "illumos")]
+    pub(crate) unsafe fn from_raw_parts(base: *mut libc::c_void, size: usize, data: *const u8, length: usize) -> Self {
+        Self{ base, size, data, length }
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe{ std::slice::from_raw_parts(self.data, self.length) }
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if !self.base.is_null() {
+            unsafe{ libc::munmap(self.base, self.size); }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn pools_hand_back_the_same_buffer() {
+        let mut pool = BufPool::new(64);
+        let buffer = pool.take();
+        assert_eq!(buffer.capacity(), 64);
+        let address = buffer.as_ptr();
+        pool.give(buffer);
+        let again = pool.take();
+        assert_eq!(again.as_ptr(), address);
+    }
+
+    #[test]
+    fn files_can_be_mapped() {
+        let mut path = std::env::temp_dir();
+        path.push("doors_buffer_test.75be1a");
+        let mut file = File::create(&path).unwrap();
+        write!(file, "mapped into memory").unwrap();
+
+        let body = Body::from(Mmap::open(&File::open(&path).unwrap()).unwrap());
+        assert_eq!(&*body, b"mapped into memory");
+
+        File::create(&path).unwrap();
+        let empty = Mmap::open(&File::open(&path).unwrap()).unwrap();
+        assert!(empty.is_empty());
+    }
+}
@@ -0,0 +1,341 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2021 Robert D. French
+ */
+//! illumos Doors Backend
+//!
+//! This is the real thing: every call is a [`DOOR_CALL(3C)`], and the kernel hands control
+//! directly to a thread in the application process.
+//!
+//! [`DOOR_CALL(3C)`]: https://illumos.org/man/3C/door_call
+
+use crate::Credentials;
+use crate::buffer::{Body, Landing, Mmap};
+use crate::Descriptor;
+use crate::Error;
+use crate::Procedure;
+use crate::Request;
+use crate::Server;
+use illumos::door_h::{
+    door_call,
+    door_create,
+    door_info,
+    door_info_t,
+    DOOR_DESCRIPTOR,
+    DOOR_REVOKED,
+    door_desc_t,
+    door_arg_t,
+    door_return,
+    door_ucred,
+};
+use illumos::stropts_h::{ fattach, fdetach };
+use illumos::ucred_h::{ ucred_t, ucred_geteuid, ucred_getegid, ucred_getpid, ucred_free };
+use illumos::errno;
+use std::cell::RefCell;
+use std::ffi;
+use std::fs::File;
+use std::os::fd::{BorrowedFd, OwnedFd};
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::fd::FromRawFd;
+use std::os::unix::io::IntoRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+
+/// Nothing extra to keep track of: the kernel runs the thread pool for us.
+pub(crate) struct Pool;
+
+
+thread_local! {
+    /// What this thread last passed to `door_return`.
+    ///
+    /// `door_return` never returns, so a response can't be dropped once it has been sent. Instead,
+    /// it is kept here, which also means the kernel can copy straight out of it, and dropped when
+    /// the thread answers its next call.
+    static RETURNED: RefCell<Option<(Vec<door_desc_t>, Body)>> = const { RefCell::new(None) };
+}
+
+
+/// Open a door on the filesystem.
+pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<OwnedFd,Error> {
+    let door = File::open(path)?;
+    Ok(door.into())
+}
+
+
+/// Invoke a door server procedure, blocking until it calls `door_return`.
+///
+/// The kernel writes the results into `response` if they fit in its capacity. If they don't, it
+/// maps a buffer of its own and points `rbuf` at that instead, which we hand back as an [`Mmap`]
+/// to be read (or copied) and unmapped. Either way, the data is not necessarily at the start of
+/// the buffer, and any descriptors follow it in the same buffer, with no promise of alignment.
+pub(crate) fn call(
+    door_descriptor: BorrowedFd<'_>,
+    descriptors: Vec<Descriptor<'_>>,
+    request: &[u8],
+    response: &mut Vec<u8>
+) -> Result<(Vec<OwnedFd>,Landing),Error> {
+    response.clear();
+    let rbuf = response.as_mut_ptr() as *const libc::c_char;
+    let rsize = response.capacity();
+
+    let mut door_descriptors: Vec<door_desc_t> = descriptors.into_iter().map(door_desc).collect();
+
+    let mut arg = door_arg_t {
+        data_ptr: request.as_ptr() as *const libc::c_char,
+        data_size: request.len(),
+        desc_ptr: door_descriptors.as_mut_ptr(),
+        desc_num: door_descriptors.len() as u32,
+        rbuf,
+        rsize
+    };
+
+    if unsafe{ door_call(door_descriptor.as_raw_fd(), &mut arg) } == -1 {
+        return Err(Error::DoorCall(errno()));
+    }
+
+    let out_fds: Vec<OwnedFd> = (0..arg.desc_num as usize).map(|i| unsafe{
+        let door_desc = ptr::read_unaligned(arg.desc_ptr.add(i));
+        OwnedFd::from_raw_fd(door_desc.as_raw_fd())
+    }).collect();
+
+    if arg.rbuf != rbuf {
+        let mapping = unsafe{
+            Mmap::from_raw_parts(arg.rbuf as *mut libc::c_void, arg.rsize, arg.data_ptr as *const u8, arg.data_size)
+        };
+        return Ok((out_fds, Landing::Mapped(mapping)));
+    }
+    if arg.data_size > 0 {
+        // The data is already in our buffer, so just slide it to the front
+        let offset = arg.data_ptr as usize - rbuf as usize;
+        unsafe{ response.set_len(offset + arg.data_size); }
+        response.drain(..offset);
+    }
+    Ok((out_fds, Landing::Buffer))
+}
+
+
+/// Invoke a door server procedure, but stop waiting for it after `timeout`.
+///
+/// `door_call` cannot be interrupted, so the call is placed from a helper thread, which is simply
+/// abandoned if the deadline passes. It goes away once the server procedure returns, and drops
+/// any descriptors in the response, since nobody is left to take them. The door descriptor and any
+/// borrowed descriptors are duplicated first, because the helper thread may outlive whatever they
+/// were borrowed from.
+pub(crate) fn call_with_timeout(
+    door_descriptor: BorrowedFd<'_>,
+    descriptors: Vec<Descriptor<'_>>,
+    request: &[u8],
+    timeout: Duration
+) -> Result<(Vec<OwnedFd>,Vec<u8>),Error> {
+    let duplicate = |e: std::io::Error| Error::DoorCall(e.raw_os_error().unwrap_or(libc::EBADF));
+    let door_descriptor = door_descriptor.try_clone_to_owned().map_err(duplicate)?;
+    let descriptors = descriptors.into_iter()
+        .map(|descriptor| descriptor.into_owned().map(Descriptor::Release))
+        .collect::<Result<Vec<_>,_>>()
+        .map_err(duplicate)?;
+    let (sender, receiver) = mpsc::channel();
+    let request = request.to_vec();
+    thread::spawn(move|| {
+        let mut response = vec![];
+        let result = call(door_descriptor.as_fd(), descriptors, &request, &mut response);
+        let _ = sender.send(result.map(|(descriptors, landing)| {
+            landing.copy_into(&mut response);
+            (descriptors, response)
+        }));
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(Error::DoorCall(libc::ETIMEDOUT))
+    }
+}
+
+
+/// Create a door for `procedure` and attach it to `path`.
+///
+/// The procedure rides along as the door's cookie. Revoking a door does not wait for calls which
+/// are already running, so the cookie is never freed: a revoked door leaks its procedure, rather
+/// than risk pulling it out from under a thread which is still answering a call. If installing
+/// fails, nobody can have called the door, so the procedure is dropped.
+pub(crate) fn install(path: &str, procedure: Procedure) -> Result<Server,Error> {
+    let jamb_path = ffi::CString::new(path)?;
+
+    // Create door. `Procedure` is a fat pointer, so box it again to get one that fits in a cookie.
+    let cookie = Box::into_raw(Box::new(procedure));
+    let door_descriptor = unsafe{ door_create(trampoline, cookie as *const libc::c_void, 0) };
+    if door_descriptor == -1 {
+        // Nobody can call a door which was never created
+        let e = errno();
+        drop(unsafe{ Box::from_raw(cookie) });
+        return Err(Error::CreateDoor(e));
+    }
+
+    // Create jamb
+    let create_new = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+    match unsafe{ libc::open(jamb_path.as_ptr(), create_new, 0400) } {
+        -1 => {
+            // Clean up the door, since we aren't going to finish. Nobody can have called it yet.
+            let e = errno();
+            unsafe{ libc::close(door_descriptor) };
+            drop(unsafe{ Box::from_raw(cookie) });
+            return Err(Error::InstallJamb(e))
+        },
+        jamb_descriptor => unsafe{ libc::close(jamb_descriptor); }
+    }
+
+    // Attach door to jamb
+    match unsafe{ fattach(door_descriptor, jamb_path.as_ptr()) } {
+        -1 => {
+            // Clean up the door and jamb, since we aren't going to finish. Nobody can have called
+            // the door yet.
+            let e = errno();
+            unsafe{ libc::close(door_descriptor) };
+            unsafe{ libc::unlink(jamb_path.as_ptr()); }
+            drop(unsafe{ Box::from_raw(cookie) });
+            Err(Error::AttachDoor(e))
+        },
+        _ => Ok(Server{ jamb_path, door_descriptor, pool: Pool })
+    }
+}
+
+
+/// Remove the jamb at `path` if nobody is answering it any more.
+///
+/// That is the case for a revoked door (its server has exited, or closed it), and for anything at
+/// `path` which is not a door at all, such as a jamb whose door was never attached.
+pub(crate) fn remove_stale(path: &str) -> Result<bool,Error> {
+    let jamb_path = ffi::CString::new(path)?;
+    let door = match File::open(path) {
+        Ok(door) => door,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(Error::OpenDoor(e))
+    };
+    let mut info = door_info_t::default();
+    if unsafe{ door_info(door.as_raw_fd(), &mut info) } == 0 && info.di_attributes & DOOR_REVOKED == 0 {
+        return Ok(false);
+    }
+    drop(door);
+
+    // Whatever is attached can go too, if anything is
+    unsafe{ fdetach(jamb_path.as_ptr()); }
+    if unsafe{ libc::unlink(jamb_path.as_ptr()) } == -1 {
+        return Err(Error::InstallJamb(errno()));
+    }
+    Ok(true)
+}
+
+
+/// Hand the current thread over to the kernel's door thread pool.
+pub(crate) fn park(_server: &Server) -> ! {
+    unsafe{ door_return(ptr::null(), 0, ptr::null(), 0); }
+}
+
+
+/// Ask the kernel who placed the call this thread is answering.
+pub(crate) fn caller() -> Result<Credentials,Error> {
+    let mut ucred: *mut ucred_t = ptr::null_mut();
+    if unsafe{ door_ucred(&mut ucred) } == -1 {
+        return Err(Error::Caller(errno()));
+    }
+    let credentials = unsafe{
+        Credentials {
+            uid: ucred_geteuid(ucred),
+            gid: ucred_getegid(ucred),
+            pid: match ucred_getpid(ucred) {
+                -1 => None,
+                pid => Some(pid)
+            }
+        }
+    };
+    unsafe{ ucred_free(ucred); }
+    Ok(credentials)
+}
+
+
+/// Detach the door, remove its jamb, and close it.
+pub(crate) fn revoke(server: &mut Server) {
+    // Stop new clients from getting a door descriptor
+    unsafe{ fdetach(server.jamb_path.as_ptr()); }
+    // Remove jamb from filesystem
+    unsafe{ libc::unlink(server.jamb_path.as_ptr()); }
+    // Stop existing clients from issuing new door_call()s
+    unsafe{ libc::close(server.door_descriptor); }
+}
+
+
+/// Describe a descriptor to the kernel.
+///
+/// Released descriptors are passed with `DOOR_RELEASE`, so the kernel closes them once they have
+/// been sent. Duplicated ones are left open.
+fn door_desc(descriptor: Descriptor<'_>) -> door_desc_t {
+    match descriptor {
+        Descriptor::Release(owned) => unsafe{ door_desc_t::from_raw_fd(owned.into_raw_fd()) },
+        Descriptor::Duplicate(borrowed) => {
+            let mut door_desc = unsafe{ door_desc_t::from_raw_fd(borrowed.as_raw_fd()) };
+            door_desc.d_attributes = DOOR_DESCRIPTOR;
+            door_desc
+        }
+    }
+}
+
+
+/// The server procedure for every door, with a signature that fits [`DOOR_CREATE(3C)`].
+///
+/// All it does is find the real procedure in the cookie, and let [`answer`] pack and unpack data
+/// so that the procedure doesn't have to deal with the doors api directly.
+///
+/// [`DOOR_CREATE(3C)`]: https://illumos.org/man/3C/door_create
+extern "C" fn trampoline(
+    cookie: *const libc::c_void,
+    argp: *const libc::c_char,
+    arg_size: libc::size_t,
+    dp: *const door_desc_t,
+    n_desc: libc::c_uint
+) {
+    let procedure = unsafe{ &*(cookie as *const Procedure) };
+    answer(procedure, argp, arg_size, dp, n_desc)
+}
+
+
+/// Unpack a door invocation, run the procedure, and `door_return` its response.
+fn answer(
+    procedure: &Procedure,
+    argp: *const libc::c_char,
+    arg_size: libc::size_t,
+    dp: *const door_desc_t,
+    n_desc: libc::c_uint
+) {
+    let request = unsafe{ std::slice::from_raw_parts(argp as *const u8, arg_size) };
+    let in_door_descriptors = unsafe{
+        std::slice::from_raw_parts::<door_desc_t>(dp, n_desc as usize)
+    };
+    let in_descriptors: Vec<OwnedFd> = in_door_descriptors.iter().map(|dd| {
+        unsafe{ OwnedFd::from_raw_fd(dd.as_raw_fd()) }
+    }).collect();
+
+    RETURNED.with(|returned| returned.borrow_mut().take());
+    let response = procedure(Request{ descriptors: in_descriptors, data: request });
+
+    let out_door_descriptors: Vec<door_desc_t> = response.descriptors.into_iter().map(door_desc).collect();
+
+    // Moving the response into RETURNED leaves its bytes where they are
+    let data_ptr = response.data.as_ptr();
+    let data_size = response.data.len();
+    let desc_ptr = out_door_descriptors.as_ptr();
+    let desc_size = out_door_descriptors.len();
+    RETURNED.with(|returned| *returned.borrow_mut() = Some((out_door_descriptors, response.data)));
+    unsafe{
+        door_return(
+            data_ptr as *const libc::c_char,
+            data_size,
+            desc_ptr,
+            desc_size as libc::c_uint
+        );
+    }
+}
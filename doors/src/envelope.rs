@@ -0,0 +1,268 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Request envelopes for the Door Protocol for Applications
+//!
+//! PortunusD prepends an [`Envelope`] to every request it forwards, so that applications can see
+//! who they are talking to, and how. The format is described in `etc/DPA.md`; in short:
+//!
+//! ```text
+//! +---------+-----------------+----------------------------+---------------+
+//! | version | length (u16 BE) | fields (`length` bytes)    | payload ...   |
+//! +---------+-----------------+----------------------------+---------------+
+//! ```
+//!
+//! Each field is a tag byte, a big-endian `u16` length, and that many bytes of value. Decoders
+//! skip tags they do not recognize, so new fields can be added without changing the version.
+//!
+//! ```
+//! use doors::envelope::{Envelope, Protocol};
+//!
+//! let envelope = Envelope {
+//!     protocol: Some(Protocol::Udp),
+//!     peer: Some("192.0.2.1:5353".parse().unwrap()),
+//!     ..Default::default()
+//! };
+//! let request = envelope.encode(b"ping");
+//!
+//! let (decoded, payload) = Envelope::decode(&request).unwrap();
+//! assert_eq!(decoded, envelope);
+//! assert_eq!(payload, b"ping");
+//! ```
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+
+/// The envelope version produced by [`Envelope::encode`], and the only one understood by
+/// [`Envelope::decode`].
+pub const VERSION: u8 = 1;
+
+/// The most an envelope can add to the front of a request: the version, the length of its fields,
+/// and as many bytes of fields as that length can describe.
+pub const MAX_LENGTH: usize = 3 + u16::MAX as usize;
+
+const PROTOCOL: u8 = 1;
+const PEER: u8 = 2;
+const LOCAL: u8 = 3;
+const SERVER_NAME: u8 = 4;
+
+
+/// Whether `request` is a health probe rather than real traffic.
+///
+/// With `set probe_interval`, PortunusD checks on each door by calling it with an empty request,
+/// which never carries an envelope or any descriptors. Applications must check for probes before
+/// reaching for a descriptor, and should answer them promptly; any response will do.
+pub fn is_probe(request: &[u8]) -> bool {
+    request.is_empty()
+}
+
+
+/// The kind of listener a request arrived on.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Protocol {
+    Tcp = 1,
+    Udp = 2,
+    Tls = 3,
+    Http = 4,
+    Https = 5
+}
+
+impl Protocol {
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::Tcp),
+            2 => Some(Self::Udp),
+            3 => Some(Self::Tls),
+            4 => Some(Self::Http),
+            5 => Some(Self::Https),
+            _ => None
+        }
+    }
+}
+
+
+/// What PortunusD knows about a request, apart from the request itself.
+///
+/// Every field is optional. Fields which PortunusD could not determine are left out of the
+/// encoding, as are fields which this version of the crate does not recognize.
+#[derive(Debug,PartialEq,Eq,Clone,Default)]
+pub struct Envelope {
+    /// The kind of listener the request arrived on.
+    pub protocol: Option<Protocol>,
+    /// The client's address.
+    pub peer: Option<SocketAddr>,
+    /// The address of the listener which received the request.
+    pub local: Option<SocketAddr>,
+    /// The server name the client asked for during the TLS handshake (SNI).
+    pub server_name: Option<String>
+}
+
+
+/// Envelope problems.
+#[derive(Debug,PartialEq)]
+pub enum EnvelopeError {
+    /// The request ended in the middle of the envelope.
+    Truncated,
+    /// The envelope was written by an incompatible version of PortunusD.
+    UnsupportedVersion(u8),
+    /// A field had the wrong length, or invalid contents. Contains the field's tag.
+    Malformed(u8)
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "Envelope is truncated"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported envelope version: {}", version),
+            Self::Malformed(tag) => write!(f, "Malformed envelope field: {}", tag)
+        }
+    }
+}
+
+
+impl Envelope {
+    /// Prepend this envelope to `payload`, producing a request ready for a door call.
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut fields = vec![];
+        if let Some(protocol) = self.protocol {
+            push_field(&mut fields, PROTOCOL, &[protocol as u8]);
+        }
+        if let Some(peer) = self.peer {
+            push_field(&mut fields, PEER, &encode_address(peer));
+        }
+        if let Some(local) = self.local {
+            push_field(&mut fields, LOCAL, &encode_address(local));
+        }
+        if let Some(server_name) = &self.server_name {
+            push_field(&mut fields, SERVER_NAME, server_name.as_bytes());
+        }
+
+        let mut request = Vec::with_capacity(3 + fields.len() + payload.len());
+        request.push(VERSION);
+        request.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        request.extend_from_slice(&fields);
+        request.extend_from_slice(payload);
+        request
+    }
+
+    /// Split a request into its envelope and its payload.
+    pub fn decode(request: &[u8]) -> Result<(Self, &[u8]), EnvelopeError> {
+        let version = *request.first().ok_or(EnvelopeError::Truncated)?;
+        if version != VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        let length = read_u16(request, 1)? as usize;
+        let fields = request.get(3..3 + length).ok_or(EnvelopeError::Truncated)?;
+        let payload = &request[3 + length..];
+
+        let mut envelope = Self::default();
+        let mut offset = 0;
+        while offset < fields.len() {
+            let tag = fields[offset];
+            let length = read_u16(fields, offset + 1)? as usize;
+            let value = fields.get(offset + 3..offset + 3 + length).ok_or(EnvelopeError::Truncated)?;
+            match tag {
+                PROTOCOL => match value {
+                    [code] => envelope.protocol = Protocol::from_code(*code),
+                    _ => return Err(EnvelopeError::Malformed(tag))
+                },
+                PEER => envelope.peer = Some(decode_address(tag, value)?),
+                LOCAL => envelope.local = Some(decode_address(tag, value)?),
+                SERVER_NAME => {
+                    let server_name = std::str::from_utf8(value).map_err(|_| EnvelopeError::Malformed(tag))?;
+                    envelope.server_name = Some(server_name.to_owned());
+                },
+                _ => {} // Something newer than us, so skip it
+            }
+            offset += 3 + length;
+        }
+
+        Ok((envelope, payload))
+    }
+}
+
+
+fn push_field(fields: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    fields.push(tag);
+    fields.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    fields.extend_from_slice(value);
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, EnvelopeError> {
+    match bytes.get(offset..offset + 2) {
+        Some(&[high, low]) => Ok(u16::from_be_bytes([high, low])),
+        _ => Err(EnvelopeError::Truncated)
+    }
+}
+
+/// Addresses are a big-endian port followed by 4 (IPv4) or 16 (IPv6) address bytes.
+fn encode_address(address: SocketAddr) -> Vec<u8> {
+    let mut bytes = address.port().to_be_bytes().to_vec();
+    match address.ip() {
+        IpAddr::V4(ip) => bytes.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => bytes.extend_from_slice(&ip.octets())
+    }
+    bytes
+}
+
+fn decode_address(tag: u8, value: &[u8]) -> Result<SocketAddr, EnvelopeError> {
+    let port = read_u16(value, 0).map_err(|_| EnvelopeError::Malformed(tag))?;
+    let ip = match &value[2..] {
+        &[a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        octets if octets.len() == 16 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(octets);
+            IpAddr::V6(Ipv6Addr::from(ip))
+        },
+        _ => return Err(EnvelopeError::Malformed(tag))
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn everything_makes_the_round_trip() {
+        let envelope = Envelope {
+            protocol: Some(Protocol::Https),
+            peer: Some("[2001:db8::1]:50123".parse().unwrap()),
+            local: Some("192.0.2.7:443".parse().unwrap()),
+            server_name: Some("portunusd.net".to_owned())
+        };
+        let request = envelope.encode(b"GET / HTTP/1.1\r\n\r\n");
+        let (decoded, payload) = Envelope::decode(&request).unwrap();
+        assert_eq!(decoded, envelope);
+        assert_eq!(payload, b"GET / HTTP/1.1\r\n\r\n");
+
+        let request = Envelope::default().encode(&[]);
+        assert_eq!(request, vec![VERSION, 0, 0]);
+        assert_eq!(Envelope::decode(&request).unwrap(), (Envelope::default(), &[][..]));
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let mut request = vec![VERSION, 0, 9];
+        request.extend_from_slice(&[99, 0, 2, 0xAB, 0xCD]);
+        request.extend_from_slice(&[PROTOCOL, 0, 1, 2]);
+        request.extend_from_slice(b"payload");
+        let (envelope, payload) = Envelope::decode(&request).unwrap();
+        assert_eq!(envelope.protocol, Some(Protocol::Udp));
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn bad_envelopes_are_rejected() {
+        assert_eq!(Envelope::decode(&[]), Err(EnvelopeError::Truncated));
+        assert_eq!(Envelope::decode(&[2, 0, 0]), Err(EnvelopeError::UnsupportedVersion(2)));
+        assert_eq!(Envelope::decode(&[VERSION, 0, 4, PEER, 0]), Err(EnvelopeError::Truncated));
+        assert_eq!(Envelope::decode(&[VERSION, 0, 6, PEER, 0, 3, 0, 80, 1]), Err(EnvelopeError::Malformed(PEER)));
+    }
+}
@@ -9,7 +9,13 @@
 //!
 //! In PortunusD, every incoming connection is forwarded to an external application via
 //! [illumos Doors][1]. You can use the `derive_server_procedure!` macro defined in this module to
-//! convert a `Fn: &[u8] -> Vec<u8>` function into a PortunusD function handler.
+//! convert a `Fn: &[u8] -> Vec<u8>` function into a PortunusD function handler. Handlers which
+//! need state of their own, like configuration or a database handle, can be closures passed to
+//! [`Server::from_fn`] instead.
+//!
+//! Doors are unique to illumos. On other platforms, this crate emulates them with a UNIX domain
+//! socket at the door's path, so that applications can be developed and tested anywhere. The API is
+//! the same either way, but only illumos gets the performance.
 //!
 //! Below is an example of an application that accepts a user's name in the request body and
 //! returns a polite greeting:
@@ -18,10 +24,11 @@
 //! use doors::derive_server_procedure;
 //! use std::fmt::format;
 //! use std::str::from_utf8;
-//! use std::os::fd::RawFd;
+//! use std::os::fd::OwnedFd;
+//! use doors::Descriptor;
 //!
 //! // Consider the function `hello`, which returns a polite greeting to a client:
-//! fn hello(_: &[RawFd], request: &[u8]) -> (Vec<RawFd>, Vec<u8>) {
+//! fn hello(_: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
 //!     match from_utf8(request) {
 //!         Err(_) => (vec![], b"I couldn't understand your name!".to_vec()),
 //!         Ok(name) => {
@@ -47,83 +54,99 @@
 //!
 //! [1]: https://github.com/robertdfrench/revolving-door
 
-use illumos::door_h::{
-    door_call,
-    door_create,
-    door_desc_t,
-    door_arg_t,
-    door_return,
-};
-use illumos::stropts_h::{ fattach, fdetach };
-use illumos::errno;
-use libc;
+pub mod buffer;
+pub mod envelope;
+
+#[cfg(target_os = "illumos")]
+mod door;
+#[cfg(target_os = "illumos")]
+use door as backend;
+#[cfg(not(target_os = "illumos"))]
+mod socket;
+#[cfg(not(target_os = "illumos"))]
+use socket as backend;
+
 use std::ffi;
 use std::fmt;
-use std::fs::File;
-use std::os::fd::RawFd;
-use std::os::fd::AsRawFd;
+use std::io;
+use std::os::fd::{BorrowedFd, OwnedFd, RawFd};
+use std::os::fd::{AsFd, AsRawFd};
 use std::os::fd::FromRawFd;
 use std::os::unix::io::IntoRawFd;
 use std::path::Path;
-use std::ptr;
-use std::slice;
+use std::time::Duration;
 
 
-/// A borrowable door client
+/// The biggest payload a single request or response may carry.
 ///
-/// Many threads may need to call the same door application. In order to facilitate this, we
-/// bend some ownership concepts a little by introducing a derivative type called `ClientRef`. This
-/// type has a separate copy of the door descriptor, and can make calls to the door application
-/// independently of the `Client` from which it was derived.
+/// Only the socket emulation enforces this, since real doors have no such limit. Anything which
+/// has to buffer a response for somebody else can use it as a sensible bound all the same.
+pub const MAX_PAYLOAD: u64 = 64 * 1024 * 1024;
+
+
+/// A borrowed door client
 ///
-/// # Caveat
+/// Many threads may need to call the same door application. A `ClientRef` borrows the door
+/// descriptor from a `Client`, so it can be copied into as many threads as you like (with
+/// [`std::thread::scope`], for example), but it cannot outlive the `Client` which produced it.
+/// Threads which need to hold on to a door for longer than that can share the `Client` itself,
+/// with an `Arc`.
 ///
-/// There is no lifetime association between a `ClientRef` and the `Client` which produced it. If
-/// the `Client` is dropped, the door descriptor will be closed, and the `ClientRef` will no longer
-/// be able to place door calls.
+/// ```compile_fail
+/// # let door_path = std::env::temp_dir().join("portunusd_test.e2b40c");
+/// let client = doors::Client::new(&door_path).unwrap();
+/// let client_ref = client.borrow();
+/// drop(client);
+/// client_ref.call(vec![], b"hello"); // The door descriptor is already closed
+/// ```
 #[derive(Clone,Copy)]
-pub struct ClientRef {
-    door_descriptor: libc::c_int
+pub struct ClientRef<'a> {
+    door_descriptor: BorrowedFd<'a>
 }
 
-impl ClientRef {
+impl ClientRef<'_> {
     /// Invoke door server procedure.
     ///
     /// This is intended to be called from a dedicated thread. It will block until the server
     /// procedure calls `door_return`. 
-    pub fn call(&self, raw_fds: Vec<RawFd>, request: &[u8]) -> Result<(Vec<RawFd>,Vec<u8>),Error> {
-        let mut response = Vec::with_capacity(1024);
-        // the vector has length zero, so rsize is zero, so the overflow handling gets triggered
-        // which fucks up alignment so data_ptr > rbuf
-
-        let mut door_descriptors: Vec<door_desc_t> = raw_fds.into_iter().map(|raw| {
-            unsafe { door_desc_t::from_raw_fd(raw) }
-        }).collect();
-
-        let mut arg = door_arg_t {
-            data_ptr: request.as_ptr() as *const i8,
-            data_size: request.len(),
-            desc_ptr: door_descriptors.as_mut_ptr(),
-            desc_num: door_descriptors.len() as u32,
-            rbuf: response.as_mut_ptr() as *const i8,
-            rsize: response.len()
-        };
-
-        if unsafe{ door_call(self.door_descriptor, &mut arg) } == -1 {
-            return Err(Error::DoorCall(errno()));
-        }
+    pub fn call(&self, descriptors: Vec<Descriptor<'_>>, request: &[u8]) -> Result<(Vec<OwnedFd>,Vec<u8>),Error> {
+        let mut response = Vec::with_capacity(buffer::BUFFER_CAPACITY);
+        let descriptors = self.call_with_buffer(descriptors, request, &mut response)?;
+        Ok((descriptors, response))
+    }
 
-        unsafe{ response.set_len(arg.data_size); }
+    /// Invoke door server procedure, and put the response in `response`.
+    ///
+    /// Whatever was in `response` is replaced. Responses which fit in its capacity are written
+    /// there directly, so a buffer which is reused from one call to the next is only reallocated
+    /// when a response turns out to be bigger than any before it. On illumos, a response which
+    /// doesn't fit arrives in a buffer mapped by the kernel instead, and is copied out of it.
+    pub fn call_with_buffer(&self, descriptors: Vec<Descriptor<'_>>, request: &[u8], response: &mut Vec<u8>) -> Result<Vec<OwnedFd>,Error> {
+        let (descriptors, landing) = backend::call(self.door_descriptor, descriptors, request, response)?;
+        landing.copy_into(response);
+        Ok(descriptors)
+    }
 
-        let slice = unsafe{ std::slice::from_raw_parts(arg.data_ptr as *const u8, arg.data_size) };
-        let dslice = unsafe{
-            std::slice::from_raw_parts(arg.desc_ptr as *const door_desc_t, arg.desc_num as usize)
-        };
-        let out_fds: Vec<RawFd> = dslice.iter().map(|door_desc| {
-            door_desc.as_raw_fd()
-        }).collect();
+    /// Invoke door server procedure, and read the response into a buffer from `pool`.
+    ///
+    /// The buffer goes back to the pool when the returned [`Buf`](buffer::Buf) is dropped, so a
+    /// pool which is reused from one call to the next saves allocating a buffer for every
+    /// response. On illumos, a response which doesn't fit in the buffer is not copied at all: the
+    /// `Buf` refers to the memory which the kernel mapped for it, and unmaps it when dropped.
+    pub fn call_into<'p>(&self, descriptors: Vec<Descriptor<'_>>, request: &[u8], pool: &'p mut buffer::BufPool) -> Result<(Vec<OwnedFd>,buffer::Buf<'p>),Error> {
+        let mut response = pool.take();
+        let (descriptors, landing) = backend::call(self.door_descriptor, descriptors, request, &mut response)?;
+        Ok((descriptors, buffer::Buf::new(pool, response, landing)))
+    }
 
-        Ok((out_fds,slice.to_vec()))
+    /// Invoke door server procedure, but give up after `timeout`.
+    ///
+    /// A call which takes too long fails with `Error::DoorCall(ETIMEDOUT)` (see
+    /// [`Error::timed_out`]). The server procedure is not interrupted, so it may still be running
+    /// after this returns. On illumos, where `door_call` cannot be cancelled, the call is placed
+    /// from a helper thread which lives until the server procedure returns.
+    pub fn call_with_timeout(&self, descriptors: Vec<Descriptor<'_>>, request: &[u8], timeout: Duration) -> Result<(Vec<OwnedFd>,Vec<u8>),Error> {
+        backend::call_with_timeout(self.door_descriptor, descriptors, request, timeout)
     }
 }
 
@@ -134,10 +157,13 @@ impl ClientRef {
 /// of a ServerProcedure -- it is PortunusD's way of accessing your application, much like a file
 /// handle is a means of accessing the bytes which make up a file.
 ///
+/// A `Client` is `Send` and `Sync`, and any number of threads may call it at once. The door
+/// descriptor is closed when the `Client` is dropped.
+///
 /// [Door]: https://github.com/robertdfrench/revolving-door#revolving-doors
 /// [`ServerProcedure`]: trait.ServerProcedure.html
 pub struct Client {
-    door_descriptor: libc::c_int
+    door_descriptor: OwnedFd
 }
 
 impl Client {
@@ -146,8 +172,7 @@ impl Client {
     /// This may fail if the door does not exist, if the path is not a door, or if some other
     /// terrible thing has happened.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self,Error> {
-        let door = File::open(path)?;
-        let door_descriptor = door.into_raw_fd();
+        let door_descriptor = backend::open(path)?;
         Ok(Self{ door_descriptor })
     }
 
@@ -156,24 +181,31 @@ impl Client {
     /// Forwad a slice of bytes through a door to a PortunusD application. If successful, the
     /// resulting `Vec<u8>` will contain the bytes returned from the application's server
     /// procedure.
-    pub fn call(&self, raw_fds: Vec<RawFd>, request: &[u8]) -> Result<(Vec<RawFd>,Vec<u8>),Error> {
+    pub fn call(&self, descriptors: Vec<Descriptor<'_>>, request: &[u8]) -> Result<(Vec<OwnedFd>,Vec<u8>),Error> {
         let cr = self.borrow();
-        cr.call(raw_fds, request)
+        cr.call(descriptors, request)
     }
 
-    /// A copy of the door descriptor that can be called from another thread
-    ///
-    /// WARNING: Nothing stops the `Client` from going out of scope without invalidating associated
-    /// `ClientRef` objects. Use with caution.
-    pub fn borrow(&self) -> ClientRef {
-        ClientRef{ door_descriptor: self.door_descriptor }
+    /// Like [`Client::call`], but reuse `response`. See [`ClientRef::call_with_buffer`].
+    pub fn call_with_buffer(&self, descriptors: Vec<Descriptor<'_>>, request: &[u8], response: &mut Vec<u8>) -> Result<Vec<OwnedFd>,Error> {
+        self.borrow().call_with_buffer(descriptors, request, response)
     }
-}
 
-impl Drop for Client {
-    /// Close PortunusD's connection the remote application
-    fn drop(&mut self) {
-        unsafe{ libc::close(self.door_descriptor); }
+    /// Like [`Client::call`], but read the response into a buffer from `pool`. See
+    /// [`ClientRef::call_into`].
+    pub fn call_into<'p>(&self, descriptors: Vec<Descriptor<'_>>, request: &[u8], pool: &'p mut buffer::BufPool) -> Result<(Vec<OwnedFd>,buffer::Buf<'p>),Error> {
+        self.borrow().call_into(descriptors, request, pool)
+    }
+
+    /// Like [`Client::call`], but give up after `timeout`. See [`ClientRef::call_with_timeout`].
+    pub fn call_with_timeout(&self, descriptors: Vec<Descriptor<'_>>, request: &[u8], timeout: Duration) -> Result<(Vec<OwnedFd>,Vec<u8>),Error> {
+        self.borrow().call_with_timeout(descriptors, request, timeout)
+    }
+
+    /// A handle to the door which can be copied into other threads, for as long as this `Client`
+    /// is around.
+    pub fn borrow(&self) -> ClientRef<'_> {
+        ClientRef{ door_descriptor: self.door_descriptor.as_fd() }
     }
 }
 
@@ -181,7 +213,8 @@ impl Drop for Client {
 /// A server procedure which has been attached to the filesystem.
 pub struct Server {
     jamb_path: ffi::CString,
-    pub door_descriptor: libc::c_int
+    pub door_descriptor: libc::c_int,
+    pool: backend::Pool
 }
 
 impl IntoRawFd for Server {
@@ -190,15 +223,28 @@ impl IntoRawFd for Server {
     }
 }
 
+impl AsFd for Client {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.door_descriptor.as_fd()
+    }
+}
+
 impl IntoRawFd for Client {
     fn into_raw_fd(self) -> RawFd {
-        self.door_descriptor as RawFd
+        self.door_descriptor.into_raw_fd()
     }
 }
 
 impl FromRawFd for Client {
     unsafe fn from_raw_fd(raw: RawFd) -> Self {
-        Self{ door_descriptor: raw }
+        Self{ door_descriptor: OwnedFd::from_raw_fd(raw) }
+    }
+}
+
+impl From<OwnedFd> for Client {
+    /// Call a door descriptor which arrived in a response.
+    fn from(door_descriptor: OwnedFd) -> Self {
+        Self{ door_descriptor }
     }
 }
 
@@ -215,6 +261,7 @@ pub enum Error {
     OpenDoor(std::io::Error),
     DoorCall(libc::c_int),
     CreateDoor(libc::c_int),
+    Caller(libc::c_int),
 }
 
 impl fmt::Display for Error {
@@ -226,11 +273,19 @@ impl fmt::Display for Error {
             Self::AttachDoor(errno) => write!(f, "Could not attach door: {}", errno),
             Self::OpenDoor(e) => write!(f, "Could not open door: {}", e),
             Self::DoorCall(errno) => write!(f, "Could not call door: {}", errno),
-            Self::CreateDoor(errno) => write!(f, "Could not create door: {}", errno)
+            Self::CreateDoor(errno) => write!(f, "Could not create door: {}", errno),
+            Self::Caller(errno) => write!(f, "Could not identify caller: {}", errno)
         }
     }
 }
 
+impl Error {
+    /// Whether this is a door call which took longer than its timeout.
+    pub fn timed_out(&self) -> bool {
+        matches!(self, Self::DoorCall(libc::ETIMEDOUT))
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(other: std::io::Error) -> Self {
         Self::OpenDoor(other)
@@ -244,13 +299,49 @@ impl From<ffi::NulError> for Error {
 }
 
 impl Server {
+    /// Make a closure available on the filesystem (as a door).
+    ///
+    /// Unlike a [`ServerProcedure`], the closure can carry state: configuration, caches, database
+    /// handles, or anything else which is `Send + Sync`. It is called from many threads at once,
+    /// so mutable state needs a `Mutex` or an atomic.
+    ///
+    /// ```
+    /// use doors::{Request, Response};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let greeting = String::from("Hello");
+    /// let visitors = AtomicUsize::new(0);
+    /// let server = doors::Server::from_fn("portunusd_test.9b1f52", move |request: Request| {
+    ///     let count = visitors.fetch_add(1, Ordering::SeqCst) + 1;
+    ///     let name = String::from_utf8_lossy(request.data);
+    ///     Response::new(format!("{}, {}! You are visitor number {}.", greeting, name, count))
+    /// }).unwrap();
+    ///
+    /// let client = doors::Client::new("portunusd_test.9b1f52").unwrap();
+    /// let (_descriptors, greeting) = client.call(vec![], b"Portunus").unwrap();
+    /// assert_eq!(greeting, b"Hello, Portunus! You are visitor number 1.");
+    /// ```
+    pub fn from_fn<F>(path: &str, procedure: F) -> Result<Server,Error>
+    where F: Fn(Request<'_>) -> Response + Send + Sync + 'static {
+        backend::install(path, Box::new(procedure))
+    }
+
+    /// Remove the jamb at `path`, if it was left behind by a server which is no longer answering.
+    ///
+    /// A server which crashes, or which gives up the privileges it needs to remove its own jamb,
+    /// leaves the jamb in place, and installing another server at the same path fails until it is
+    /// gone. A door which is still answering is left alone. Returns whether anything was removed.
+    pub fn remove_stale(path: &str) -> Result<bool,Error> {
+        backend::remove_stale(path)
+    }
+
     /// Hand the current thread over to the door pool.
     ///
     /// This is useful when an application has finished starting up, and we'd like to put the
     /// "main" thread into the thread pool available to door clients. Only use this if there is no
     /// meaningful work for a "main" thread to be doing when the application is otherwise idle.
     pub fn park(&self) -> ! {
-        unsafe{ door_return(ptr::null(), 0, ptr::null(), 0); }
+        backend::park(self)
     }
 }
 
@@ -261,99 +352,145 @@ impl Drop for Server {
     /// descriptors that client processes may have. This will prevent PortunusD from forwarding
     /// additional requests to your application.
     fn drop(&mut self) {
-        // Stop new clients from getting a door descriptor
-        unsafe{ fdetach(self.jamb_path.as_ptr()); }
-        // Remove jamb from filesystem
-        unsafe{ libc::unlink(self.jamb_path.as_ptr()); }
-        // Stop existing clients from issuing new door_call()s
-        unsafe{ libc::close(self.door_descriptor); }
+        backend::revoke(self)
     }
 }
 
 
-/// Trait for types derived from the `define_server_procedure!` macro.
+/// Who placed a door call.
 ///
-/// Because `define_server_procedure!` creates a new type to "host" each server procedure, we need
-/// to define a trait so we can work with these types generically.
+/// The ids are the caller's *effective* user and group ids.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    /// Not every platform can tell us the caller's process id.
+    pub pid: Option<libc::pid_t>
+}
+
+/// Find out who placed the door call currently being answered.
 ///
-pub trait ServerProcedure {
+/// This only works from within a server procedure, on the thread which is answering the call.
+/// Applications can use it to decide whether to honor a request.
+pub fn caller() -> Result<Credentials,Error> {
+    backend::caller()
+}
 
-    /// This is the part you define.  The function body you give in `define_server_procedure!` will
-    /// end up as the definition of this `rust` function, which will be called by the associated
-    /// `c_wrapper` function in this trait.
-    fn rust_wrapper(descriptors: &[RawFd], request: &[u8]) -> (Vec<RawFd>, Vec<u8>);
 
-    /// This is a wrapper that fits the Doors API All it does is pack and unpack data so that our
-    /// server procedure doesn't have to deal with the doors api directly. Its unusual signature
-    /// comes directly from [`DOOR_CREATE(3C)`].
-    ///
-    /// [`DOOR_CREATE(3C)`]: https://illumos.org/man/3C/door_create
-    extern "C" fn c_wrapper(
-        _cookie: *const libc::c_void,
-        argp: *const libc::c_char,
-        arg_size: libc::size_t,
-        dp: *const door_desc_t,
-        n_desc: libc::c_uint
-    ) {
-        let request = unsafe{ slice::from_raw_parts(argp as *const u8, arg_size) };
-        let in_door_descriptors = unsafe{
-            slice::from_raw_parts::<door_desc_t>(dp, n_desc as usize)
-        };
-        let in_raw_descriptors: Vec<RawFd> = in_door_descriptors.iter().map(|dd| {
-            dd.as_raw_fd()
-        }).collect();
+/// A descriptor to send along with a door call or its response.
+///
+/// The variant decides who is responsible for closing it afterwards.
+#[derive(Debug)]
+pub enum Descriptor<'a> {
+    /// Hand the descriptor over, and close our copy once it has been sent (`DOOR_RELEASE`).
+    Release(OwnedFd),
+    /// Send a duplicate of the descriptor, and leave ours open.
+    Duplicate(BorrowedFd<'a>)
+}
 
-        let (out_raw_descriptors, response) = Self::rust_wrapper(&in_raw_descriptors, request);
+impl Descriptor<'_> {
+    /// Hand over anything which owns a descriptor, like a `File` or a `TcpStream`.
+    pub fn release<D: Into<OwnedFd>>(descriptor: D) -> Descriptor<'static> {
+        Descriptor::Release(descriptor.into())
+    }
 
-        let out_door_descriptors: Vec<door_desc_t> = out_raw_descriptors.into_iter().map(|raw| {
-            unsafe{ door_desc_t::from_raw_fd(raw) }
-        }).collect();
+    /// A descriptor we own, duplicating a borrowed one if need be.
+    ///
+    /// This is useful when a descriptor has to outlive whatever it was borrowed from, like when a
+    /// call is placed from another thread.
+    pub fn into_owned(self) -> io::Result<OwnedFd> {
+        match self {
+            Descriptor::Release(owned) => Ok(owned),
+            Descriptor::Duplicate(borrowed) => borrowed.try_clone_to_owned()
+        }
+    }
+}
 
-        let data_ptr = response.as_ptr();
-        let data_size = response.len();
-        let desc_ptr = out_door_descriptors.as_ptr();
-        let desc_size = out_door_descriptors.len();
-        unsafe{
-            door_return(
-                data_ptr as *const libc::c_char,
-                data_size,
-                desc_ptr,
-                desc_size as libc::c_uint
-            );
+impl AsFd for Descriptor<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Descriptor::Release(owned) => owned.as_fd(),
+            Descriptor::Duplicate(borrowed) => borrowed.as_fd()
         }
     }
+}
 
-    /// Make this procedure available on the filesystem (as a door).
-    fn install(path: &str) -> Result<Server,Error> {
-        let jamb_path = ffi::CString::new(path)?;
+impl AsRawFd for Descriptor<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
 
-        // Create door
-        let door_descriptor = unsafe{ door_create(Self::c_wrapper, ptr::null(), 0) };
-        if door_descriptor == -1 {
-            return Err(Error::CreateDoor(errno()));
-        }
+impl From<OwnedFd> for Descriptor<'static> {
+    fn from(owned: OwnedFd) -> Self {
+        Descriptor::Release(owned)
+    }
+}
 
-        // Create jamb
-        let create_new = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
-        match unsafe{ libc::open(jamb_path.as_ptr(), create_new, 0400) } {
-            -1 => {
-                // Clean up the door, since we aren't going to finish
-                unsafe{ libc::close(door_descriptor) }; 
-                return Err(Error::InstallJamb(errno()))
-            },
-            jamb_descriptor => unsafe{ libc::close(jamb_descriptor); }
-        }
+impl<'a> From<BorrowedFd<'a>> for Descriptor<'a> {
+    fn from(borrowed: BorrowedFd<'a>) -> Self {
+        Descriptor::Duplicate(borrowed)
+    }
+}
 
-        // Attach door to jamb
-        match unsafe{ fattach(door_descriptor, jamb_path.as_ptr()) } {
-            -1 => {
-                // Clean up the door and jamb, since we aren't going to finish
-                unsafe{ libc::close(door_descriptor) }; 
-                unsafe{ libc::unlink(jamb_path.as_ptr()); }
-                Err(Error::AttachDoor(errno()))
-            },
-            _ => Ok(Server{ jamb_path, door_descriptor })
-        }
+
+/// A door call, as seen by the procedure answering it.
+#[derive(Debug)]
+pub struct Request<'a> {
+    /// Descriptors passed along with the call. They are closed when dropped, unless the procedure
+    /// keeps them or hands them on.
+    pub descriptors: Vec<OwnedFd>,
+    pub data: &'a [u8]
+}
+
+/// What a procedure sends back to its caller.
+#[derive(Debug,Default)]
+pub struct Response {
+    pub descriptors: Vec<Descriptor<'static>>,
+    pub data: buffer::Body
+}
+
+impl Response {
+    /// A response which carries no descriptors.
+    ///
+    /// `data` can be anything which turns into a [`Body`](buffer::Body): a `Vec<u8>` or `String`
+    /// (which are moved, not copied), a `&'static` slice, or an [`Mmap`](buffer::Mmap).
+    pub fn new<D: Into<buffer::Body>>(data: D) -> Self {
+        Self{ descriptors: vec![], data: data.into() }
+    }
+}
+
+impl From<(Vec<Descriptor<'static>>, Vec<u8>)> for Response {
+    fn from((descriptors, data): (Vec<Descriptor<'static>>, Vec<u8>)) -> Self {
+        Self{ descriptors, data: data.into() }
+    }
+}
+
+/// A boxed procedure, as handed to the backend by [`Server::from_fn`].
+pub(crate) type Procedure = Box<dyn Fn(Request<'_>) -> Response + Send + Sync>;
+
+/// Signature of [`ServerProcedure::rust_wrapper`].
+type RustWrapper = fn(Vec<OwnedFd>, &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>);
+
+
+/// Trait for types derived from the `define_server_procedure!` macro.
+///
+/// Because `define_server_procedure!` creates a new type to "host" each server procedure, we need
+/// to define a trait so we can work with these types generically.
+///
+pub trait ServerProcedure {
+
+    /// This is the part you define.  The function body you give in `define_server_procedure!` will
+    /// end up as the definition of this `rust` function, which will be called whenever the door is
+    /// invoked.
+    fn rust_wrapper(descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>);
+
+    /// Make this procedure available on the filesystem (as a door).
+    ///
+    /// This is shorthand for [`Server::from_fn`] with a closure which calls `rust_wrapper`.
+    fn install(path: &str) -> Result<Server,Error> where Self: Sized {
+        let procedure: RustWrapper = Self::rust_wrapper;
+        Server::from_fn(path, move |request| procedure(request.descriptors, request.data).into())
     }
 }
 
@@ -370,10 +507,11 @@ pub trait ServerProcedure {
 /// use doors::derive_server_procedure;
 /// use std::fmt::format;
 /// use std::str::from_utf8;
-/// use std::os::fd::RawFd;
+/// use std::os::fd::OwnedFd;
+/// use doors::Descriptor;
 ///
 /// // Consider this function, which returns a polite greeting to a client:
-/// fn hello(_: &[RawFd], request: &[u8]) -> (Vec<RawFd>, Vec<u8>) {
+/// fn hello(_: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
 ///     match from_utf8(request) {
 ///         Err(_) => (vec![], b"Your name is not valid utf8".to_vec()),
 ///         Ok(name) => {
@@ -401,9 +539,9 @@ macro_rules! derive_server_procedure {
         struct $type_name;
         impl doors::ServerProcedure for $type_name {
             fn rust_wrapper(
-                in_descriptors: &[std::os::fd::RawFd], 
+                in_descriptors: Vec<std::os::fd::OwnedFd>,
                 request: &[u8]
-            ) -> (Vec<std::os::fd::RawFd>, Vec<u8>) {
+            ) -> (Vec<doors::Descriptor<'static>>, Vec<u8>) {
                 $function_name(in_descriptors, request)
             }
         }
@@ -413,9 +551,307 @@ macro_rules! derive_server_procedure {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs::File;
+    use std::io::{Read, Seek, Write};
+    use std::thread;
+
+    fn shout(descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        (descriptors.into_iter().map(Descriptor::from).collect(), request.to_ascii_uppercase())
+    }
+
+    struct Shout;
+    impl ServerProcedure for Shout {
+        fn rust_wrapper(descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+            shout(descriptors, request)
+        }
+    }
+
+    fn door_path(name: &str) -> std::path::PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn descriptors_make_the_round_trip() {
+        let path = door_path("doors_test.2d61c4");
+        let _server = Shout::install(path.to_str().unwrap()).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        let mut file_path = std::env::temp_dir();
+        file_path.push("doors_test.2d61c4.txt");
+        let mut file = File::create(&file_path).unwrap();
+        write!(file, "knock knock").unwrap();
+        drop(file);
+
+        let file = File::open(&file_path).unwrap();
+        let (mut descriptors, response) = client.call(vec![Descriptor::release(file)], b"who's there?").unwrap();
+        assert_eq!(response, b"WHO'S THERE?");
+        assert_eq!(descriptors.len(), 1);
+
+        let mut file = File::from(descriptors.remove(0));
+        let mut contents = String::new();
+        file.rewind().unwrap();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "knock knock");
+    }
+
+    #[test]
+    fn duplicated_descriptors_stay_open() {
+        let path = door_path("doors_test.0e5d27");
+        let _server = Shout::install(path.to_str().unwrap()).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        let mut file_path = std::env::temp_dir();
+        file_path.push("doors_test.0e5d27.txt");
+        std::fs::write(&file_path, "knock knock").unwrap();
+
+        let mut file = File::open(&file_path).unwrap();
+        let (descriptors, _) = client.call(vec![Descriptor::Duplicate(file.as_fd())], b"hello").unwrap();
+        assert_eq!(descriptors.len(), 1);
+        assert_ne!(descriptors[0].as_raw_fd(), file.as_raw_fd());
+
+        // Our copy is still ours to use
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "knock knock");
+    }
+
+    #[test]
+    fn client_refs_can_call_concurrently() {
+        let path = door_path("doors_test.8e03f7");
+        let _server = Shout::install(path.to_str().unwrap()).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        thread::scope(|scope| {
+            for i in 0..8 {
+                let client_ref = client.borrow();
+                scope.spawn(move|| {
+                    let request = format!("call number {}", i);
+                    let (_, response) = client_ref.call(vec![], request.as_bytes()).unwrap();
+                    assert_eq!(response, request.to_uppercase().into_bytes());
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn shared_clients_can_call_concurrently() {
+        let path = door_path("doors_test.a5c813");
+        let _server = Shout::install(path.to_str().unwrap()).unwrap();
+        let client = std::sync::Arc::new(Client::new(&path).unwrap());
+
+        let threads: Vec<_> = (0..8).map(|i| {
+            let client = std::sync::Arc::clone(&client);
+            thread::spawn(move|| {
+                let request = format!("call number {}", i);
+                let (_, response) = client.call(vec![], request.as_bytes()).unwrap();
+                assert_eq!(response, request.to_uppercase().into_bytes());
+            })
+        }).collect();
+
+        // The threads keep the door open, even once we are done with it
+        drop(client);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    fn whoami(_descriptors: Vec<OwnedFd>, _request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        match caller() {
+            Ok(credentials) => (vec![], format!("{} {}", credentials.uid, credentials.gid).into_bytes()),
+            Err(e) => (vec![], e.to_string().into_bytes())
+        }
+    }
+
+    struct WhoAmI;
+    impl ServerProcedure for WhoAmI {
+        fn rust_wrapper(descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+            whoami(descriptors, request)
+        }
+    }
+
+    #[test]
+    fn servers_can_identify_their_callers() {
+        let path = door_path("doors_test.7c2a95");
+        let _server = WhoAmI::install(path.to_str().unwrap()).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        let (_, response) = client.call(vec![], &[]).unwrap();
+        let expected = unsafe{ format!("{} {}", libc::geteuid(), libc::getegid()) };
+        assert_eq!(String::from_utf8(response).unwrap(), expected);
+
+        // Outside of a door call, there is nobody to identify
+        assert!(caller().is_err());
+    }
+
+    fn dawdle(_descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        if request == b"slowly" {
+            thread::sleep(Duration::from_millis(500));
+        }
+        (vec![], request.to_vec())
+    }
+
+    struct Dawdle;
+    impl ServerProcedure for Dawdle {
+        fn rust_wrapper(descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+            dawdle(descriptors, request)
+        }
+    }
+
+    #[test]
+    fn slow_calls_can_be_abandoned() {
+        let path = door_path("doors_test.f3a81b");
+        let _server = Dawdle::install(path.to_str().unwrap()).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        let started = std::time::Instant::now();
+        let e = client.call_with_timeout(vec![], b"slowly", Duration::from_millis(50)).unwrap_err();
+        assert!(e.timed_out());
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        // The abandoned call does not get in the way of the next one
+        let (_, response) = client.call_with_timeout(vec![], b"quickly", Duration::from_secs(5)).unwrap();
+        assert_eq!(response, b"quickly");
+    }
+
+    #[test]
+    fn closures_can_keep_state() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Mutex;
+
+        let path = door_path("doors_test.c47e09");
+        let prefix = String::from("call");
+        let calls = AtomicUsize::new(0);
+        let seen = Mutex::new(vec![]);
+        let _server = Server::from_fn(path.to_str().unwrap(), move |request| {
+            let count = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            let mut seen = seen.lock().unwrap();
+            seen.push(request.data.to_vec());
+            Response::new(format!("{} {} of {} distinct", prefix, count, seen.len()))
+        }).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        let (_, response) = client.call(vec![], b"one").unwrap();
+        assert_eq!(response, b"call 1 of 1 distinct");
+        let (_, response) = client.call(vec![], b"two").unwrap();
+        assert_eq!(response, b"call 2 of 2 distinct");
+    }
+
+    #[test]
+    #[cfg(not(target_os = "illumos"))]
+    fn revoked_closures_are_dropped() {
+        use std::sync::Arc;
+
+        let path = door_path("doors_test.61d3ab");
+        let state = Arc::new(());
+        let captured = Arc::clone(&state);
+        let server = Server::from_fn(path.to_str().unwrap(), move |_| {
+            Response::new(Arc::strong_count(&captured).to_string())
+        }).unwrap();
+        let client = Client::new(&path).unwrap();
+        let (_, response) = client.call(vec![], &[]).unwrap();
+        assert_eq!(response, b"2");
+
+        // Workers go home once the door is revoked, and take the closure with them
+        drop(server);
+        let started = std::time::Instant::now();
+        while Arc::strong_count(&state) > 1 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// A response of `request` mebibytes, counting up from zero.
+    fn megabytes(request: Request) -> Response {
+        let length = request.data.first().copied().unwrap_or(0) as usize * 1024 * 1024;
+        Response::new((0..length).map(|i| i as u8).collect::<Vec<u8>>())
+    }
+
+    #[test]
+    fn large_responses_arrive_intact() {
+        let path = door_path("doors_test.d8a417");
+        let _server = Server::from_fn(path.to_str().unwrap(), megabytes).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        for size in [0u8, 1, 8] {
+            let (_, response) = client.call(vec![], &[size]).unwrap();
+            assert_eq!(response.len(), size as usize * 1024 * 1024);
+            assert!(response.iter().enumerate().all(|(i, byte)| *byte == i as u8));
+        }
+    }
+
+    #[test]
+    fn response_buffers_can_be_reused() {
+        let path = door_path("doors_test.4f60c2");
+        let _server = Server::from_fn(path.to_str().unwrap(), megabytes).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        // Too small at first, so the buffer has to grow
+        let mut buffer = Vec::with_capacity(16);
+        client.call_with_buffer(vec![], &[4], &mut buffer).unwrap();
+        assert_eq!(buffer.len(), 4 * 1024 * 1024);
+        assert_eq!(buffer[1024 * 1024 + 7], 7);
+
+        // Now it is big enough for anything up to 4 MiB
+        let address = buffer.as_ptr();
+        for size in [2u8, 0, 4] {
+            client.call_with_buffer(vec![], &[size], &mut buffer).unwrap();
+            assert_eq!(buffer.len(), size as usize * 1024 * 1024);
+            assert!(buffer.iter().enumerate().all(|(i, byte)| *byte == i as u8));
+            assert_eq!(buffer.as_ptr(), address);
+        }
+    }
+
+    #[test]
+    fn pooled_buffers_are_reused() {
+        static ANSWER: &[u8] = b"forty-two";
+        let path = door_path("doors_test.a1c7e3");
+        let _server = Server::from_fn(path.to_str().unwrap(), |_: Request| Response::new(ANSWER)).unwrap();
+        let client = Client::new(&path).unwrap();
+
+        let mut pool = buffer::BufPool::default();
+        let (_, response) = client.call_into(vec![], b"", &mut pool).unwrap();
+        assert_eq!(&*response, ANSWER);
+        let address = response.as_ptr();
+        drop(response);
+
+        let (_, response) = client.call_into(vec![], b"", &mut pool).unwrap();
+        assert_eq!(&*response, ANSWER);
+        assert_eq!(response.as_ptr(), address);
+    }
+
+    #[test]
+    fn stale_jambs_can_be_removed() {
+        let path = door_path("doors_test.0e5d3b");
+        let path_str = path.to_str().unwrap();
+
+        // As if a server had exited without cleaning up after itself
+        File::create(&path).unwrap();
+        assert!(Server::from_fn(path_str, megabytes).is_err());
+        assert!(Server::remove_stale(path_str).unwrap());
+        assert!(!Server::remove_stale(path_str).unwrap());
+
+        // A door which is still answering is left alone
+        let _server = Server::from_fn(path_str, megabytes).unwrap();
+        assert!(!Server::remove_stale(path_str).unwrap());
+        let (_, response) = Client::new(&path).unwrap().call(vec![], &[0]).unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn revoked_doors_cannot_be_opened() {
+        let path = door_path("doors_test.5b9e20");
+        let server = Shout::install(path.to_str().unwrap()).unwrap();
+        drop(server);
+        assert!(Client::new(&path).is_err());
+    }
 
     #[test]
+    #[cfg(target_os = "illumos")]
     fn raw_fd_to_door_desc_and_back() {
+        use illumos::door_h::door_desc_t;
         let raw: RawFd = 6;
         let dd = unsafe{ door_desc_t::from_raw_fd(raw) };
         assert_eq!(dd.as_raw_fd(), raw);
@@ -0,0 +1,759 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! UNIX Domain Socket Backend
+//!
+//! Doors only exist on illumos. Everywhere else, we fake them with a `SOCK_SEQPACKET` socket bound
+//! at the jamb path. This is slower than the real thing, but it lets door applications be built
+//! and tested on a Linux laptop.
+//!
+//! # Anatomy of a Call
+//!
+//! A [`Client`](crate::Client) holds a `SOCK_SEQPACKET` connection to the server, and many threads
+//! may share that connection (just like a door descriptor). To keep concurrent calls from
+//! stepping on each other's responses, each call creates a private `SOCK_STREAM` socket pair and
+//! sends one end of it to the server as a single `SCM_RIGHTS` packet. Packets are atomic, so the
+//! server never sees half an invocation. The request and response then travel over the private
+//! pair as length-prefixed [`Frame`]s, so they can be as big as [`MAX_PAYLOAD`].
+//!
+//! On the server side, one thread per connection turns incoming packets into invocations, and a
+//! pool of worker threads answers them. Like the kernel's door thread pool, a new worker is only
+//! created when every existing worker is busy.
+
+use crate::Credentials;
+use crate::buffer::Landing;
+use crate::Descriptor;
+use crate::Error;
+use crate::MAX_PAYLOAD;
+use crate::Procedure;
+use crate::Request;
+use crate::Server;
+use illumos::errno;
+use std::cell::Cell;
+use std::ffi;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::panic;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// The most descriptors that can accompany a single request or response.
+///
+/// This matches Linux's `SCM_MAX_FD`.
+const MAX_DESCRIPTORS: usize = 253;
+
+
+
+thread_local! {
+    /// Who placed the call this thread is answering, if any.
+    static CALLER: Cell<Option<Credentials>> = const { Cell::new(None) };
+}
+
+
+/// Preamble for a request or response on the private socket pair.
+///
+/// Any descriptors ride along with the frame as `SCM_RIGHTS` ancillary data, and `length` bytes of
+/// payload follow immediately after it. Both ends are on the same host, so native byte order is
+/// fine.
+#[repr(C)]
+#[derive(Clone,Copy,Default)]
+struct Frame {
+    descriptors: u32,
+    reserved: u32,
+    length: u64
+}
+
+
+/// State shared between a `Server` and the threads which answer its calls.
+struct Shared {
+    procedure: Procedure,
+    sender: Mutex<Option<mpsc::Sender<RawFd>>>,
+    receiver: Mutex<mpsc::Receiver<RawFd>>,
+    connections: Mutex<Vec<RawFd>>,
+    idle: AtomicUsize,
+    revoked: AtomicBool
+}
+
+
+/// The threads and sockets behind a [`Server`].
+pub(crate) struct Pool {
+    listener: RawFd,
+    shared: Arc<Shared>
+}
+
+
+/// Connect to the socket at a jamb path.
+pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<OwnedFd,Error> {
+    let address = socket_address(path.as_ref())?;
+    let descriptor = seqpacket_socket().map_err(Error::OpenDoor)?;
+    let result = unsafe{
+        libc::connect(
+            descriptor,
+            &address as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_un>() as libc::socklen_t
+        )
+    };
+    if result == -1 {
+        let e = io::Error::last_os_error();
+        unsafe{ libc::close(descriptor); }
+        return Err(Error::OpenDoor(e));
+    }
+    Ok(unsafe{ OwnedFd::from_raw_fd(descriptor) })
+}
+
+
+/// Remove the jamb at `path` if nobody is listening on it any more.
+///
+/// A socket whose server has exited (or anything else at `path` which is not a socket) refuses
+/// connections.
+pub(crate) fn remove_stale(path: &str) -> Result<bool,Error> {
+    let jamb_path = ffi::CString::new(path)?;
+    match open(path) {
+        Ok(_) => Ok(false),
+        Err(Error::OpenDoor(e)) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(Error::OpenDoor(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            if unsafe{ libc::unlink(jamb_path.as_ptr()) } == -1 {
+                return Err(Error::InstallJamb(errno()));
+            }
+            Ok(true)
+        },
+        Err(e) => Err(e)
+    }
+}
+
+
+/// Send a request over a private socket pair and wait for the response.
+///
+/// `SCM_RIGHTS` always sends a duplicate, so released descriptors are simply dropped (and closed)
+/// once the call is over. The response is read into `response`, which only grows if it is too
+/// small.
+pub(crate) fn call(
+    door_descriptor: BorrowedFd<'_>,
+    descriptors: Vec<Descriptor<'_>>,
+    request: &[u8],
+    response: &mut Vec<u8>
+) -> Result<(Vec<OwnedFd>,Landing),Error> {
+    let descriptors = place_call(door_descriptor, &descriptors, request, response, None)?;
+    Ok((descriptors, Landing::Buffer))
+}
+
+/// Like [`call`], but give up if the response has not arrived after `timeout`.
+///
+/// Hanging up on the private socket pair is all it takes to cancel the call, as far as the client
+/// is concerned. The server finds out when it tries to write the response.
+pub(crate) fn call_with_timeout(
+    door_descriptor: BorrowedFd<'_>,
+    descriptors: Vec<Descriptor<'_>>,
+    request: &[u8],
+    timeout: Duration
+) -> Result<(Vec<OwnedFd>,Vec<u8>),Error> {
+    let mut response = vec![];
+    let descriptors = place_call(door_descriptor, &descriptors, request, &mut response, Some(Instant::now() + timeout))?;
+    Ok((descriptors, response))
+}
+
+fn place_call(
+    door_descriptor: BorrowedFd<'_>,
+    descriptors: &[Descriptor<'_>],
+    request: &[u8],
+    response: &mut Vec<u8>,
+    deadline: Option<Instant>
+) -> Result<Vec<OwnedFd>,Error> {
+    let raw_fds: Vec<RawFd> = descriptors.iter().map(|descriptor| descriptor.as_raw_fd()).collect();
+    let (ours, theirs) = socket_pair(libc::SOCK_STREAM).map_err(|_| Error::DoorCall(errno()))?;
+    let delivered = send_message(door_descriptor.as_raw_fd(), &[0], &[theirs]);
+    unsafe{ libc::close(theirs); }
+    let exchanged = delivered
+        .and_then(|_| limit(ours, deadline))
+        .and_then(|_| write_frame(ours, &raw_fds, request))
+        .and_then(|_| limit(ours, deadline))
+        .and_then(|_| read_frame(ours, response));
+    unsafe{ libc::close(ours); }
+    exchanged.map(owned).map_err(|e| match e.raw_os_error() {
+        // A socket timeout looks like a nonblocking socket with nothing to say
+        Some(libc::EAGAIN | libc::ETIMEDOUT) => Error::DoorCall(libc::ETIMEDOUT),
+        Some(errno) => Error::DoorCall(errno),
+        None => Error::DoorCall(libc::EPROTO)
+    })
+}
+
+/// Make every send and receive on `socket` give up once `deadline` has passed.
+///
+/// The timeout applies to each system call separately, so a response which trickles in can take
+/// a little longer than that. A deadline of `None` means no timeout at all.
+fn limit(socket: RawFd, deadline: Option<Instant>) -> io::Result<()> {
+    let remaining = match deadline {
+        None => return Ok(()),
+        Some(deadline) => deadline.saturating_duration_since(Instant::now())
+    };
+    if remaining.is_zero() {
+        return Err(io::Error::from_raw_os_error(libc::ETIMEDOUT));
+    }
+    // A zero timeval would mean "wait forever", which is the opposite of what we want
+    let timeout = libc::timeval {
+        tv_sec: remaining.as_secs() as libc::time_t,
+        tv_usec: remaining.subsec_micros().max(1) as libc::suseconds_t
+    };
+    for option in [libc::SO_SNDTIMEO, libc::SO_RCVTIMEO] {
+        let result = unsafe{
+            libc::setsockopt(
+                socket,
+                libc::SOL_SOCKET,
+                option,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t
+            )
+        };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+
+/// Bind a socket at `path` and start answering calls with `procedure`.
+///
+/// The procedure is dropped once the server has been revoked and its last worker has gone home.
+pub(crate) fn install(path: &str, procedure: Procedure) -> Result<Server,Error> {
+    let jamb_path = ffi::CString::new(path)?;
+    let address = socket_address(Path::new(path))?;
+
+    // Create "door"
+    let listener = seqpacket_socket().map_err(|_| Error::CreateDoor(errno()))?;
+
+    // Create jamb
+    let bound = unsafe{
+        libc::bind(
+            listener,
+            &address as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_un>() as libc::socklen_t
+        )
+    };
+    if bound == -1 {
+        let e = errno();
+        // Clean up the socket, since we aren't going to finish
+        unsafe{ libc::close(listener); }
+        return Err(Error::InstallJamb(e));
+    }
+
+    // Attach "door" to jamb
+    if unsafe{ libc::listen(listener, libc::SOMAXCONN) } == -1 {
+        let e = errno();
+        // Clean up the socket and jamb, since we aren't going to finish
+        unsafe{ libc::close(listener); }
+        unsafe{ libc::unlink(jamb_path.as_ptr()); }
+        return Err(Error::AttachDoor(e));
+    }
+
+    // Like a real door descriptor, our own handle is just a connection that we can hand out to
+    // other processes.
+    let (door_descriptor, server_end) = match socket_pair(libc::SOCK_SEQPACKET) {
+        Ok(pair) => pair,
+        Err(_) => {
+            let e = errno();
+            unsafe{ libc::close(listener); }
+            unsafe{ libc::unlink(jamb_path.as_ptr()); }
+            return Err(Error::CreateDoor(e));
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let shared = Arc::new(Shared {
+        procedure,
+        sender: Mutex::new(Some(sender)),
+        receiver: Mutex::new(receiver),
+        connections: Mutex::new(vec![]),
+        idle: AtomicUsize::new(0),
+        revoked: AtomicBool::new(false)
+    });
+
+    Shared::serve_connection(&shared, server_end);
+    let acceptor = Arc::clone(&shared);
+    thread::spawn(move|| acceptor.accept(listener));
+
+    Ok(Server{ jamb_path, door_descriptor, pool: Pool{ listener, shared } })
+}
+
+
+/// Turn the current thread into another worker.
+pub(crate) fn park(server: &Server) -> ! {
+    server.pool.shared.work();
+    loop {
+        thread::park();
+    }
+}
+
+
+/// Who placed the call this thread is answering.
+pub(crate) fn caller() -> Result<Credentials,Error> {
+    CALLER.with(|caller| caller.get()).ok_or(Error::Caller(libc::EINVAL))
+}
+
+
+/// Stop accepting connections, hang up on existing clients, and remove the jamb.
+pub(crate) fn revoke(server: &mut Server) {
+    let shared = &server.pool.shared;
+    shared.revoked.store(true, Ordering::SeqCst);
+
+    // Stop new clients from connecting. The acceptor thread closes the listener once it wakes up.
+    unsafe{ libc::unlink(server.jamb_path.as_ptr()); }
+    unsafe{ libc::shutdown(server.pool.listener, libc::SHUT_RDWR); }
+
+    // Stop existing clients from issuing new calls
+    for connection in shared.connections.lock().unwrap().iter() {
+        unsafe{ libc::shutdown(*connection, libc::SHUT_RDWR); }
+    }
+    unsafe{ libc::close(server.door_descriptor); }
+
+    // Let idle workers go home
+    shared.sender.lock().unwrap().take();
+}
+
+
+impl Shared {
+    /// Accept connections until the server is revoked.
+    fn accept(self: Arc<Self>, listener: RawFd) {
+        loop {
+            let connection = unsafe{
+                libc::accept4(listener, ptr::null_mut(), ptr::null_mut(), libc::SOCK_CLOEXEC)
+            };
+            if connection != -1 {
+                Self::serve_connection(&self, connection);
+            } else if self.revoked.load(Ordering::SeqCst) {
+                break;
+            } else if errno() != libc::EINTR && errno() != libc::ECONNABORTED {
+                eprintln!("doors: could not accept connection: {}", io::Error::last_os_error());
+                break;
+            }
+        }
+        unsafe{ libc::close(listener); }
+    }
+
+    /// Spawn a thread to turn packets from `connection` into invocations.
+    fn serve_connection(shared: &Arc<Self>, connection: RawFd) {
+        shared.connections.lock().unwrap().push(connection);
+        let shared = Arc::clone(shared);
+        thread::spawn(move|| {
+            while let Ok(Some(invocation)) = receive_invocation(connection) {
+                shared.dispatch(invocation);
+            }
+            let mut connections = shared.connections.lock().unwrap();
+            connections.retain(|c| *c != connection);
+            unsafe{ libc::close(connection); }
+        });
+    }
+
+    /// Queue an invocation, hiring a new worker if everybody is busy.
+    fn dispatch(self: &Arc<Self>, invocation: RawFd) {
+        let sender = self.sender.lock().unwrap();
+        match sender.as_ref() {
+            Some(sender) => {
+                if self.idle.load(Ordering::SeqCst) == 0 {
+                    let worker = Arc::clone(self);
+                    thread::spawn(move|| worker.work());
+                }
+                if sender.send(invocation).is_err() {
+                    unsafe{ libc::close(invocation); }
+                }
+            },
+            None => unsafe{ libc::close(invocation); }
+        }
+    }
+
+    /// Answer invocations until the server is revoked.
+    fn work(&self) {
+        loop {
+            self.idle.fetch_add(1, Ordering::SeqCst);
+            let invocation = self.receiver.lock().unwrap().recv();
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+            match invocation {
+                Ok(invocation) => {
+                    self.answer(invocation);
+                    unsafe{ libc::close(invocation); }
+                },
+                Err(_) => return
+            }
+        }
+    }
+
+    /// Read a request, run the procedure, and write back its response.
+    ///
+    /// Released descriptors are closed once they have been sent, when the response is dropped. A
+    /// client which hangs up early is not our problem.
+    fn answer(&self, invocation: RawFd) {
+        let mut request = vec![];
+        let in_descriptors = match read_frame(invocation, &mut request) {
+            Ok(received) => received,
+            Err(_) => return
+        };
+        CALLER.with(|caller| caller.set(peer_credentials(invocation)));
+        let response = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            (self.procedure)(Request{ descriptors: owned(in_descriptors), data: &request })
+        }));
+        CALLER.with(|caller| caller.set(None));
+        // A procedure which panics gets no response, and the client sees the invocation close
+        let response = match response {
+            Ok(response) => response,
+            Err(_) => return
+        };
+        let out_descriptors: Vec<RawFd> = response.descriptors.iter().map(|descriptor| descriptor.as_raw_fd()).collect();
+        let _ = write_frame(invocation, &out_descriptors, &response.data);
+    }
+}
+
+
+/// The credentials of whoever is on the other end of a UNIX socket.
+///
+/// The private socket pair for each call is created by the client, so this identifies the
+/// process which placed the call.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(socket: RawFd) -> Option<Credentials> {
+    let mut ucred: libc::ucred = unsafe{ mem::zeroed() };
+    let mut length = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe{
+        libc::getsockopt(
+            socket,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut length
+        )
+    };
+    match result {
+        0 => Some(Credentials{ uid: ucred.uid, gid: ucred.gid, pid: Some(ucred.pid) }),
+        _ => None
+    }
+}
+
+/// The credentials of whoever is on the other end of a UNIX socket.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_credentials(socket: RawFd) -> Option<Credentials> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    match unsafe{ libc::getpeereid(socket, &mut uid, &mut gid) } {
+        0 => Some(Credentials{ uid, gid, pid: None }),
+        _ => None
+    }
+}
+
+
+/// Build a `sockaddr_un` for a jamb path.
+fn socket_address(path: &Path) -> Result<libc::sockaddr_un,Error> {
+    let bytes = path.as_os_str().as_bytes();
+    let mut address: libc::sockaddr_un = unsafe{ mem::zeroed() };
+    address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if bytes.contains(&0) {
+        // Produce the same error that CString would
+        ffi::CString::new(bytes)?;
+    }
+    if bytes.len() >= address.sun_path.len() {
+        return Err(Error::OpenDoor(io::Error::from_raw_os_error(libc::ENAMETOOLONG)));
+    }
+    for (dst, src) in address.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+    Ok(address)
+}
+
+fn seqpacket_socket() -> io::Result<RawFd> {
+    match unsafe{ libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        descriptor => Ok(descriptor)
+    }
+}
+
+fn socket_pair(kind: libc::c_int) -> io::Result<(RawFd,RawFd)> {
+    let mut fds = [0; 2];
+    match unsafe{ libc::socketpair(libc::AF_UNIX, kind | libc::SOCK_CLOEXEC, 0, fds.as_mut_ptr()) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok((fds[0], fds[1]))
+    }
+}
+
+
+/// Take ownership of descriptors which arrived with `SCM_RIGHTS`.
+fn owned(raw_fds: Vec<RawFd>) -> Vec<OwnedFd> {
+    raw_fds.into_iter().map(|raw| unsafe{ OwnedFd::from_raw_fd(raw) }).collect()
+}
+
+
+/// Wait for the next invocation on a connection.
+///
+/// Returns `None` once the client hangs up.
+fn receive_invocation(connection: RawFd) -> io::Result<Option<RawFd>> {
+    let mut byte = [0u8; 1];
+    loop {
+        match receive_message(connection, &mut byte, 1) {
+            Ok((0, _)) => return Ok(None),
+            Ok((_, mut descriptors)) => match descriptors.pop() {
+                Some(invocation) => {
+                    for stray in descriptors {
+                        unsafe{ libc::close(stray); }
+                    }
+                    return Ok(Some(invocation));
+                },
+                // A packet without a socket is meaningless, but not fatal
+                None => continue
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+
+/// Write a [`Frame`] (with descriptors) followed by its payload.
+fn write_frame(socket: RawFd, descriptors: &[RawFd], payload: &[u8]) -> io::Result<()> {
+    if descriptors.len() > MAX_DESCRIPTORS {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    if payload.len() as u64 > MAX_PAYLOAD {
+        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+    }
+    let frame = Frame {
+        descriptors: descriptors.len() as u32,
+        reserved: 0,
+        length: payload.len() as u64
+    };
+    let header = unsafe{
+        std::slice::from_raw_parts(&frame as *const Frame as *const u8, mem::size_of::<Frame>())
+    };
+    let sent = send_message(socket, header, descriptors)?;
+    send_all(socket, &header[sent..])?;
+    send_all(socket, payload)
+}
+
+/// Read a [`Frame`] (with descriptors), and its payload into `payload`.
+///
+/// `payload` is cleared first, and only reallocated if the payload does not fit. Payloads bigger
+/// than [`MAX_PAYLOAD`] are refused with `EMSGSIZE`.
+fn read_frame(socket: RawFd, payload: &mut Vec<u8>) -> io::Result<Vec<RawFd>> {
+    let mut frame = Frame::default();
+    let header = unsafe{
+        std::slice::from_raw_parts_mut(&mut frame as *mut Frame as *mut u8, mem::size_of::<Frame>())
+    };
+    let (received, descriptors) = loop {
+        match receive_message(socket, header, MAX_DESCRIPTORS) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => break result?
+        }
+    };
+    let complete = receive_all(socket, &mut header[received..])
+        .and_then(|_| {
+            if frame.descriptors as usize != descriptors.len() {
+                return Err(io::Error::from_raw_os_error(libc::EPROTO));
+            }
+            // The length comes from whoever is on the other end, which may be any process that can
+            // reach the jamb, so it has to be checked before we make room for it
+            if frame.length > MAX_PAYLOAD {
+                return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+            }
+            payload.clear();
+            payload.reserve(frame.length as usize);
+            receive_appending(socket, payload, frame.length as usize)
+        });
+    match complete {
+        Ok(()) => Ok(descriptors),
+        Err(e) => {
+            for raw in descriptors {
+                unsafe{ libc::close(raw); }
+            }
+            Err(e)
+        }
+    }
+}
+
+
+/// Send some bytes, with descriptors attached, in a single `sendmsg`.
+fn send_message(socket: RawFd, bytes: &[u8], descriptors: &[RawFd]) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut libc::c_void,
+        iov_len: bytes.len()
+    };
+    let payload_size = mem::size_of_val(descriptors);
+    let space = unsafe{ libc::CMSG_SPACE(payload_size as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut message: libc::msghdr = unsafe{ mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    if !descriptors.is_empty() {
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = space as _;
+        unsafe{
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(payload_size as u32) as _;
+            ptr::copy_nonoverlapping(
+                descriptors.as_ptr() as *const u8,
+                libc::CMSG_DATA(header),
+                payload_size
+            );
+        }
+    }
+
+    loop {
+        match unsafe{ libc::sendmsg(socket, &message, libc::MSG_NOSIGNAL) } {
+            -1 if errno() == libc::EINTR => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            sent => return Ok(sent as usize)
+        }
+    }
+}
+
+/// Receive some bytes and up to `max_descriptors` descriptors in a single `recvmsg`.
+fn receive_message(socket: RawFd, bytes: &mut [u8], max_descriptors: usize) -> io::Result<(usize,Vec<RawFd>)> {
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_mut_ptr() as *mut libc::c_void,
+        iov_len: bytes.len()
+    };
+    let space = unsafe{
+        libc::CMSG_SPACE((max_descriptors * mem::size_of::<RawFd>()) as u32)
+    } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut message: libc::msghdr = unsafe{ mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = space as _;
+
+    let received = match unsafe{ libc::recvmsg(socket, &mut message, libc::MSG_CMSG_CLOEXEC) } {
+        -1 => return Err(io::Error::last_os_error()),
+        received => received as usize
+    };
+
+    let mut descriptors = vec![];
+    let mut header = unsafe{ libc::CMSG_FIRSTHDR(&message) };
+    while !header.is_null() {
+        let (level, kind, length) = unsafe{
+            ((*header).cmsg_level, (*header).cmsg_type, (*header).cmsg_len as usize)
+        };
+        if level == libc::SOL_SOCKET && kind == libc::SCM_RIGHTS {
+            let data = unsafe{ libc::CMSG_DATA(header) };
+            let count = (length - (data as usize - header as usize)) / mem::size_of::<RawFd>();
+            for i in 0..count {
+                let raw = unsafe{ ptr::read_unaligned((data as *const RawFd).add(i)) };
+                descriptors.push(raw);
+            }
+        }
+        header = unsafe{ libc::CMSG_NXTHDR(&message, header) };
+    }
+
+    if message.msg_flags & libc::MSG_CTRUNC != 0 {
+        for raw in descriptors {
+            unsafe{ libc::close(raw); }
+        }
+        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+    }
+
+    Ok((received, descriptors))
+}
+
+fn send_all(socket: RawFd, mut bytes: &[u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        let sent = unsafe{
+            libc::send(socket, bytes.as_ptr() as *const libc::c_void, bytes.len(), libc::MSG_NOSIGNAL)
+        };
+        match sent {
+            -1 if errno() == libc::EINTR => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            sent => bytes = &bytes[sent as usize..]
+        }
+    }
+    Ok(())
+}
+
+/// Receive exactly `length` more bytes onto the end of `bytes`, which must already have room.
+fn receive_appending(socket: RawFd, bytes: &mut Vec<u8>, length: usize) -> io::Result<()> {
+    let end = bytes.len() + length;
+    while bytes.len() < end {
+        let wanted = end - bytes.len();
+        let spare = &mut bytes.spare_capacity_mut()[..wanted];
+        let received = unsafe{
+            libc::recv(socket, spare.as_mut_ptr() as *mut libc::c_void, wanted, 0)
+        };
+        match received {
+            -1 if errno() == libc::EINTR => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            0 => return Err(io::Error::from_raw_os_error(libc::ECONNRESET)),
+            // The kernel has initialized this many more bytes
+            received => unsafe{ bytes.set_len(bytes.len() + received as usize) }
+        }
+    }
+    Ok(())
+}
+
+fn receive_all(socket: RawFd, mut bytes: &mut [u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        let received = unsafe{
+            libc::recv(socket, bytes.as_mut_ptr() as *mut libc::c_void, bytes.len(), 0)
+        };
+        match received {
+            -1 if errno() == libc::EINTR => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            0 => return Err(io::Error::from_raw_os_error(libc::ECONNRESET)),
+            received => bytes = &mut bytes[received as usize..]
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let (ours, theirs) = socket_pair(libc::SOCK_STREAM).unwrap();
+        let (ours, theirs) = unsafe{ (OwnedFd::from_raw_fd(ours), OwnedFd::from_raw_fd(theirs)) };
+
+        // A peer may claim any length it likes
+        let frame = Frame{ descriptors: 0, reserved: 0, length: u64::MAX };
+        let header = unsafe{
+            std::slice::from_raw_parts(&frame as *const Frame as *const u8, mem::size_of::<Frame>())
+        };
+        send_all(ours.as_raw_fd(), header).unwrap();
+        let mut payload = vec![];
+        let e = read_frame(theirs.as_raw_fd(), &mut payload).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EMSGSIZE));
+        assert_eq!(payload.capacity(), 0);
+
+        // Frames within the limit still arrive intact
+        write_frame(ours.as_raw_fd(), &[], b"just right").unwrap();
+        read_frame(theirs.as_raw_fd(), &mut payload).unwrap();
+        assert_eq!(payload, b"just right");
+    }
+
+    #[test]
+    fn panicking_procedures_hang_up_on_the_client() {
+        let path = std::env::temp_dir().join("doors_socket_test.9d2c47");
+        let _ = std::fs::remove_file(&path);
+        let _server = Server::from_fn(path.to_str().unwrap(), |request: Request| {
+            if request.data == b"panic" {
+                panic!("as requested");
+            }
+            crate::Response::new(request.data.to_vec())
+        }).unwrap();
+        let client = crate::Client::new(&path).unwrap();
+
+        assert!(client.call(vec![], b"panic").is_err());
+        assert!(client.call(vec![], b"panic").is_err());
+        assert_eq!(client.call(vec![], b"still here").unwrap().1, b"still here");
+    }
+}
@@ -18,19 +18,14 @@
 
 
 #![allow(non_camel_case_types)]
-use libc;
 
 
 /// Signature for a Door Server Procedure
 ///
 /// All "Server Procedures" (functions which respond to `door_call` requests) must use this type
-/// signature. Because `portunusd` neither shares descriptors with applications nor makes use of the
-/// `cookie` field, we can consider only:
-///
-/// * `argp`
-/// * `arg_size`
-///
-/// which together specify an array of bytes.  See [`DOOR_CREATE(3C)`] for examples and further
+/// signature. `argp` and `arg_size` together specify an array of bytes, `dp` and `n_desc` specify
+/// any descriptors which came along with them, and `cookie` is whatever was passed to
+/// [door_create] (the doors crate keeps a boxed closure there).  See [`DOOR_CREATE(3C)`] for examples and further
 /// detail.
 ///
 /// [`DOOR_CREATE(3C)`]: https://illumos.org/man/3c/door_create
@@ -47,8 +42,8 @@ extern "C" {
     /// Turns a function into a file descriptor.
     ///
     /// The function in question must match the "Server Procedure" signature
-    /// [door_server_procedure_t][1]. Portunus does not currently use the `cookie` argument. Since
-    /// it will not send any file descriptors, applications are free to set `attributes` to
+    /// [door_server_procedure_t][1], and is handed `cookie` on every call. Applications which do
+    /// not expect any file descriptors are free to set `attributes` to
     /// [DOOR_REFUSE_DESC](constant.DOOR_REFUSE_DESC.html).
     ///
     /// See [`DOOR_CREATE(3C)`] for more details.
@@ -74,6 +69,28 @@ extern "C" {
     pub fn door_call(d: libc::c_int, params: *const door_arg_t) -> libc::c_int;
 
 
+    /// Find out who placed the door call currently being answered.
+    ///
+    /// Only meaningful from within a server procedure. If `*info` is null, the system allocates a
+    /// [`ucred_t`](crate::ucred_h::ucred_t), which must later be released with
+    /// [`ucred_free`](crate::ucred_h::ucred_free).
+    ///
+    /// See [`DOOR_UCRED(3C)`].
+    ///
+    /// [`DOOR_UCRED(3C)`]: https://illumos.org/man/3c/door_ucred
+    pub fn door_ucred(info: *mut *mut crate::ucred_h::ucred_t) -> libc::c_int;
+
+
+    /// Describe the door behind descriptor `d`.
+    ///
+    /// Fails with `EBADF` if `d` is not a door at all.
+    ///
+    /// See [`DOOR_INFO(3C)`].
+    ///
+    /// [`DOOR_INFO(3C)`]: https://illumos.org/man/3c/door_info
+    pub fn door_info(d: libc::c_int, info: *mut door_info_t) -> libc::c_int;
+
+
     /// The inverse of `door_call` - return data and control to the calling process.
     ///
     /// Use this at the end of `server_procedure` in lieu of the traditional `return` statement to
@@ -168,6 +185,7 @@ pub type door_attr_t = libc::c_uint;
 ///
 /// [1]: https://github.com/robertdfrench/portunusd/blob/trunk/etc/DPA.md
 /// [`DOOR_CREATE(3C)`]: https://illumos.org/man/3c/door_create#DESCRIPTION
+pub const DOOR_REVOKED: door_attr_t = 0x08; // The door has been revoked.
 pub const DOOR_REFUSE_DESC: door_attr_t = 0x40; // Disable file descriptor passing.
 pub const DOOR_DESCRIPTOR: door_attr_t = 0x10000; // A file descriptor is being passed.
 pub const DOOR_RELEASE: door_attr_t = 0x40000; // Passed references are also released.
@@ -201,6 +219,28 @@ pub struct door_desc_t__d_data__d_desc {
 }
 
 
+/// What [`door_info`] knows about a door
+///
+/// See [`DOOR_INFO(3C)`]. The attribute we care about is
+/// [DOOR_REVOKED](constant.DOOR_REVOKED.html), which tells us that nobody is answering any more.
+///
+/// [`DOOR_INFO(3C)`]: https://illumos.org/man/3c/door_info
+#[derive(Debug,Default)]
+#[repr(C)]
+pub struct door_info_t {
+    pub di_target: libc::pid_t,
+    pub di_proc: door_ptr_t,
+    pub di_data: door_ptr_t,
+    pub di_attributes: door_attr_t,
+    pub di_uniquifier: door_id_t,
+    di_resv: [libc::c_int; 4]
+}
+
+
+/// A pointer in the server's address space, wide enough for any data model.
+pub type door_ptr_t = libc::c_ulonglong;
+
+
 /// Opaque Door ID
 ///
 /// Some kind of door identifier. The doors API handles this for us, we don't really need to worry
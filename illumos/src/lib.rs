@@ -21,8 +21,8 @@
 
 pub mod door_h;
 pub mod stropts_h;
+pub mod ucred_h;
 
-use libc;
 use std::os::fd;
 use std::os::fd::AsRawFd;
 use std::os::fd::FromRawFd;
@@ -36,11 +36,21 @@ use std::os::fd::FromRawFd;
 /// See [`PERROR(3C)`], but don't think too hard about the fact that this is a function and that
 /// one doesn't seem to be.
 ///
+/// On platforms other than illumos (where the `doors` crate falls back to UNIX domain sockets), we
+/// let the standard library find errno for us.
+///
 /// [`PERROR(3C)`]: https://illumos.org/man/3c/errno
+#[cfg(target_os = "illumos")]
 pub fn errno() -> libc::c_int {
     unsafe{ *libc::___errno() }
 }
 
+/// Good ole UNIX errno, for everybody else
+#[cfg(not(target_os = "illumos"))]
+pub fn errno() -> libc::c_int {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
 impl AsRawFd for door_h::door_desc_t {
     fn as_raw_fd(&self) -> fd::RawFd {
         let d_data = &self.d_data;
@@ -66,10 +76,9 @@ impl FromRawFd for door_h::door_desc_t {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
-    use std::path::Path;
-    use std::ptr;
-    use std::ffi::{CStr,CString};
+    use std::ffi::CString;
+    #[cfg(target_os = "illumos")]
+    use std::{fs, path::Path, ptr, ffi::CStr};
 
     #[test]
     fn errno_works() {
@@ -81,6 +90,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(target_os = "illumos")]
     fn can_invoke_own_door() {
         // The simplest possible smoke test is to see if we can both call and answer our own door
         // invocation. Remember: door_create does not change control, but door_call and door_return
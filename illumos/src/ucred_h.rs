@@ -0,0 +1,42 @@
+/* 
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+
+//! Unsafe Declarations for the illumos User Credentials API
+//!
+//! This module merely re-exports the subset of the illumos ucred api that we need for this
+//! project. It makes no attempt at safety or ergonomics.
+//!
+//! A door server can ask the kernel who placed the call it is currently answering (see
+//! [`door_ucred`](crate::door_h::door_ucred)). The answer comes back as an opaque `ucred_t`, which
+//! can only be picked apart with these functions. See [`UCRED_GET(3C)`].
+//!
+//! [`UCRED_GET(3C)`]: https://illumos.org/man/3c/ucred_get
+
+#![allow(non_camel_case_types)]
+
+
+/// Opaque user credentials.
+#[repr(C)]
+pub struct ucred_t {
+    _private: [u8; 0]
+}
+
+
+extern "C" {
+    /// The effective user id of the process described by `uc`.
+    pub fn ucred_geteuid(uc: *const ucred_t) -> libc::uid_t;
+
+    /// The effective group id of the process described by `uc`.
+    pub fn ucred_getegid(uc: *const ucred_t) -> libc::gid_t;
+
+    /// The process id described by `uc`, or -1 if it is not available.
+    pub fn ucred_getpid(uc: *const ucred_t) -> libc::pid_t;
+
+    /// Release a `ucred_t` allocated by the system.
+    pub fn ucred_free(uc: *mut ucred_t);
+}
@@ -0,0 +1,246 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Socket Activation
+//!
+//! Rather than binding every address itself, PortunusD can take over sockets which a supervisor
+//! (or a previous PortunusD, during an upgrade) has already bound. They arrive in one of two ways:
+//!
+//! * Through the `LISTEN_PID`, `LISTEN_FDS`, and `LISTEN_FDNAMES` environment variables, as
+//!   systemd passes them: descriptors 3, 4, and so on, with colon-separated names.
+//! * Through `portunusd --inherit-fd web=3`, once for each descriptor.
+//!
+//! An [`Inherited`] socket is used in place of binding a forwarding statement if the statement's
+//! `name` matches the socket's, or if the socket is already bound to the statement's address.
+//! Sockets which no statement claims are closed once PortunusD starts.
+
+// Types
+use crate::config::{ForwardingStatement, Protocol};
+use crate::relay::Listener;
+use std::env;
+use std::io;
+use std::mem;
+use std::net;
+use std::os::fd::RawFd;
+
+// Macros
+use errors::define_error_enum;
+
+// Traits
+use std::os::fd::FromRawFd;
+
+
+define_error_enum!(
+    pub enum ActivationError {
+        Io(io::Error),
+        Invalid(String)
+    }
+);
+
+
+/// The first descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+
+/// A bound socket handed to us by whoever started PortunusD.
+pub struct Inherited {
+    pub name: Option<String>,
+    pub listener: Listener
+}
+
+impl Inherited {
+    /// Take ownership of `descriptor`, which must be a listening TCP socket or a UDP socket.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may use or close `descriptor` afterwards, unless this returns an error, in
+    /// which case it is left alone.
+    pub unsafe fn from_raw_fd(name: Option<String>, descriptor: RawFd) -> Result<Self, ActivationError> {
+        let invalid = |problem: &str| ActivationError::Invalid(format!("descriptor {} {}", descriptor, problem));
+        match socket_family(descriptor) {
+            Ok(libc::AF_INET | libc::AF_INET6) => {},
+            Ok(_) => return Err(invalid("is not an IP socket")),
+            Err(_) => return Err(invalid("is not a socket"))
+        }
+        let listener = match socket_option(descriptor, libc::SO_TYPE) {
+            Ok(libc::SOCK_STREAM) => match socket_option(descriptor, libc::SO_ACCEPTCONN)? {
+                0 => return Err(invalid("is not listening")),
+                _ => Listener::Tcp(net::TcpListener::from_raw_fd(descriptor))
+            },
+            Ok(libc::SOCK_DGRAM) => Listener::Udp(net::UdpSocket::from_raw_fd(descriptor)),
+            Ok(_) => return Err(invalid("is neither a TCP nor a UDP socket")),
+            Err(_) => return Err(invalid("is not a socket"))
+        };
+
+        // Our children have no business with these
+        libc::fcntl(descriptor, libc::F_SETFD, libc::FD_CLOEXEC);
+        Ok(Self{ name, listener })
+    }
+
+    /// Should `statement` use this socket, rather than binding its own?
+    pub fn matches(&self, statement: &ForwardingStatement) -> bool {
+        let datagram = matches!(self.listener, Listener::Udp(_));
+        if datagram != (statement.protocol == Protocol::UDP) {
+            return false;
+        }
+        match (&statement.name, &self.name) {
+            (Some(wanted), Some(name)) if wanted == name => true,
+            _ => self.listener.local_addr().map(|address| address == statement.address).unwrap_or(false)
+        }
+    }
+
+    /// The socket's name, or else its address, for log messages.
+    pub fn describe(&self) -> String {
+        match (&self.name, self.listener.local_addr()) {
+            (Some(name), _) => name.clone(),
+            (None, Ok(address)) => address.to_string(),
+            (None, Err(_)) => "unknown socket".to_owned()
+        }
+    }
+}
+
+
+/// Take over the sockets described by `LISTEN_PID`, `LISTEN_FDS`, and `LISTEN_FDNAMES`.
+///
+/// If `LISTEN_PID` is missing or names some other process, the variables were meant for somebody
+/// else, and nothing is inherited. Otherwise, the variables are removed from the environment, so
+/// that no child of ours mistakes them for its own.
+pub fn from_env() -> Result<Vec<Inherited>, ActivationError> {
+    let variable = |key: &str| env::var(key).ok();
+    let descriptors = listen_fds(
+        variable("LISTEN_PID").as_deref(),
+        variable("LISTEN_FDS").as_deref(),
+        variable("LISTEN_FDNAMES").as_deref(),
+        std::process::id()
+    )?;
+    if !descriptors.is_empty() {
+        for key in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(key);
+        }
+    }
+    descriptors.into_iter()
+        .map(|(name, descriptor)| unsafe{ Inherited::from_raw_fd(name, descriptor) })
+        .collect()
+}
+
+/// Take over the socket described by an `--inherit-fd name=N` flag.
+pub fn from_flag(flag: &str) -> Result<Inherited, ActivationError> {
+    let invalid = || ActivationError::Invalid(format!("--inherit-fd should look like web=3, not {}", flag));
+    let (name, descriptor) = flag.split_once('=').ok_or_else(invalid)?;
+    let descriptor: RawFd = descriptor.parse().map_err(|_| invalid())?;
+    if name.is_empty() || descriptor < LISTEN_FDS_START {
+        return Err(invalid());
+    }
+    unsafe{ Inherited::from_raw_fd(Some(name.to_owned()), descriptor) }
+}
+
+/// Work out which descriptors systemd passed us, and what they are called.
+fn listen_fds(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&str>,
+    our_pid: u32
+) -> Result<Vec<(Option<String>, RawFd)>, ActivationError> {
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(our_pid) {
+        return Ok(vec![]);
+    }
+    let count: RawFd = count.and_then(|count| count.parse().ok())
+        .ok_or_else(|| ActivationError::Invalid(format!("LISTEN_FDS should be a number, not {:?}", count)))?;
+    let mut names = names.map(|names| names.split(':').map(str::to_owned).collect::<Vec<_>>()).unwrap_or_default();
+    names.resize(count.max(0) as usize, String::new());
+    Ok(names.into_iter()
+        .zip(LISTEN_FDS_START..LISTEN_FDS_START + count.max(0))
+        .map(|(name, descriptor)| (Some(name).filter(|name| !name.is_empty()), descriptor))
+        .collect())
+}
+
+/// The address family a socket is bound in, like `AF_INET`.
+fn socket_family(descriptor: RawFd) -> io::Result<libc::c_int> {
+    let mut address: libc::sockaddr_storage = unsafe{ mem::zeroed() };
+    let mut length = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe{
+        libc::getsockname(descriptor, &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut length)
+    };
+    match result {
+        0 => Ok(address.ss_family as libc::c_int),
+        _ => Err(io::Error::last_os_error())
+    }
+}
+
+/// Read an integer socket option.
+fn socket_option(descriptor: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe{
+        libc::getsockopt(descriptor, libc::SOL_SOCKET, option, &mut value as *mut libc::c_int as *mut libc::c_void, &mut length)
+    };
+    match result {
+        0 => Ok(value),
+        _ => Err(io::Error::last_os_error())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+
+    #[test]
+    fn reads_what_systemd_passed() {
+        assert_eq!(listen_fds(None, Some("2"), None, 42).unwrap(), vec![]);
+        assert_eq!(listen_fds(Some("41"), Some("2"), None, 42).unwrap(), vec![]);
+        assert_eq!(listen_fds(Some("42"), Some("2"), Some("web:"), 42).unwrap(), vec![
+            (Some("web".to_owned()), 3),
+            (None, 4)
+        ]);
+        assert!(listen_fds(Some("42"), None, None, 42).is_err());
+    }
+
+    #[test]
+    fn matches_statements_by_name_or_address() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let inherited = from_flag(&format!("web={}", listener.into_raw_fd())).unwrap();
+        assert_eq!(inherited.describe(), "web");
+
+        let by_address: ForwardingStatement = format!("forward tcp {} to /var/run/echo.door", address).parse().unwrap();
+        let by_name: ForwardingStatement = "forward http 0.0.0.0:80 name web to { map GET / to /var/run/blog.door }".parse().unwrap();
+        let neither: ForwardingStatement = "forward tcp 0.0.0.0:80 name api to /var/run/echo.door".parse().unwrap();
+        let datagram: ForwardingStatement = format!("forward udp {} name web to /var/run/echo.door", address).parse().unwrap();
+        assert!(inherited.matches(&by_address));
+        assert!(inherited.matches(&by_name));
+        assert!(!inherited.matches(&neither));
+        assert!(!inherited.matches(&datagram));
+    }
+
+    #[test]
+    fn refuses_sockets_we_cannot_use() {
+        assert!(from_flag("web").is_err());
+        assert!(from_flag("web=stdin").is_err());
+        assert!(from_flag("web=0").is_err());
+
+        // A TCP socket, but not a listening one
+        let socket = unsafe{ libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+        assert!(matches!(from_flag(&format!("web={}", socket)), Err(ActivationError::Invalid(_))));
+        unsafe{ libc::close(socket) };
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let descriptor = file.into_raw_fd();
+        assert!(matches!(from_flag(&format!("web={}", descriptor)), Err(ActivationError::Invalid(_))));
+        unsafe{ libc::close(descriptor) };
+
+        // A listening socket, but not an IP one, which is still ours to close
+        let mut path = std::env::temp_dir();
+        path.push("portunusd_activation_test.4d8e21");
+        let _ = std::fs::remove_file(&path);
+        let descriptor = std::os::unix::net::UnixListener::bind(&path).unwrap().into_raw_fd();
+        assert!(matches!(from_flag(&format!("web={}", descriptor)), Err(ActivationError::Invalid(_))));
+        assert_eq!(unsafe{ libc::close(descriptor) }, 0);
+        let _ = std::fs::remove_file(&path);
+    }
+}
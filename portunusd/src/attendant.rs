@@ -7,57 +7,535 @@
  */
 
 // Types
+use crate::balance::Balancer;
+use crate::config::{Atlas, Limits, Route};
+use crate::http;
+use crate::http::Request;
+use crate::health::CircuitOpen;
+use crate::metrics::{CallError, Meter, MeteredDoor};
+use crate::peer::{self, Peer, PeerError};
+use crate::tls;
+use doors::Descriptor;
+use doors::envelope::{Envelope, Protocol};
+use rustls::ServerConfig;
 use std::any;
+use std::collections::HashMap;
 use std::io;
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
 use std::net;
 use std::thread;
+use std::time::Duration;
 
 // Macros
 use errors::define_error_enum;
 
 // Traits
-use std::os::fd::IntoRawFd;
+use std::io::Read;
+use std::io::Write;
+use std::os::fd::AsFd;
 
 define_error_enum!(
     pub enum AttendError {
         Io(io::Error),
-        Recv(mpsc::RecvError),
-        Door(doors::Error)
+        Door(doors::Error),
+        CircuitOpen(CircuitOpen),
+        Tls(tls::TlsError),
+        Peer(PeerError)
     }
 );
 
+impl From<CallError> for AttendError {
+    fn from(e: CallError) -> Self {
+        match e {
+            CallError::Door(e) => AttendError::Door(e),
+            CallError::CircuitOpen(e) => AttendError::CircuitOpen(e)
+        }
+    }
+}
+
+/// How long an HTTP client may dawdle between bytes of its request.
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Describe a TCP connection for the door which will handle it.
+fn envelope(protocol: Protocol, stream: &net::TcpStream) -> Envelope {
+    Envelope {
+        protocol: Some(protocol),
+        peer: stream.peer_addr().ok(),
+        local: stream.local_addr().ok(),
+        server_name: None
+    }
+}
+
+/// Where a `tcp`, `udp`, or `tls` statement sends its traffic.
+pub enum Destination {
+    /// One of this host's own doors
+    Pool(Balancer),
+    /// Another PortunusD host, which has doors of its own
+    Peer(Peer)
+}
+
+/// A pool of threads sharing one bounded queue of work.
+///
+/// `max_inflight` threads take turns receiving from the queue, so that many pieces of work can be
+/// underway at once. Up to `queue_depth` more can wait for a free thread; beyond that,
+/// [`Staff::send`] hands the work back so the caller can turn it away. A thread whose work panics
+/// keeps receiving.
+pub struct Staff<T> {
+    sender: mpsc::SyncSender<T>,
+    join_handles: Vec<thread::JoinHandle<()>>
+}
+
+impl<T: Send + 'static> Staff<T> {
+    pub fn hire<F>(limits: &Limits, work: F) -> Self
+    where F: Fn(T) + Send + Sync + 'static {
+        let (sender, receiver) = mpsc::sync_channel(limits.queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let work = Arc::new(work);
+        let join_handles = (0..limits.max_inflight.max(1)).map(|_| {
+            let receiver = Arc::clone(&receiver);
+            let work = Arc::clone(&work);
+            thread::spawn(move|| loop {
+                // Only hold the lock while waiting, so the others can work in the meantime
+                let next = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(poisoned) => poisoned.into_inner().recv()
+                };
+                match next {
+                    // One bad piece of work must not cost us a thread for good
+                    Ok(item) => if panic::catch_unwind(AssertUnwindSafe(|| work(item))).is_err() {
+                        eprintln!("Attendant panicked; carrying on");
+                    },
+                    Err(_) => break
+                }
+            })
+        }).collect();
+        Self{ sender, join_handles }
+    }
+
+    /// Queue `item` for the next free thread, unless the queue is full.
+    pub fn send(&self, item: T) -> Result<(), mpsc::TrySendError<T>> {
+        self.sender.try_send(item)
+    }
+
+    /// Wait for the staff to finish whatever they have already been sent, and then stop.
+    pub fn join(self) -> Result<(), Box<dyn any::Any + Send + 'static>> {
+        drop(self.sender);
+        let mut result = Ok(());
+        for join_handle in self.join_handles {
+            if let Err(e) = join_handle.join() {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+
 pub struct DoorAttendant {
-    pub sender: mpsc::Sender<net::TcpStream>,
-    pub join_handle: thread::JoinHandle<()>
+    staff: Staff<net::TcpStream>
 }
 
 impl DoorAttendant {
-    pub fn new(doorc: doors::ClientRef) -> Self {
-        let (sender, mut receiver) = mpsc::channel();
-        let join_handle = thread::spawn(move|| {
-            loop {
-                if let Err(e) = Self::attend(&mut receiver, doorc) {
-                    eprintln!("Door error: {:?}", e);
-                    let name = std::ffi::CString::new("Door problem").expect("CString::new failed");
-                    unsafe{ libc::perror(name.as_ptr()) };
+    pub fn new(destination: Destination, meter: Arc<Meter>, limits: &Limits) -> Self {
+        let staff = Staff::hire(limits, move|client| {
+            if let Err(e) = Self::attend(client, &destination, &meter) {
+                eprintln!("Door error: {:?}", e);
+            }
+        });
+        Self{ staff }
+    }
+
+    /// Hand `client` to the door. If the door takes too long, hang up on the client, since
+    /// nobody will be answering.
+    ///
+    /// If the destination is a peer, the connection is spliced to the peer instead, which hands it
+    /// to a door of its own. The splice runs on the attendant's own thread, so it counts against
+    /// `max_inflight` until the connection closes.
+    pub fn attend(client: net::TcpStream, destination: &Destination, meter: &Meter) -> Result<(), AttendError> {
+        meter.dequeue();
+        let envelope = envelope(Protocol::Tcp, &client);
+        match destination {
+            Destination::Pool(pool) => {
+                let doorc = pool.choose(envelope.peer.map(|peer| peer.ip()));
+                let result = doorc.call(meter, vec![Descriptor::Duplicate(client.as_fd())], &envelope.encode(&[]));
+                hang_up_if_abandoned(&client, &result);
+                result?;
+            },
+            Destination::Peer(peer) => {
+                let upstream = peer.send(&envelope.encode(&[]))?;
+                tls::shuttle(upstream, client, &Meter::default());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn send(&self, stream: net::TcpStream) -> Result<(), mpsc::TrySendError<net::TcpStream>> {
+        self.staff.send(stream)
+    }
+
+    /// Wait for the attendant to finish whatever it has already been sent, and then stop.
+    pub fn join(self) -> Result<(), Box<dyn any::Any + Send + 'static>> {
+        self.staff.join()
+    }
+}
+
+
+/// Shut down a connection whose door call was abandoned, even if the door still has a copy of it.
+fn hang_up_if_abandoned<T>(client: &net::TcpStream, result: &Result<T, CallError>) {
+    if matches!(result, Err(e) if e.timed_out()) {
+        let _ = client.shutdown(net::Shutdown::Both);
+    }
+}
+
+
+/// A single UDP packet, and who sent it.
+pub struct Datagram {
+    pub peer: net::SocketAddr,
+    pub payload: Vec<u8>
+}
+
+impl Datagram {
+    /// Build the door request for this datagram, which arrived on a socket bound to `local`.
+    ///
+    /// The door receives an [`Envelope`], followed by the datagram exactly as it arrived. See the
+    /// [DPA](https://github.com/robertdfrench/portunusd/blob/trunk/etc/DPA.md).
+    pub fn to_request(&self, local: Option<net::SocketAddr>) -> Vec<u8> {
+        let envelope = Envelope {
+            protocol: Some(Protocol::Udp),
+            peer: Some(self.peer),
+            local,
+            server_name: None
+        };
+        envelope.encode(&self.payload)
+    }
+}
+
+/// Like a [`DoorAttendant`], but for UDP.
+///
+/// Each datagram becomes one door call, and the door's response (if it is not empty) is sent back
+/// to the peer from the same socket on which the datagram arrived.
+pub struct DatagramAttendant {
+    staff: Staff<Datagram>
+}
+
+impl DatagramAttendant {
+    pub fn new(destination: Destination, socket: net::UdpSocket, meter: Arc<Meter>, limits: &Limits) -> Self {
+        let local = socket.local_addr().ok();
+        let staff = Staff::hire(limits, move|datagram| {
+            if let Err(e) = Self::attend(datagram, &destination, &socket, local, &meter) {
+                eprintln!("Door error: {:?}", e);
+            }
+        });
+        Self{ staff }
+    }
+
+    pub fn attend(
+        datagram: Datagram,
+        destination: &Destination,
+        socket: &net::UdpSocket,
+        local: Option<net::SocketAddr>,
+        meter: &Meter
+    ) -> Result<(), AttendError> {
+        meter.dequeue();
+        let request = datagram.to_request(local);
+        let response = match destination {
+            Destination::Pool(pool) => {
+                let doorc = pool.choose(Some(datagram.peer.ip()));
+                // Any descriptors are dropped, since there is nobody to give them to
+                let (_descriptors, response) = doorc.call(meter, vec![], &request)?;
+                response
+            },
+            Destination::Peer(peer) => peer.call(&request)?
+        };
+        if !response.is_empty() {
+            meter.sent(socket.send_to(&response, datagram.peer)?);
+        }
+        Ok(())
+    }
+
+    pub fn send(&self, datagram: Datagram) -> Result<(), mpsc::TrySendError<Datagram>> {
+        self.staff.send(datagram)
+    }
+
+    /// Wait for the attendant to finish whatever it has already been sent, and then stop.
+    pub fn join(self) -> Result<(), Box<dyn any::Any + Send + 'static>> {
+        self.staff.join()
+    }
+}
+
+
+/// Like a [`DoorAttendant`], but for HTTP.
+///
+/// Rather than handing the connection to a door, the `HttpAttendant` reads a request itself and
+/// uses an [`Atlas`] to choose which door should answer it. The door receives the (normalized)
+/// request bytes and returns the complete response bytes, which are written back to the client
+/// before the connection is closed.
+///
+/// If given a TLS configuration, the attendant completes a handshake before reading the request,
+/// which is how `https` works.
+pub struct HttpAttendant {
+    staff: Staff<net::TcpStream>
+}
+
+impl HttpAttendant {
+    pub fn new(
+        atlas: Atlas,
+        doors: HashMap<PathBuf, MeteredDoor>,
+        tls: Option<Arc<ServerConfig>>,
+        meter: Arc<Meter>,
+        limits: &Limits
+    ) -> Self {
+        let staff = Staff::hire(limits, move|stream| {
+            if let Err(e) = Self::attend(stream, &atlas, &doors, tls.as_ref(), &meter) {
+                eprintln!("Door error: {:?}", e);
+            }
+        });
+        Self{ staff }
+    }
+
+    pub fn attend(
+        stream: net::TcpStream,
+        atlas: &Atlas,
+        doors: &HashMap<PathBuf, MeteredDoor>,
+        tls: Option<&Arc<ServerConfig>>,
+        meter: &Meter
+    ) -> Result<(), AttendError> {
+        meter.dequeue();
+        stream.set_read_timeout(Some(HTTP_READ_TIMEOUT))?;
+        match tls {
+            None => {
+                let envelope = envelope(Protocol::Http, &stream);
+                Self::serve(stream, &envelope, atlas, doors, meter)
+            },
+            Some(config) => {
+                let mut envelope = envelope(Protocol::Https, &stream);
+                let mut stream = match tls::accept(config, stream) {
+                    Ok(stream) => stream,
+                    // A failed handshake is the client's problem, not ours
+                    Err(_) => return Ok(())
+                };
+                envelope.server_name = stream.conn.server_name().map(|name| name.to_owned());
+                let result = Self::serve(&mut stream, &envelope, atlas, doors, meter);
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+                result
+            }
+        }
+    }
+
+    /// Read one request, and write back one response.
+    fn serve<S: Read + Write>(
+        mut stream: S,
+        envelope: &Envelope,
+        atlas: &Atlas,
+        doors: &HashMap<PathBuf, MeteredDoor>,
+        meter: &Meter
+    ) -> Result<(), AttendError> {
+        let result = match Request::read_from(&mut io::BufReader::new(&mut stream)) {
+            Ok(request) => {
+                meter.received(request.to_bytes().len());
+                Self::forward(&request, envelope, atlas, doors, meter)
+            },
+            Err(e) => match e.status() {
+                Some(status) => Ok(http::response(status, &[])),
+                // The client hung up or stalled, so there is nobody to answer
+                None => return Ok(())
+            }
+        };
+        let response = match &result {
+            Ok(response) => response.clone(),
+            Err(AttendError::Door(e)) if e.timed_out() => http::response(504, &[]),
+            // The door's circuit is open, so don't keep the client waiting
+            Err(AttendError::CircuitOpen(_)) => http::response(503, &[]),
+            Err(_) => http::response(502, &[])
+        };
+        stream.write_all(&response)?;
+        meter.sent(response.len());
+        result.map(|_| ())
+    }
+
+    /// Route a request through the Atlas and call the chosen door.
+    fn forward(
+        request: &Request,
+        envelope: &Envelope,
+        atlas: &Atlas,
+        doors: &HashMap<PathBuf, MeteredDoor>,
+        meter: &Meter
+    ) -> Result<Vec<u8>, AttendError> {
+        match atlas.route(&request.method, request.path()) {
+            Route::NotFound => Ok(http::response(404, &[])),
+            Route::MethodNotAllowed(allowed) => {
+                let allowed: Vec<String> = allowed.iter().map(|m| m.to_string()).collect();
+                Ok(http::response(405, &[("Allow", allowed.join(", "))]))
+            },
+            Route::Door(path) => {
+                let door = doors.get(path).ok_or(doors::Error::DoorCall(libc::EBADF))?;
+                // There is nobody to give any descriptors to, so they are dropped
+                let (_descriptors, response) = door.call(meter, vec![], &envelope.encode(&request.to_bytes()))?;
+                if response.is_empty() {
+                    // Per the DPA, an empty response means the application gave up
+                    return Ok(http::response(502, &[]));
                 }
+                Ok(response)
+            }
+        }
+    }
+
+    pub fn send(&self, stream: net::TcpStream) -> Result<(), mpsc::TrySendError<net::TcpStream>> {
+        self.staff.send(stream)
+    }
+
+    /// Wait for the attendant to finish whatever it has already been sent, and then stop.
+    pub fn join(self) -> Result<(), Box<dyn any::Any + Send + 'static>> {
+        self.staff.join()
+    }
+}
+
+
+/// Like a [`DoorAttendant`], but for TLS.
+///
+/// The attendant completes the handshake, and then hands the door one end of a UNIX socket pair
+/// instead of the client connection itself. A [`tls::splice`] thread decrypts traffic from the
+/// client into the socket pair, and encrypts traffic from the door back out to the client.
+pub struct TlsAttendant {
+    staff: Staff<net::TcpStream>
+}
+
+impl TlsAttendant {
+    pub fn new(destination: Destination, tls: Arc<ServerConfig>, meter: Arc<Meter>, limits: &Limits) -> Self {
+        let staff = Staff::hire(limits, move|client| {
+            match Self::attend(client, &destination, &tls, &meter) {
+                // A failed handshake is the client's problem, not ours
+                Err(AttendError::Tls(_)) => {},
+                Err(e) => eprintln!("Door error: {:?}", e),
+                Ok(()) => {}
+            }
+        });
+        Self{ staff }
+    }
+
+    pub fn attend(
+        client: net::TcpStream,
+        destination: &Destination,
+        tls: &Arc<ServerConfig>,
+        meter: &Arc<Meter>
+    ) -> Result<(), AttendError> {
+        meter.dequeue();
+        let mut envelope = envelope(Protocol::Tls, &client);
+        let hang_up = client.try_clone()?;
+        let stream = tls::accept(tls, client)?;
+        envelope.server_name = stream.conn.server_name().map(|name| name.to_owned());
+        let (ours, theirs) = UnixStream::pair()?;
+        let downstream = tls::splice(stream, ours, Arc::clone(meter));
+        match destination {
+            Destination::Pool(pool) => {
+                let doorc = pool.choose(envelope.peer.map(|peer| peer.ip()));
+                let result = doorc.call(meter, vec![Descriptor::release(theirs)], &envelope.encode(&[]));
+                hang_up_if_abandoned(&hang_up, &result);
+                result?;
+            },
+            Destination::Peer(peer) => {
+                // Decrypted here, and encrypted again for the peer. Stay with the connection until
+                // it closes, so that it counts against `max_inflight`.
+                let upstream = peer.send(&envelope.encode(&[]))?;
+                tls::shuttle(upstream, theirs, &Meter::default());
+                let _ = downstream.join();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn send(&self, stream: net::TcpStream) -> Result<(), mpsc::TrySendError<net::TcpStream>> {
+        self.staff.send(stream)
+    }
+
+    /// Wait for the attendant to finish whatever it has already been sent, and then stop.
+    pub fn join(self) -> Result<(), Box<dyn any::Any + Send + 'static>> {
+        self.staff.join()
+    }
+}
+
+
+/// Like a [`TlsAttendant`], but for traffic forwarded by a peer.
+///
+/// The peer has already described the original client in an [`Envelope`], which is passed on to
+/// the door as-is. `tcp` and `tls` requests get one end of a UNIX socket pair, spliced to the
+/// peer's connection; anything else gets a single response, which is sent back to the peer.
+pub struct PeerAttendant {
+    staff: Staff<net::TcpStream>
+}
+
+impl PeerAttendant {
+    pub fn new(pool: Balancer, tls: Arc<ServerConfig>, meter: Arc<Meter>, limits: &Limits) -> Self {
+        let staff = Staff::hire(limits, move|client| {
+            match Self::attend(client, &pool, &tls, &meter) {
+                // Hosts we do not know are turned away during the handshake
+                Err(AttendError::Tls(_)) => {},
+                Err(e) => eprintln!("Peer error: {:?}", e),
+                Ok(()) => {}
             }
         });
-        Self{ sender, join_handle }
+        Self{ staff }
     }
 
-    pub fn attend(receiver: &mut mpsc::Receiver<net::TcpStream>, doorc: doors::ClientRef) -> Result<(), AttendError> {
-        let client = receiver.recv()?;
-        doorc.call(vec![client.into_raw_fd()], &vec![])?;
+    pub fn attend(
+        client: net::TcpStream,
+        pool: &Balancer,
+        tls: &Arc<ServerConfig>,
+        meter: &Arc<Meter>
+    ) -> Result<(), AttendError> {
+        meter.dequeue();
+        let hang_up = client.try_clone()?;
+        let mut stream = tls::accept(tls, client)?;
+        let (envelope, request) = peer::receive(&mut stream)?;
+        let doorc = pool.choose(envelope.peer.map(|peer| peer.ip()));
+        match envelope.protocol {
+            Some(Protocol::Tcp | Protocol::Tls) => {
+                let (ours, theirs) = UnixStream::pair()?;
+                tls::splice(stream, ours, Arc::clone(meter));
+                let result = doorc.call(meter, vec![Descriptor::release(theirs)], &request);
+                hang_up_if_abandoned(&hang_up, &result);
+                result?;
+            },
+            _ => {
+                let (_descriptors, response) = doorc.call(meter, vec![], &request)?;
+                peer::respond(stream, &response)?;
+                meter.sent(response.len());
+            }
+        }
         Ok(())
     }
 
-    pub fn send(&self, stream: net::TcpStream) -> Result<(), mpsc::SendError<net::TcpStream>> {
-        self.sender.send(stream)
+    pub fn send(&self, stream: net::TcpStream) -> Result<(), mpsc::TrySendError<net::TcpStream>> {
+        self.staff.send(stream)
     }
 
-    pub fn join(self) -> Result<(), Box<(dyn any::Any + Send + 'static)>> {
-        self.join_handle.join()
+    /// Wait for the attendant to finish whatever it has already been sent, and then stop.
+    pub fn join(self) -> Result<(), Box<dyn any::Any + Send + 'static>> {
+        self.staff.join()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staff_survive_panics() {
+        let limits = Limits{ max_inflight: 1, ..Limits::default() };
+        let (done, finished) = mpsc::channel();
+        let staff = Staff::hire(&limits, move|n: u32| {
+            assert!(n != 0, "zero is a bad piece of work");
+            done.send(n).unwrap();
+        });
+        staff.send(0).unwrap();
+        staff.send(1).unwrap();
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok(1));
+        staff.join().unwrap();
     }
 }
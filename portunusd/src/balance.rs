@@ -0,0 +1,186 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Load Balancing
+//!
+//! A `tcp`, `udp`, or `tls` statement forwards to one door, or to a [`Pool`] of copies of the same
+//! application. Either way, its attendant asks a [`Balancer`] which door should take each
+//! connection (or datagram); a single door is simply a pool of one. Members whose circuit is open
+//! (see [`crate::health`]) are skipped, unless every member's circuit is open, or until the
+//! circuit is ready to let a call through to see whether the member has come back.
+//!
+//! [`Pool`]: crate::config::Pool
+
+// Types
+use crate::config::Strategy;
+use crate::health::Health;
+use crate::metrics::MeteredDoor;
+use std::collections::hash_map::DefaultHasher;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Traits
+use std::hash::{Hash, Hasher};
+
+
+/// Chooses a member of a pool for each connection.
+pub struct Balancer {
+    strategy: Strategy,
+    members: Vec<MeteredDoor>,
+    /// How many turns have been taken, for [`Strategy::RoundRobin`]. Modulo the number of
+    /// members, it is the index of the member whose turn is next.
+    turns: AtomicUsize
+}
+
+impl Balancer {
+    /// Balance traffic across `members`, which must not be empty.
+    pub fn new(strategy: Strategy, members: Vec<MeteredDoor>) -> Self {
+        assert!(!members.is_empty(), "a pool needs at least one door");
+        Self{ strategy, members, turns: AtomicUsize::new(0) }
+    }
+
+    /// The door which should take a connection from `client`.
+    ///
+    /// `client` only matters for [`Strategy::ConsistentHash`]. Each client address goes to the
+    /// healthy member which scores highest for it, so when a member's circuit opens, only its
+    /// own clients move elsewhere.
+    pub fn choose(&self, client: Option<IpAddr>) -> &MeteredDoor {
+        let healthy = |door: &&MeteredDoor| !matches!(door.health(), Health::Open(_)) || door.retry_due();
+        let chosen = match self.strategy {
+            // Members which are skipped lose their turn, so the healthy ones keep alternating
+            Strategy::RoundRobin => (0..self.members.len())
+                .map(|_| &self.members[self.turns.fetch_add(1, Ordering::Relaxed) % self.members.len()])
+                .find(healthy),
+            Strategy::LeastInflight => self.members.iter()
+                .filter(healthy)
+                .min_by_key(|door| door.inflight()),
+            Strategy::ConsistentHash => self.members.iter()
+                .filter(healthy)
+                .max_by_key(|door| {
+                    let mut hasher = DefaultHasher::new();
+                    (client, door.path()).hash(&mut hasher);
+                    hasher.finish()
+                })
+        };
+        chosen.unwrap_or(&self.members[0])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health;
+    use crate::metrics::Meter;
+    use crate::relay::tests::Ping;
+    use doors::ServerProcedure;
+    use doors::Descriptor;
+    use std::os::fd::OwnedFd;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn stall(_descriptors: Vec<OwnedFd>, _request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        thread::sleep(Duration::from_millis(500));
+        (vec![], vec![])
+    }
+    doors::derive_server_procedure!(stall as Stall);
+
+    fn install<P: ServerProcedure>(name: &str) -> (PathBuf, doors::Server, MeteredDoor) {
+        let (path, server) = crate::relay::tests::install::<P>(&format!("portunusd_balance_test.{}", name));
+        let door = MeteredDoor::new(health::open(&path).unwrap());
+        (path, server, door)
+    }
+
+    /// Make a member's application go away, and probe it until its circuit opens.
+    fn fail(server: doors::Server, door: &Arc<health::Door>) {
+        drop(server);
+        while !matches!(door.probe(), Health::Open(_)) {}
+    }
+
+    #[test]
+    fn members_take_turns_until_their_circuit_opens() {
+        let (a, a_server, a_door) = install::<Ping>("5e1f02");
+        let (b, _b_server, b_door) = install::<Ping>("5e1f03");
+        let (c, _c_server, c_door) = install::<Ping>("5e1f04");
+        let balancer = Balancer::new(Strategy::RoundRobin, vec![a_door, b_door, c_door]);
+
+        let chosen: Vec<PathBuf> = (0..4).map(|_| balancer.choose(None).path().to_path_buf()).collect();
+        assert_eq!(chosen, vec![a.clone(), b.clone(), c.clone(), a.clone()]);
+
+        fail(a_server, &health::open(&a).unwrap());
+        let chosen: Vec<PathBuf> = (0..4).map(|_| balancer.choose(None).path().to_path_buf()).collect();
+        assert_eq!(chosen, vec![b.clone(), c.clone(), b, c]);
+    }
+
+    #[test]
+    fn members_come_back_without_a_prober() {
+        let (a, a_server, a_door) = install::<Ping>("5e1f09");
+        let (b, _b_server, b_door) = install::<Ping>("5e1f0a");
+        let balancer = Balancer::new(Strategy::RoundRobin, vec![a_door, b_door]);
+        let meter = Meter::default();
+
+        // The first member's application goes away, and its calls fail until its circuit opens
+        drop(a_server);
+        let a_health = health::open(&a).unwrap();
+        while !matches!(a_health.health(), Health::Open(_)) {
+            let _ = balancer.choose(None).call(&meter, vec![], &[]);
+        }
+        let chosen: Vec<PathBuf> = (0..2).map(|_| balancer.choose(None).path().to_path_buf()).collect();
+        assert_eq!(chosen, vec![b.clone(), b.clone()]);
+
+        // Once it comes back, its next turn after the retry is due finds it
+        let (_, _a_server, _) = install::<Ping>("5e1f09");
+        a_health.expire();
+        let mut door = balancer.choose(None);
+        if door.path() != a {
+            door = balancer.choose(None);
+        }
+        assert_eq!(door.path(), a);
+        door.call(&meter, vec![], &[]).unwrap();
+        assert_eq!(a_health.health(), Health::Healthy);
+    }
+
+    #[test]
+    fn the_least_busy_member_is_chosen() {
+        let (a, _a_server, a_door) = install::<Stall>("5e1f05");
+        let (b, _b_server, b_door) = install::<Stall>("5e1f06");
+        let balancer = Arc::new(Balancer::new(Strategy::LeastInflight, vec![a_door, b_door]));
+        assert_eq!(balancer.choose(None).path(), a);
+
+        // While a call to the first member is underway, the second is less busy
+        let caller = {
+            let balancer = Arc::clone(&balancer);
+            thread::spawn(move|| balancer.choose(None).call(&Meter::default(), vec![], &[]))
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(balancer.choose(None).path(), b);
+        caller.join().unwrap().unwrap();
+        assert_eq!(balancer.choose(None).path(), a);
+    }
+
+    #[test]
+    fn clients_stick_to_one_member_while_it_is_healthy() {
+        let (a, a_server, a_door) = install::<Ping>("5e1f07");
+        let (b, _b_server, b_door) = install::<Ping>("5e1f08");
+        let balancer = Balancer::new(Strategy::ConsistentHash, vec![a_door, b_door]);
+
+        let clients: Vec<IpAddr> = (1..=32).map(|n| IpAddr::from([192, 0, 2, n])).collect();
+        let chosen: Vec<PathBuf> = clients.iter().map(|client| balancer.choose(Some(*client)).path().to_path_buf()).collect();
+        for (client, door) in clients.iter().zip(&chosen) {
+            assert_eq!(balancer.choose(Some(*client)).path(), door);
+        }
+        assert!(chosen.contains(&a) && chosen.contains(&b));
+
+        // When the first member's circuit opens, its clients move to the second
+        fail(a_server, &health::open(&a).unwrap());
+        for client in &clients {
+            assert_eq!(balancer.choose(Some(*client)).path(), b);
+        }
+    }
+}
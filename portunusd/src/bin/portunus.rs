@@ -8,6 +8,12 @@
 //! Portunus Controller
 
 // Types
+use portunusd::config::Config;
+use portunusd::control::{self, ClientError};
+use portunusd::health::Health;
+use portunusd::metrics::Snapshot;
+use std::time::Duration;
+use std::fs;
 use std::io;
 use std::path;
 use std::process;
@@ -22,7 +28,7 @@ use clap::ValueEnum;
 define_error_enum!(
     pub enum MainError {
         Door(doors::Error),
-        Utf8(std::string::FromUtf8Error),
+        Control(ClientError),
         Io(io::Error)
     }
 );
@@ -38,6 +44,14 @@ struct Cli {
     #[arg(short, long, value_name = "FILE")]
     portunusd: Option<path::PathBuf>,
 
+    /// Override config file passed to portunusd
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<path::PathBuf>,
+
+    /// Print stats as JSON rather than as a table
+    #[arg(long)]
+    json: bool,
+
     #[arg(value_enum)]
     mode: Mode,
 }
@@ -50,25 +64,94 @@ enum Mode {
     /// Start portunusd if not already running
     Start,
 
-    /// Stop portunusd if it is running
+    /// Stop portunusd if it is running, once open connections have finished
     Stop,
 
+    /// List each listener, and the doors it forwards to
+    Listeners,
+
+    /// Show connections, calls, latencies, errors, and bytes for each listener and door
+    Stats,
+
+    /// Re-read the config file without dropping connections
+    Reload,
+
+    /// Stop every listener, and wait for open connections to finish, but leave portunusd up
+    Drain,
+
     /// Print the version of portunus
-    Version
+    Version,
+
+    /// Report every problem with the config file, and exit non-zero if there are any
+    CheckConfig
+}
+
+/// Describe a latency quantile, which is only known to within a histogram bucket.
+fn latency(quantile: Option<Duration>, count: u64) -> String {
+    match quantile {
+        _ if count == 0 => "-".to_owned(),
+        Some(bound) if bound < Duration::from_millis(1) => format!("<={}us", bound.as_micros()),
+        Some(bound) => format!("<={}ms", bound.as_millis()),
+        None => ">5000ms".to_owned()
+    }
+}
+
+/// One row of the stats table.
+fn row(name: &str, metrics: &Snapshot) -> String {
+    let count = metrics.latency.count();
+    let errors: u64 = metrics.errors.iter().map(|(_, count)| count).sum();
+    format!("{:<32} {:>9} {:>7} {:>8} {:>9} {:>9} {:>7} {:>12} {:>12}",
+        name, metrics.accepted, metrics.active, metrics.rejected,
+        latency(metrics.latency.quantile(0.5), count),
+        latency(metrics.latency.quantile(0.99), count),
+        errors, metrics.bytes_in, metrics.bytes_out)
+}
+
+/// Print stats as two tables: one for listeners, and one for doors.
+fn print_stats(stats: &control::Stats) {
+    let header = |kind: &str, accepted: &str| format!("{:<32} {:>9} {:>7} {:>8} {:>9} {:>9} {:>7} {:>12} {:>12}",
+        kind, accepted, "ACTIVE", "REJECTED", "P50", "P99", "ERRORS", "BYTES IN", "BYTES OUT");
+    println!("{}", header("LISTENER", "ACCEPTED"));
+    for listener in &stats.listeners {
+        println!("{}", row(&format!("{} {}", listener.protocol, listener.address), &listener.metrics));
+    }
+    println!();
+    println!("{}", header("DOOR", "CALLS"));
+    for door in &stats.doors {
+        let name = match door.health {
+            Health::Healthy => door.path.display().to_string(),
+            health => format!("{} ({})", door.path.display(), health)
+        };
+        println!("{}", row(&name, &door.metrics));
+        for (errno, count) in &door.metrics.errors {
+            println!("    {} x {}", count, std::io::Error::from_raw_os_error(*errno));
+        }
+    }
+}
+
+/// Explain a failed control request, and give up.
+fn fail(e: ClientError) -> ! {
+    match e {
+        ClientError::Door(e) => eprintln!("portunusd is down: {:?}", e),
+        ClientError::Control(e) => eprintln!("portunusd refused: {}", e)
+    }
+    process::exit(1);
 }
 
 fn main() -> Result<(),MainError> {
     let cli = Cli::parse();
+    let door_path = cli.door.unwrap_or(path::Path::new("/var/run/portunusd.door").to_path_buf());
+    let connect = || control::Client::new(&door_path).unwrap_or_else(|e| fail(e.into()));
 
     match cli.mode {
         Mode::Status => {
-            let door_path = cli.door.unwrap_or(path::Path::new("/var/run/portunusd.door").to_path_buf());
-
-            match doors::Client::new(door_path) {
-                Ok(portunusd_client) => {
-                    let (_descriptors, content) = portunusd_client.call(vec![], &vec![])?;
-                    let response = String::from_utf8(content)?;
-                    println!("portunusd is up: {}", response);
+            match control::Client::new(&door_path).map_err(ClientError::from).and_then(|client| client.status()) {
+                Ok(status) => {
+                    println!("portunusd {} is up: pid {}, {} listeners, up {}s, config {}",
+                        status.version, status.pid, status.listeners, status.uptime.as_secs(), status.config.display());
+                    for (path, health) in &status.doors {
+                        println!("    {}: {}", path.display(), health);
+                    }
                 },
                 Err(e) => {
                     println!("portunusd is down: {:?}", e);
@@ -76,19 +159,19 @@ fn main() -> Result<(),MainError> {
             }
         },
         Mode::Start => {
-            let door_path = cli.door.unwrap_or(path::Path::new("/var/run/portunusd.door").to_path_buf());
-
-            let needs_to_be_started = match doors::Client::new(door_path.clone()) {
-                Ok(portunusd_client) => !portunusd_client.call(vec![], &vec![]).is_ok(),
+            let needs_to_be_started = match control::Client::new(&door_path) {
+                Ok(portunusd_client) => portunusd_client.status().is_err(),
                 Err(_) => true
             };
 
             if needs_to_be_started {
                 let portunusd_path = cli.portunusd.unwrap_or(path::Path::new("/usr/sbin/portunusd").to_path_buf());
-                let portunusd_output = process::Command::new(portunusd_path)
-                    .arg("--door")
-                    .arg(door_path)
-                    .output()?;
+                let mut portunusd_command = process::Command::new(portunusd_path);
+                portunusd_command.arg("--door").arg(&door_path);
+                if let Some(config_path) = cli.config {
+                    portunusd_command.arg("--config").arg(config_path);
+                }
+                let portunusd_output = portunusd_command.output()?;
                 if portunusd_output.status.success() {
                     println!("Started the portunusd server");
                 } else {
@@ -99,19 +182,60 @@ fn main() -> Result<(),MainError> {
             }
         },
         Mode::Stop => {
-            let door_path = cli.door.unwrap_or(path::Path::new("/var/run/portunusd.door").to_path_buf());
-
-            match doors::Client::new(door_path.clone()) {
-                Ok(portunusd_client) => {
-                    portunusd_client.call(vec![], &vec![69])?;
+            match control::Client::new(&door_path) {
+                Ok(portunusd_client) => match portunusd_client.stop() {
+                    Ok(()) => println!("Stopping the portunusd server"),
+                    Err(e) => fail(e)
                 },
                 Err(_) => {
                     println!("This thing isn't even running anymore bud");
                 }
             }
-        }
+        },
+        Mode::Listeners => {
+            for listener in connect().listeners().unwrap_or_else(|e| fail(e)) {
+                let doors: Vec<String> = listener.doors.iter().map(|door| door.display().to_string()).collect();
+                println!("{} {} -> {}", listener.protocol, listener.address, doors.join(", "));
+            }
+        },
+        Mode::Stats => {
+            let stats = connect().stats().unwrap_or_else(|e| fail(e));
+            match cli.json {
+                true => println!("{}", stats.to_json()),
+                false => print_stats(&stats)
+            }
+        },
+        Mode::Reload => {
+            let changes = connect().reload().unwrap_or_else(|e| fail(e));
+            println!("Reloaded: {}", changes);
+        },
+        Mode::Drain => {
+            match connect().drain().unwrap_or_else(|e| fail(e)) {
+                0 => println!("Drained every listener"),
+                abandoned => println!("Drained, but gave up waiting on {} listeners", abandoned)
+            }
+        },
         Mode::Version => {
             println!("{}", env!("CARGO_PKG_VERSION"));
+        },
+        Mode::CheckConfig => {
+            let config_path = cli.config.unwrap_or(path::Path::new("/opt/local/etc/portunusd.conf").to_path_buf());
+            let config: Config = match fs::read_to_string(&config_path)?.parse() {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("{}", e.in_file(&config_path));
+                    process::exit(1);
+                }
+            };
+
+            let problems = config.validate();
+            for problem in &problems {
+                eprintln!("{}: {}", config_path.display(), problem);
+            }
+            if !problems.is_empty() {
+                process::exit(1);
+            }
+            println!("{} is ok", config_path.display());
         }
     }
 
@@ -14,16 +14,19 @@
 
 
 use std::collections::HashMap;
+use std::fmt;
 use std::net::AddrParseError;
 use std::net::SocketAddr;
+use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 
 /// HTTP Request Methods
 ///
 /// See <https://developer.mozilla.org/en-US/docs/Web/HTTP/Methods>
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
 pub enum Method {
     GET,
     HEAD,
@@ -37,18 +40,123 @@ pub enum Method {
 }
 
 /// Generic Parsing Error
+///
+/// Each parser splits its input on whitespace, and records which of those tokens it choked on.
+/// Callers which handed a parser only part of their own input shift that index with
+/// [`ParseError::at`], so that by the time the error reaches [`Config`] it identifies a token in
+/// the original statement. [`Config`] then turns the index into a [`Location`].
 #[derive(Debug,PartialEq)]
-pub struct ParseError(String);
+pub struct ParseError {
+    message: String,
+    token: Option<usize>,
+    location: Option<Box<Location>>
+}
+
+/// Where in a config file a [`ParseError`] happened.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Location {
+    pub file: Option<PathBuf>,
+    /// Counting from 1
+    pub line: usize,
+    /// Counting from 1, in characters
+    pub column: usize,
+    /// The offending token, or an empty string if the statement ended too soon
+    pub token: String,
+    /// The whole line containing the offending token
+    pub source: String
+}
 
 macro_rules! parse_error {
-    ( $onlystr:expr ) => {
-        Err(ParseError($onlystr.to_owned()))
+    ( @ $token:expr, $($format:tt)+ ) => {
+        Err(ParseError::new(format!($($format)+)).at($token))
     };
-    ( $fmtstr:expr, $($parameters:expr), + ) => {
-        Err(ParseError(format!($fmtstr, $($parameters), +)))
+    ( $($format:tt)+ ) => {
+        Err(ParseError::new(format!($($format)+)))
     };
 }
 
+impl ParseError {
+    fn new(message: String) -> Self {
+        Self{ message, token: None, location: None }
+    }
+
+    /// Say that this error came from a parser which was given the input starting at token
+    /// `offset`.
+    ///
+    /// If the inner parser did not know which token was at fault, then the whole of its input
+    /// was to blame, so the error points at its first token.
+    fn at(mut self, offset: usize) -> Self {
+        self.token = Some(self.token.unwrap_or(0) + offset);
+        self
+    }
+
+    /// Record the name of the file that was being parsed.
+    pub fn in_file<P: AsRef<Path>>(mut self, file: P) -> Self {
+        if let Some(location) = self.location.as_mut() {
+            location.file = Some(file.as_ref().to_path_buf());
+        }
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_deref()
+    }
+
+    /// Attach a location, given the positioned tokens of the statement that failed to parse.
+    fn locate(mut self, tokens: &[Token], lines: &[&str]) -> Self {
+        let token = match (self.token, tokens.last()) {
+            (Some(index), _) if index < tokens.len() => tokens[index].clone(),
+            (_, Some(last)) => {
+                // The statement ended before the parser got what it wanted, so point just past it
+                let column = last.column + last.text.chars().count();
+                Token{ line: last.line, column, text: "" }
+            },
+            (_, None) => return self
+        };
+        self.location = Some(Box::new(Location {
+            file: None,
+            line: token.line + 1,
+            column: token.column + 1,
+            token: token.text.to_owned(),
+            source: lines[token.line].to_owned()
+        }));
+        self
+    }
+}
+
+/// A whitespace-delimited word in a config file, and where to find it. Lines and columns count
+/// from 0.
+#[derive(Clone)]
+struct Token<'a> {
+    line: usize,
+    column: usize,
+    text: &'a str
+}
+
+/// Split a line into [`Token`]s the same way `split_whitespace` would.
+fn tokenize(line: usize, text: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut start: Option<(usize, usize)> = None;
+    for (column, (offset, c)) in text.char_indices().enumerate() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((column, offset)),
+            (true, Some((start_column, start_offset))) => {
+                tokens.push(Token{ line, column: start_column, text: &text[start_offset..offset] });
+                start = None;
+            },
+            _ => {}
+        }
+    }
+    if let Some((column, offset)) = start {
+        tokens.push(Token{ line, column, text: &text[offset..] });
+    }
+    tokens
+}
+
 
 impl FromStr for Method {
     type Err = ParseError;
@@ -70,6 +178,13 @@ impl FromStr for Method {
     }
 }
 
+impl fmt::Display for Method {
+    /// Methods are written exactly as they are parsed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 
 /// Express an association between a `(Method,URI)` tuple and a door.
 ///
@@ -84,7 +199,7 @@ impl FromStr for Method {
 /// * `method`: "GET"
 /// * `prefix`: "/photos"
 /// * `door`: "/var/run/photo_album.door"
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone)]
 pub struct MapStatement {
     method: Method,
     prefix: PathBuf,
@@ -100,26 +215,26 @@ impl FromStr for MapStatement {
         let mut parts = input.split_whitespace();
 
         if parts.next() != Some("map") {
-            return parse_error!("MapStatement should begin with 'map'");
+            return parse_error!(@0, "MapStatement should begin with 'map'");
         }
 
         let method: Method = match parts.next() {
-            Some(m) => m.parse()?,
-            None => return parse_error!("MapStatement: No method specified")
+            Some(m) => m.parse().map_err(|e: ParseError| e.at(1))?,
+            None => return parse_error!(@1, "MapStatement: No method specified")
         };
 
         let prefix: PathBuf = match parts.next() {
             Some(p) => p.parse().unwrap(), // PathBuf.parse is infallible
-            None => return parse_error!("MapStatement: No prefix specified")
+            None => return parse_error!(@2, "MapStatement: No prefix specified")
         };
 
         if parts.next() != Some("to") {
-            return parse_error!("MapStatement: Needs a 'to door' clause");
+            return parse_error!(@3, "MapStatement: Needs a 'to door' clause");
         }
 
         let door: PathBuf = match parts.next() {
             Some(d) => d.parse().unwrap(), // PathBuf.parse is infallible
-            None => return parse_error!("MapStatement: No door path specified")
+            None => return parse_error!(@4, "MapStatement: No door path specified")
         };
 
         Ok(Self{ method, prefix, door })
@@ -143,11 +258,83 @@ impl FromStr for MapStatement {
 /// ```
 ///
 /// In this example, the parts between the curly braces are the "Atlas".
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone)]
 pub struct Atlas {
     maps: Vec<MapStatement>
 }
 
+
+/// Where an Atlas says a request should go.
+#[derive(Debug,PartialEq)]
+pub enum Route<'atlas> {
+    /// The longest matching prefix for this method points to this door.
+    Door(&'atlas Path),
+    /// Some prefix matches the path, but not for this method. These methods would have worked.
+    MethodNotAllowed(Vec<Method>),
+    /// No prefix matches the path at all.
+    NotFound
+}
+
+
+impl Atlas {
+    /// Choose a door for an HTTP request.
+    ///
+    /// Prefixes are compared one path component at a time, so `/photos` matches `/photos/1` but
+    /// not `/photosynthesis`. Any query string should already have been removed from `path`. When
+    /// several maps match, the one with the longest prefix wins.
+    ///
+    /// # Example
+    /// ```
+    /// use portunusd::config::{Atlas, Method, Route};
+    /// use std::path::Path;
+    ///
+    /// let atlas: Atlas = "{ map GET / to /var/run/blog.door map POST /signup to /var/run/mailer.door }"
+    ///     .parse().unwrap();
+    ///
+    /// assert_eq!(atlas.route(&Method::GET, "/posts/1"), Route::Door(Path::new("/var/run/blog.door")));
+    /// assert_eq!(atlas.route(&Method::POST, "/signup"), Route::Door(Path::new("/var/run/mailer.door")));
+    /// assert_eq!(atlas.route(&Method::POST, "/posts/1"), Route::MethodNotAllowed(vec![Method::GET]));
+    /// ```
+    pub fn route(&self, method: &Method, path: &str) -> Route<'_> {
+        let path = Path::new(path);
+        let matching: Vec<&MapStatement> = self.maps.iter()
+            .filter(|map| path.starts_with(&map.prefix))
+            .collect();
+
+        if matching.is_empty() {
+            return Route::NotFound;
+        }
+
+        let best = matching.iter()
+            .filter(|map| &map.method == method)
+            .max_by_key(|map| map.prefix.as_os_str().len());
+
+        match best {
+            Some(map) => Route::Door(&map.door),
+            None => {
+                let mut allowed: Vec<Method> = vec![];
+                for map in matching {
+                    if !allowed.contains(&map.method) {
+                        allowed.push(map.method);
+                    }
+                }
+                Route::MethodNotAllowed(allowed)
+            }
+        }
+    }
+
+    /// Every door mentioned in this Atlas, without duplicates.
+    pub fn doors(&self) -> Vec<&Path> {
+        let mut doors: Vec<&Path> = vec![];
+        for map in &self.maps {
+            if !doors.contains(&map.door.as_path()) {
+                doors.push(&map.door);
+            }
+        }
+        doors
+    }
+}
+
 impl FromStr for Atlas {
     type Err = ParseError ;
 
@@ -156,7 +343,7 @@ impl FromStr for Atlas {
         let mut parts = input.split_whitespace();
 
         if parts.next() != Some("{") {
-            return parse_error!("Atlas: Should start with curly brace");
+            return parse_error!(@0, "Atlas: Should start with curly brace");
         }
 
         if parts.nth_back(0) != Some("}") {
@@ -171,9 +358,10 @@ impl FromStr for Atlas {
             let map_statement = loop_parts.take(5);
             let map_statement: Vec<&str> = map_statement.collect();
             let map_statement = map_statement.join(" ");
+            let offset = 1 + 5 * maps.len();
             let map_statement = match map_statement.as_str() {
                 "" => break,
-                _ => map_statement.parse::<MapStatement>()?
+                _ => map_statement.parse::<MapStatement>().map_err(|e| e.at(offset))?
             };
             maps.push(map_statement)
         }
@@ -183,14 +371,127 @@ impl FromStr for Atlas {
 }
 
 
+/// Several copies of the same door application, sharing a listener.
+///
+/// Each connection (or datagram) goes to one member of the pool, chosen by the pool's
+/// [`Strategy`]. Members whose circuit is open are skipped.
+///
+/// # Example
+///
+/// ```portunusd
+/// forward tcp 0.0.0.0:7 to { pool /var/run/echo1.door /var/run/echo2.door }
+/// forward udp 0.0.0.0:53 to { pool consistent_hash /var/run/dns1.door /var/run/dns2.door }
+/// ```
+#[derive(Debug,PartialEq,Clone)]
+pub struct Pool {
+    pub strategy: Strategy,
+    pub doors: Vec<PathBuf>
+}
+
+impl FromStr for Pool {
+    type Err = ParseError;
+
+    /// { pool [strategy] /var/run/echo1.door /var/run/echo2.door }
+    fn from_str(input: &str) -> Result<Self,Self::Err> {
+        let mut parts: Vec<&str> = input.split_whitespace().collect();
+
+        if parts.first() != Some(&"{") {
+            return parse_error!(@0, "Pool: Should start with curly brace");
+        }
+        if parts.pop() != Some("}") {
+            return parse_error!("Pool: Should end with curly brace");
+        }
+        if parts.get(1) != Some(&"pool") {
+            return parse_error!(@1, "Pool: Should start with 'pool'");
+        }
+
+        let mut index = 2;
+        let strategy = match parts.get(index) {
+            Some(name) if !name.starts_with('/') => {
+                index += 1;
+                name.parse().map_err(|e: ParseError| e.at(index - 1))?
+            },
+            _ => Strategy::default()
+        };
+        let doors: Vec<PathBuf> = parts[index..].iter().map(PathBuf::from).collect();
+        if doors.is_empty() {
+            return parse_error!(@index, "Pool: Needs at least one door");
+        }
+
+        Ok(Self{ strategy, doors })
+    }
+}
+
+
+/// How a [`Pool`] chooses which member gets the next connection.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub enum Strategy {
+    /// Take turns (`round_robin`)
+    #[default]
+    RoundRobin,
+    /// Choose the member with the fewest calls underway (`least_inflight`)
+    LeastInflight,
+    /// Send each client IP address to the same member, for as long as it stays healthy
+    /// (`consistent_hash`)
+    ConsistentHash
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::RoundRobin => "round_robin",
+            Self::LeastInflight => "least_inflight",
+            Self::ConsistentHash => "consistent_hash"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Self,Self::Err> {
+        match input {
+            "round_robin" => Ok(Self::RoundRobin),
+            "least_inflight" => Ok(Self::LeastInflight),
+            "consistent_hash" => Ok(Self::ConsistentHash),
+            unrecognized => parse_error!("Strategy should be round_robin, least_inflight, or consistent_hash: {}", unrecognized)
+        }
+    }
+}
+
+
 /// Something to which request data can be delivered.
 ///
-/// This is either a door, or an Atlas of Maps to Doors. Either way, it is all we need in order to
-/// specify who should receive a given request.
-#[derive(Debug,PartialEq)]
+/// This is either a door, a [`Pool`] of doors, an Atlas of Maps to Doors, or another PortunusD host
+/// (`peer <address>`). Either way, it is all we need in order to specify who should receive a
+/// given request.
+#[derive(Debug,PartialEq,Clone)]
 pub enum ForwardingTarget {
     Door(PathBuf),
-    Atlas(Atlas)
+    Pool(Pool),
+    Atlas(Atlas),
+    Peer(SocketAddr)
+}
+
+impl ForwardingTarget {
+    /// Every local door this target forwards to, without duplicates.
+    pub fn doors(&self) -> Vec<&Path> {
+        match self {
+            Self::Door(door) => vec![door.as_path()],
+            Self::Peer(_) => vec![],
+            Self::Pool(pool) => {
+                let mut doors: Vec<&Path> = vec![];
+                for door in &pool.doors {
+                    if !doors.contains(&door.as_path()) {
+                        doors.push(door);
+                    }
+                }
+                doors
+            },
+            Self::Atlas(atlas) => atlas.doors()
+        }
+    }
 }
 
 
@@ -200,12 +501,18 @@ impl FromStr for ForwardingTarget {
     fn from_str(input: &str) -> Result<Self,Self::Err> {
         if input.starts_with("/") {
             let door: PathBuf = input.parse().unwrap(); // PathBuf.parse is Infallible
-            return Ok(Self::Door(door))
+            Ok(Self::Door(door))
+        } else if let Some(address) = input.strip_prefix("peer ") {
+            let address = address.trim().parse().map_err(|e: AddrParseError| ParseError::from(e).at(1))?;
+            Ok(Self::Peer(address))
+        } else if input.split_whitespace().nth(1) == Some("pool") {
+            let pool: Pool = input.parse()?;
+            Ok(Self::Pool(pool))
         } else if input.starts_with("{") {
             let atlas: Atlas = input.parse()?;
-            return Ok(Self::Atlas(atlas))
+            Ok(Self::Atlas(atlas))
         } else {
-            return parse_error!("ForwardingTarget should start with '/' or '{{': {}", input);
+            parse_error!(@0, "ForwardingTarget should start with '/' or '{{': {}", input)
         }
     }
 }
@@ -255,12 +562,12 @@ impl FromStr for ForwardingTarget {
 /// ## certificate parameters must be set (for tls). Lastly, it means that the
 /// ## forwarding target must be an Atlas (a collection of "map" statements).
 /// forward https 0.0.0.0:443 to {
-/// 	map GET /subscriptions to /var/run/list_subscriptions.door
-/// 	map POST /subscriptions/new to /var/run/mailer_signup.door
-/// 	map DELETE /subscriptions to /var/run/unsubscribe.door
+///     map GET /subscriptions to /var/run/list_subscriptions.door
+///     map POST /subscriptions/new to /var/run/mailer_signup.door
+///     map DELETE /subscriptions to /var/run/unsubscribe.door
 /// }
 /// ```
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone)]
 pub enum Protocol {
     UDP,
     TCP,
@@ -270,7 +577,25 @@ pub enum Protocol {
     /// Implies TCP, and implies that a corresponding [`Atlas`] will be defined.
     HTTP,
     /// Implies HTTP and TLS
-    HTTPS
+    HTTPS,
+    /// Implies TCP, with mutual TLS. Accepts traffic forwarded by other PortunusD hosts; see
+    /// [`crate::peer`].
+    Peer
+}
+
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::UDP => "udp",
+            Self::TCP => "tcp",
+            Self::TLS => "tls",
+            Self::HTTP => "http",
+            Self::HTTPS => "https",
+            Self::Peer => "peer"
+        };
+        write!(f, "{}", name)
+    }
 }
 
 
@@ -284,6 +609,7 @@ impl FromStr for Protocol {
             "tls" => Ok(Self::TLS),
             "http" => Ok(Self::HTTP),
             "https" => Ok(Self::HTTPS),
+            "peer" => Ok(Self::Peer),
             unrecognized => parse_error!("Unrecognized Protocol: {}", unrecognized)
         }
     }
@@ -297,7 +623,7 @@ impl FromStr for Protocol {
 /// ```portunusd
 /// forward udp 0.0.0.0:7 to /var/run/echo.door
 /// forward http 0.0.0.0:80 to {
-/// 	map GET / to /var/run/acme_client.door
+///     map GET / to /var/run/acme_client.door
 /// }
 /// ```
 ///
@@ -305,17 +631,130 @@ impl FromStr for Protocol {
 /// the `/var/run/echo.door` application door. It also states that any TCP traffic arriving on port
 /// 80 should be interpreted as HTTP, and forwarded to `/var/run/acme_client.door` if and only if
 /// it is a "GET" request whose URI begins with "/".
-#[derive(Debug,PartialEq)]
+///
+/// [`Limits`] go between the address and `to`, as does an optional `name`, by which an inherited
+/// socket can be matched to the statement (see [`crate::activation`]):
+///
+/// ```portunusd
+/// forward http 0.0.0.0:80 name web max_inflight 4 queue_depth 16 overflow 503 to {
+///     map GET / to /var/run/blog.door
+/// }
+/// ```
+#[derive(Debug,PartialEq,Clone)]
 pub struct ForwardingStatement {
     pub protocol: Protocol,
     pub address: SocketAddr,
-    pub target: ForwardingTarget
+    pub target: ForwardingTarget,
+    pub limits: Limits,
+    pub name: Option<String>
 }
 
+impl ForwardingStatement {
+    /// What to do with traffic when the queue is full, as configured, or else the protocol's
+    /// default: a 503 for `http`, dropping the datagram for `udp`, and a TCP reset otherwise.
+    pub fn overflow(&self) -> Overflow {
+        self.limits.overflow.unwrap_or(match self.protocol {
+            Protocol::HTTP => Overflow::Unavailable,
+            Protocol::UDP => Overflow::Drop,
+            _ => Overflow::Reset
+        })
+    }
+}
+
+
+/// How much work a forwarding statement may take on at once.
+///
+/// Each statement has `max_inflight` attendant threads, so that many door calls can be underway at
+/// once. Up to `queue_depth` more connections (or datagrams) may wait for an attendant, and the
+/// rest are turned away according to `overflow`. Door calls which take longer than `timeout` are
+/// abandoned.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct Limits {
+    pub max_inflight: usize,
+    pub queue_depth: usize,
+    /// `None` means the protocol's default; see [`ForwardingStatement::overflow`]
+    pub overflow: Option<Overflow>,
+    /// `None` means door calls may take as long as they like
+    pub timeout: Option<Duration>
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self{ max_inflight: 1, queue_depth: 128, overflow: None, timeout: None }
+    }
+}
+
+
+/// What to do with traffic which no attendant has room for.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Overflow {
+    /// Reset the TCP connection (`rst`)
+    Reset,
+    /// Answer with `503 Service Unavailable` (`503`). Only for `http`.
+    Unavailable,
+    /// Close the connection, or ignore the datagram (`drop`)
+    Drop
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Reset => "rst",
+            Self::Unavailable => "503",
+            Self::Drop => "drop"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Self,Self::Err> {
+        match input {
+            "rst" => Ok(Self::Reset),
+            "503" => Ok(Self::Unavailable),
+            "drop" => Ok(Self::Drop),
+            unrecognized => parse_error!("Overflow should be rst, 503, or drop: {}", unrecognized)
+        }
+    }
+}
+
+
+impl fmt::Display for ParseError {
+    /// Errors with a location are rendered with a caret under the offending token:
+    ///
+    /// ```text
+    /// portunusd.conf:12:13: Unrecognized Protocol: htps
+    ///    |
+    /// 12 | forward htps 0.0.0.0:443 to {
+    ///    |         ^^^^
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let location = match &self.location {
+            Some(location) => location,
+            None => return write!(f, "{}", self.message)
+        };
+        if let Some(file) = &location.file {
+            write!(f, "{}:", file.display())?;
+        }
+        writeln!(f, "{}:{}: {}", location.line, location.column, self.message)?;
+
+        let gutter = " ".repeat(location.line.to_string().len());
+        let indent: String = location.source.chars()
+            .take(location.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(location.token.chars().count().max(1));
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", location.line, location.source)?;
+        write!(f, "{} | {}{}", gutter, indent, carets)
+    }
+}
 
 impl From<AddrParseError> for ParseError {
     fn from(ape: AddrParseError) -> Self {
-        Self(format!("Invalid Address: {}", ape))
+        Self::new(format!("Invalid Address: {}", ape))
     }
 }
 
@@ -326,37 +765,109 @@ impl FromStr for ForwardingStatement {
         let mut parts = input.split_whitespace();
 
         if parts.next() != Some("forward") {
-            return parse_error!("ForwardingStatement should start with 'forward': {}", input);
+            return parse_error!(@0, "ForwardingStatement should start with 'forward': {}", input);
         }
 
         let protocol: Protocol = match parts.next() {
-            Some(p) => p.parse()?,
-            None => return parse_error!("ForwardingStatement missing Protocol: {}", input)
+            Some(p) => p.parse().map_err(|e: ParseError| e.at(1))?,
+            None => return parse_error!(@1, "ForwardingStatement missing Protocol: {}", input)
         };
 
         let address: SocketAddr = match parts.next() {
-            Some(a) => a.parse()?,
-            None => return parse_error!("ForwardingStatement missing SocketAddr: {}", input)
+            Some(a) => a.parse().map_err(|e: AddrParseError| ParseError::from(e).at(2))?,
+            None => return parse_error!(@2, "ForwardingStatement missing SocketAddr: {}", input)
         };
 
-        if parts.next() != Some("to") {
-            return parse_error!("ForwardingStatement needs 'to /door/path': {}", input);
+        // Any limits (and a name) come next, as name/value pairs, until we reach 'to'
+        let mut limits = Limits::default();
+        let mut statement_name = None;
+        let mut index = 3;
+        loop {
+            let name = match parts.next() {
+                Some("to") => break,
+                Some(name) => name,
+                None => return parse_error!(@index, "ForwardingStatement needs 'to /door/path': {}", input)
+            };
+            let value = match parts.next() {
+                Some(value) => value,
+                None => return parse_error!(@index, "{} needs a value: {}", name, input)
+            };
+            let count = || value.parse::<usize>().or(parse_error!(@index + 1, "{} should be a whole number: {}", name, value));
+            match name {
+                "name" => statement_name = Some(value.to_owned()),
+                "max_inflight" => match count()? {
+                    0 => return parse_error!(@index + 1, "max_inflight should be at least 1"),
+                    max_inflight => limits.max_inflight = max_inflight
+                },
+                "queue_depth" => limits.queue_depth = count()?,
+                "overflow" => limits.overflow = Some(value.parse().map_err(|e: ParseError| e.at(index + 1))?),
+                "timeout" => match value.parse::<f64>().ok().and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()) {
+                    Some(timeout) if !timeout.is_zero() => limits.timeout = Some(timeout),
+                    _ => return parse_error!(@index + 1, "timeout should be a positive number of seconds: {}", value)
+                },
+                _ => return parse_error!(@index, "ForwardingStatement needs 'to /door/path': {}", input)
+            }
+            index += 2;
         }
+        let index = index + 1;
 
         let target: ForwardingTarget = match parts.next() {
             Some("{") => {
                 let atlas: Vec<&str> = parts.take_while(|part| part != &"}").collect();
                 let atlas = format!("{{ {} }}", atlas.join(" "));
-                atlas.parse()?
+                atlas.parse().map_err(|e: ParseError| e.at(index))?
             },
-            Some(door) => door.parse()?,
-            None => return parse_error!("ForwardingStatement missing Target: {}", input)
+            Some("peer") => match parts.next() {
+                Some(address) => format!("peer {}", address).parse().map_err(|e: ParseError| e.at(index))?,
+                None => return parse_error!(@index + 1, "ForwardingStatement missing peer address: {}", input)
+            },
+            Some(door) => door.parse().map_err(|e: ParseError| e.at(index))?,
+            None => return parse_error!(@index, "ForwardingStatement missing Target: {}", input)
         };
 
-        Ok(ForwardingStatement{ protocol, address, target })
+        Ok(ForwardingStatement{ protocol, address, target, limits, name: statement_name })
+    }
+}
+
+/// The SHA-256 digest of a DER-encoded certificate, which identifies a trusted peer.
+///
+/// Written as 64 hex digits, optionally separated by colons, just as `openssl x509 -noout
+/// -fingerprint -sha256` prints it.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct Fingerprint(pub [u8; 32]);
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits: Vec<String> = self.0.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "{}", digits.join(":"))
     }
 }
 
+impl FromStr for Fingerprint {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Self,Self::Err> {
+        let digits: Vec<u8> = input.bytes().filter(|byte| *byte != b':').collect();
+        let mut fingerprint = [0u8; 32];
+        if digits.len() != 64 {
+            return parse_error!("Fingerprint should be 64 hex digits: {}", input);
+        }
+        for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+            // from_str_radix would also take a sign, like "+f"
+            if !pair.iter().all(u8::is_ascii_hexdigit) {
+                return parse_error!("Fingerprint should be 64 hex digits: {}", input);
+            }
+            let pair = std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok());
+            match pair {
+                Some(value) => *byte = value,
+                None => return parse_error!("Fingerprint should be 64 hex digits: {}", input)
+            }
+        }
+        Ok(Self(fingerprint))
+    }
+}
+
+
 #[derive(Debug,PartialEq)]
 pub struct Parameter {
     key: String,
@@ -371,17 +882,17 @@ impl FromStr for Parameter {
         let mut parts = input.split_whitespace();
 
         if parts.next() != Some("set") {
-            return parse_error!("Parameters must begin with 'set': {}", input);
+            return parse_error!(@0, "Parameters must begin with 'set': {}", input);
         }
 
         let key = match parts.next() {
             Some(key) => key.to_owned(),
-            None => return parse_error!("Parameter missing key: {}", input)
+            None => return parse_error!(@1, "Parameter missing key: {}", input)
         };
 
         let value = match parts.next() {
             Some(value) => value.to_owned(),
-            None => return parse_error!("Parameter missing value: {}", input)
+            None => return parse_error!(@2, "Parameter missing value: {}", input)
         };
 
         Ok(Parameter{ key, value })
@@ -391,7 +902,236 @@ impl FromStr for Parameter {
 #[derive(Debug,PartialEq)]
 pub struct Config {
     parameters: HashMap<String,String>,
-    pub statements: Vec<ForwardingStatement>
+    pub statements: Vec<ForwardingStatement>,
+    /// The line on which each of the `statements` begins, counting from 1.
+    lines: Vec<usize>,
+    /// The line on which each parameter was (last) set, counting from 1.
+    parameter_lines: HashMap<String,usize>
+}
+
+
+/// How long to wait for in-flight connections when shutting down, unless the config says
+/// otherwise with `set shutdown_timeout <seconds>`.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// Something wrong with a config that parsed, but can never work.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Problem {
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+
+impl Config {
+    /// Look up the value of a `set` statement.
+    pub fn parameter(&self, key: &str) -> Option<&str> {
+        self.parameters.get(key).map(|value| value.as_str())
+    }
+
+    /// The line on which the `index`th forwarding statement begins, counting from 1.
+    pub fn line(&self, index: usize) -> usize {
+        self.lines.get(index).copied().unwrap_or(0)
+    }
+
+    /// How long to wait for in-flight connections to finish when shutting down.
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.parameter("shutdown_timeout")
+            .and_then(|seconds| seconds.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT)
+    }
+
+    /// How often to probe each door, or `None` unless the config asks for probes with `set
+    /// probe_interval <seconds>`.
+    ///
+    /// Probing is opt-in, since an application which is not expecting probes may not survive them.
+    pub fn probe_interval(&self) -> Option<Duration> {
+        self.parameter("probe_interval")
+            .and_then(|seconds| seconds.parse().ok())
+            .map(Duration::from_secs)
+            .filter(|interval| !interval.is_zero())
+    }
+
+    /// The certificates of every trusted peer, as set by `set peers <fingerprint>,<fingerprint>`.
+    ///
+    /// Fingerprints which do not parse are left out; [`Config::validate`] reports them.
+    pub fn peers(&self) -> Vec<Fingerprint> {
+        self.parameter("peers").into_iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|fingerprint| fingerprint.parse().ok())
+            .collect()
+    }
+
+    /// Where to serve OpenMetrics, if anywhere, as set by `set metrics_listen <address>`.
+    ///
+    /// Returns `None` if the address does not parse; [`Config::validate`] reports that.
+    pub fn metrics_listen(&self) -> Option<SocketAddr> {
+        self.parameter("metrics_listen").and_then(|address| address.parse().ok())
+    }
+
+    /// Find every problem that would stop this config from working.
+    ///
+    /// Parsing only checks that each line is well-formed. This checks the statements against each
+    /// other and against the parameters, so that all of the problems can be reported at once
+    /// (rather than one per attempt to start PortunusD).
+    ///
+    /// # Example
+    /// ```
+    /// use portunusd::config::Config;
+    ///
+    /// let config: Config = "forward tcp 0.0.0.0:7 to /var/run/echo.door
+    /// forward tls 0.0.0.0:7 to /var/run/echo.door".parse().unwrap();
+    ///
+    /// let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+    /// assert_eq!(problems, vec![
+    ///     "line 2: tls needs the 'certificate' parameter",
+    ///     "line 2: tls needs the 'key' parameter",
+    ///     "line 2: tls needs the 'domain' parameter",
+    ///     "line 2: 0.0.0.0:7 is already bound by line 1",
+    /// ]);
+    /// ```
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+        let mut bound: Vec<(bool, SocketAddr, usize)> = vec![];
+        let mut named: Vec<(String, usize)> = vec![];
+
+        for key in ["shutdown_timeout", "probe_interval"] {
+            if let Some(value) = self.parameter(key) {
+                if value.parse::<u64>().is_err() {
+                    let line = self.parameter_lines.get(key).copied().unwrap_or(0);
+                    problems.push(Problem{ line, message: format!("{} must be a whole number of seconds", key) });
+                }
+            }
+        }
+
+        if let Some(value) = self.parameter("peers") {
+            let line = self.parameter_lines.get("peers").copied().unwrap_or(0);
+            for fingerprint in value.split(',') {
+                if fingerprint.parse::<Fingerprint>().is_err() {
+                    problems.push(Problem{ line, message: format!("peers must be SHA-256 certificate fingerprints, not {}", fingerprint) });
+                }
+            }
+        }
+
+        // Giving up root only makes sense if we know whom to become
+        for key in ["group", "chroot"] {
+            if self.parameter(key).is_some() && self.parameter("user").is_none() {
+                let line = self.parameter_lines.get(key).copied().unwrap_or(0);
+                problems.push(Problem{ line, message: format!("{} needs the 'user' parameter", key) });
+            }
+        }
+        if let Some(directory) = self.parameter("chroot") {
+            if Path::new(directory).is_relative() {
+                let line = self.parameter_lines.get("chroot").copied().unwrap_or(0);
+                problems.push(Problem{ line, message: format!("chroot {} must be absolute", directory) });
+            }
+        }
+
+        // The metrics endpoint is a TCP listener like any other, so nothing else may bind it
+        if let Some(value) = self.parameter("metrics_listen") {
+            let line = self.parameter_lines.get("metrics_listen").copied().unwrap_or(0);
+            match value.parse::<SocketAddr>() {
+                Ok(address) => bound.push((false, address, line)),
+                Err(_) => problems.push(Problem{ line, message: format!("metrics_listen must be an address like 127.0.0.1:9100, not {}", value) })
+            }
+        }
+
+        for (index, statement) in self.statements.iter().enumerate() {
+            let line = self.line(index);
+            let mut problem = |message: String| problems.push(Problem{ line, message });
+            let protocol = statement.protocol.to_string();
+
+            match (&statement.protocol, &statement.target) {
+                (Protocol::TCP | Protocol::UDP | Protocol::TLS, ForwardingTarget::Atlas(_)) => {
+                    problem(format!("{} can only forward to a door or a pool, not an Atlas", protocol));
+                },
+                (Protocol::HTTP | Protocol::HTTPS, ForwardingTarget::Door(_)) => {
+                    problem(format!("{} can only forward to an Atlas, not a door", protocol));
+                },
+                (Protocol::HTTP | Protocol::HTTPS, ForwardingTarget::Pool(_)) => {
+                    problem(format!("{} can only forward to an Atlas, not a pool", protocol));
+                },
+                (Protocol::HTTP | Protocol::HTTPS, ForwardingTarget::Peer(_)) => {
+                    problem(format!("{} can only forward to an Atlas, not a peer", protocol));
+                },
+                (Protocol::Peer, ForwardingTarget::Atlas(_)) => {
+                    problem("peer can only forward to a door or a pool, not an Atlas".to_owned());
+                },
+                (Protocol::Peer, ForwardingTarget::Peer(_)) => {
+                    problem("peer can only forward to a door or a pool, not another peer".to_owned());
+                },
+                _ => {}
+            }
+
+            for door in statement.target.doors() {
+                if door.is_relative() {
+                    problem(format!("door path {} must be absolute", door.display()));
+                }
+            }
+
+            match (&statement.protocol, statement.overflow()) {
+                (Protocol::UDP, Overflow::Reset) => problem("udp cannot reset on overflow, only drop".to_owned()),
+                (Protocol::HTTP, _) | (_, Overflow::Reset | Overflow::Drop) => {},
+                (_, Overflow::Unavailable) => problem(format!("{} cannot answer 503 on overflow, only http can", protocol))
+            }
+
+            if matches!(statement.protocol, Protocol::TLS | Protocol::HTTPS) {
+                for key in ["certificate", "key", "domain"] {
+                    if self.parameter(key).is_none() {
+                        problem(format!("{} needs the '{}' parameter", protocol, key));
+                    }
+                }
+            }
+
+            // Peers prove who they are to each other with our certificate, and theirs
+            let talks_to_peers = match (&statement.protocol, &statement.target) {
+                (Protocol::Peer, _) => Some(protocol.clone()),
+                (Protocol::TCP | Protocol::UDP | Protocol::TLS, ForwardingTarget::Peer(_)) => Some(format!("{} to a peer", protocol)),
+                _ => None
+            };
+            if let Some(description) = talks_to_peers {
+                for key in ["certificate", "key", "peers"] {
+                    if self.parameter(key).is_none() {
+                        problem(format!("{} needs the '{}' parameter", description, key));
+                    }
+                }
+            }
+
+            // TCP and UDP ports are separate, and port 0 asks the kernel for any free port
+            let datagram = statement.protocol == Protocol::UDP;
+            let address = statement.address;
+            let conflict = bound.iter().find(|(other_datagram, other, _)| {
+                *other_datagram == datagram
+                    && address.port() != 0
+                    && other.port() == address.port()
+                    && other.is_ipv4() == address.is_ipv4()
+                    && (other.ip() == address.ip() || other.ip().is_unspecified() || address.ip().is_unspecified())
+            });
+            match conflict {
+                Some((_, _, other_line)) => {
+                    problem(format!("{} is already bound by line {}", address, other_line));
+                },
+                None => bound.push((datagram, address, line))
+            }
+
+            // Names pick out inherited sockets, so they must not be ambiguous
+            if let Some(name) = &statement.name {
+                match named.iter().find(|(other, _)| other == name) {
+                    Some((_, other_line)) => problem(format!("name {} is already used by line {}", name, other_line)),
+                    None => named.push((name.clone(), line))
+                }
+            }
+        }
+
+        problems
+    }
 }
 
 
@@ -399,38 +1139,56 @@ impl FromStr for Config {
     type Err = ParseError;
 
     fn from_str(input: &str) -> Result<Self,Self::Err> {
-        let mut lines = input.lines();
+        let source: Vec<&str> = input.lines().collect();
+        let mut lines = source.iter().copied().enumerate();
         let mut parameters = HashMap::new();
         let mut statements = vec![];
+        let mut statement_lines = vec![];
+        let mut parameter_lines = HashMap::new();
 
         let loop_lines = &mut lines;
 
-        while let Some(line) = loop_lines.next() {
+        while let Some((index, line)) = loop_lines.next() {
+            // Every token of this statement, in the same order its parser will see them
+            let mut tokens = tokenize(index, line);
+
             if line.starts_with("set") {
-                let parameter: Parameter = line.parse()?;
+                let parameter: Parameter = line.parse()
+                    .map_err(|e: ParseError| e.locate(&tokens, &source))?;
+                parameter_lines.insert(parameter.key.clone(), index + 1);
                 parameters.insert(parameter.key, parameter.value);
             } else if line.starts_with("#") {
                 // comment, skip
-            } else if line.len() == 0 {
+            } else if line.is_empty() {
                 // empty line, skip
             } else if line.starts_with("forward") {
                 if line.ends_with("{") {
                     // this is a block, so keep absorbing until the block ends
-                    let block = loop_lines.take_while(|l| l != &"}");
-                    let block: Vec<&str> = block.collect();
+                    let mut block: Vec<&str> = vec![];
+                    for (block_index, block_line) in loop_lines.by_ref() {
+                        tokens.extend(tokenize(block_index, block_line));
+                        if block_line == "}" {
+                            break;
+                        }
+                        block.push(block_line);
+                    }
                     let block = format!("{} {} }}", line, block.join(" "));
-                    let statement: ForwardingStatement = block.parse()?;
+                    let statement: ForwardingStatement = block.parse()
+                        .map_err(|e: ParseError| e.locate(&tokens, &source))?;
                     statements.push(statement)
                 } else {
-                    let statement: ForwardingStatement = line.parse()?;
+                    let statement: ForwardingStatement = line.parse()
+                        .map_err(|e: ParseError| e.locate(&tokens, &source))?;
                     statements.push(statement)
                 }
+                statement_lines.push(index + 1);
             } else {
-                return parse_error!("Unparseable Nonsense: {}", line);
+                let e = ParseError::new(format!("Unparseable Nonsense: {}", line)).at(0);
+                return Err(e.locate(&tokens, &source));
             }
         }
 
-        Ok(Self{ parameters, statements })
+        Ok(Self{ parameters, statements, lines: statement_lines, parameter_lines })
     }
 }
 
@@ -483,6 +1241,35 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn routes_to_the_longest_matching_prefix() {
+        let atlas: Atlas = r#"{
+            map GET / to /var/run/files.door
+            map GET /photos to /var/run/photo_album.door
+            map GET /photos/private to /var/run/vault.door
+            map DELETE /photos to /var/run/photo_album.door
+        }"#.parse().unwrap();
+
+        assert_eq!(atlas.route(&Method::GET, "/index.html"), Route::Door(Path::new("/var/run/files.door")));
+        assert_eq!(atlas.route(&Method::GET, "/photos"), Route::Door(Path::new("/var/run/photo_album.door")));
+        assert_eq!(atlas.route(&Method::GET, "/photos/1.jpg"), Route::Door(Path::new("/var/run/photo_album.door")));
+        assert_eq!(atlas.route(&Method::GET, "/photosynthesis"), Route::Door(Path::new("/var/run/files.door")));
+        assert_eq!(atlas.route(&Method::GET, "/photos/private/1.jpg"), Route::Door(Path::new("/var/run/vault.door")));
+        assert_eq!(atlas.route(&Method::DELETE, "/photos/1.jpg"), Route::Door(Path::new("/var/run/photo_album.door")));
+    }
+
+    #[test]
+    fn distinguishes_not_found_from_method_not_allowed() {
+        let atlas: Atlas = r#"{
+            map GET /primes to /var/run/eratosthenes.door
+            map POST /primes to /var/run/eratosthenes.door
+        }"#.parse().unwrap();
+
+        assert_eq!(atlas.route(&Method::GET, "/blog"), Route::NotFound);
+        assert_eq!(atlas.route(&Method::DELETE, "/primes/7"), Route::MethodNotAllowed(vec![Method::GET, Method::POST]));
+        assert_eq!(atlas.doors(), vec![Path::new("/var/run/eratosthenes.door")]);
+    }
+
     #[test]
     fn can_parse_forwarding_target() {
         let actual: ForwardingTarget = "/door/path".parse().unwrap();
@@ -508,6 +1295,19 @@ mod tests {
         let atlas = Atlas{ maps };
         let expected = ForwardingTarget::Atlas(atlas);
         assert_eq!(actual, expected);
+
+        let actual: ForwardingTarget = "{ pool /echo1.door /echo2.door /echo1.door }".parse().unwrap();
+        let pool = Pool{ strategy: Strategy::RoundRobin, doors: vec!["/echo1.door".into(), "/echo2.door".into(), "/echo1.door".into()] };
+        assert_eq!(actual.doors(), vec![Path::new("/echo1.door"), Path::new("/echo2.door")]);
+        assert_eq!(actual, ForwardingTarget::Pool(pool));
+
+        let actual: ForwardingTarget = "{ pool least_inflight /echo.door }".parse().unwrap();
+        let pool = Pool{ strategy: Strategy::LeastInflight, doors: vec!["/echo.door".into()] };
+        assert_eq!(actual, ForwardingTarget::Pool(pool));
+
+        let actual: ForwardingTarget = "peer 10.0.0.2:9443".parse().unwrap();
+        assert_eq!(actual, ForwardingTarget::Peer("10.0.0.2:9443".parse().unwrap()));
+        assert!(actual.doors().is_empty());
     }
 
     #[test]
@@ -517,6 +1317,7 @@ mod tests {
         assert_eq!("tls".parse::<Protocol>().unwrap(), Protocol::TLS);
         assert_eq!("http".parse::<Protocol>().unwrap(), Protocol::HTTP);
         assert_eq!("https".parse::<Protocol>().unwrap(), Protocol::HTTPS);
+        assert_eq!("peer".parse::<Protocol>().unwrap(), Protocol::Peer);
     }
 
     #[test]
@@ -531,10 +1332,41 @@ mod tests {
         let target = ForwardingTarget::Door("/dns.door".parse().unwrap());
         let protocol: Protocol = "udp".parse().unwrap();
         let address: SocketAddr = "0.0.0.0:53".parse().unwrap();
-        let expected = ForwardingStatement{ protocol, address, target };
+        let expected = ForwardingStatement{ protocol, address, target, limits: Limits::default(), name: None };
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn can_parse_forwarding_statement_with_limits() {
+        let actual: ForwardingStatement = "forward tcp 0.0.0.0:7 max_inflight 4 queue_depth 0 overflow drop timeout 2.5 to /echo.door"
+            .parse().unwrap();
+        assert_eq!(actual.limits, Limits{
+            max_inflight: 4,
+            queue_depth: 0,
+            overflow: Some(Overflow::Drop),
+            timeout: Some(Duration::from_millis(2500))
+        });
+        assert_eq!(actual.target, ForwardingTarget::Door("/echo.door".into()));
+        assert_eq!(actual.overflow(), Overflow::Drop);
+
+        let named: ForwardingStatement = "forward tcp 0.0.0.0:7 name echo max_inflight 2 to /echo.door".parse().unwrap();
+        assert_eq!(named.name.as_deref(), Some("echo"));
+        assert_eq!(named.limits, Limits{ max_inflight: 2, ..Limits::default() });
+
+        let defaults: ForwardingStatement = "forward http 0.0.0.0:80 queue_depth 8 to { map GET / to /blog.door }".parse().unwrap();
+        assert_eq!(defaults.limits, Limits{ queue_depth: 8, ..Limits::default() });
+        assert_eq!(defaults.overflow(), Overflow::Unavailable);
+
+        let e = "forward tcp 0.0.0.0:7 max_inflight 0 to /echo.door".parse::<ForwardingStatement>().unwrap_err();
+        assert_eq!(e.token, Some(4));
+        let e = "forward tcp 0.0.0.0:7 overflow later to /echo.door".parse::<ForwardingStatement>().unwrap_err();
+        assert_eq!((e.message(), e.token), ("Overflow should be rst, 503, or drop: later", Some(4)));
+        let e = "forward tcp 0.0.0.0:7 timeout 0 to /echo.door".parse::<ForwardingStatement>().unwrap_err();
+        assert_eq!((e.message(), e.token), ("timeout should be a positive number of seconds: 0", Some(4)));
+        let e = "forward tcp 0.0.0.0:7 queue_depth 2 to echo.door".parse::<ForwardingStatement>().unwrap_err();
+        assert_eq!(e.token, Some(6));
+    }
+
     #[test]
     fn can_parse_forwarding_statement_with_atlas() {
         let actual: ForwardingStatement = r#"forward http 0.0.0.0:80 to {
@@ -551,7 +1383,7 @@ mod tests {
         let target = ForwardingTarget::Atlas(atlas);
         let protocol: Protocol = "http".parse().unwrap();
         let address: SocketAddr = "0.0.0.0:80".parse().unwrap();
-        let expected = ForwardingStatement{ protocol, address, target };
+        let expected = ForwardingStatement{ protocol, address, target, limits: Limits::default(), name: None };
         assert_eq!(actual, expected);
     }
 
@@ -591,5 +1423,149 @@ forward https 0.0.0.0:443 to {
         assert_eq!(config.parameters.get("domain").unwrap(), "example.org");
         assert_eq!(config.statements[0].protocol, Protocol::UDP);
         assert_eq!(config.statements.len(), 4);
+        assert_eq!(config.line(2), 11);
+        assert_eq!(config.line(3), 15);
+        assert!(config.validate().is_empty());
+        assert_eq!(config.shutdown_timeout(), DEFAULT_SHUTDOWN_TIMEOUT);
+        assert_eq!(config.probe_interval(), None);
+    }
+
+    #[test]
+    fn parse_errors_point_into_blocks() {
+        let e = "set domain example.org\n\nforward https 0.0.0.0:443 to {\n    map GET / to /var/run/blog.door\n    map FETCH /signup to /var/run/subscribe.door\n}\n"
+            .parse::<Config>().unwrap_err().in_file("portunusd.conf");
+        let location = e.location().unwrap();
+        assert_eq!((location.line, location.column), (5, 9));
+        assert_eq!(location.token, "FETCH");
+        assert_eq!(e.to_string(), "portunusd.conf:5:9: Unrecognized Method: FETCH
+  |
+5 |     map FETCH /signup to /var/run/subscribe.door
+  |         ^^^^^");
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_offending_token() {
+        let e = "forward tcp 0.0.0.0:7 to /var/run/echo.door\nforward tcp 0.0.0.0:seven to /var/run/echo.door"
+            .parse::<Config>().unwrap_err();
+        let location = e.location().unwrap();
+        assert_eq!((location.line, location.column, location.token.as_str()), (2, 13, "0.0.0.0:seven"));
+
+        let e = "forward http 0.0.0.0:80 to {\n    map GET /\n}".parse::<Config>().unwrap_err();
+        let location = e.location().unwrap();
+        assert_eq!((location.line, location.column, location.token.as_str()), (3, 1, "}"));
+
+        let e = "forward udp 0.0.0.0:53\n".parse::<Config>().unwrap_err();
+        let location = e.location().unwrap();
+        assert_eq!((location.line, location.column, location.token.as_str()), (1, 23, ""));
+
+        let e = "forward tcp 0.0.0.0:7 to { pool fastest /var/run/echo.door }".parse::<Config>().unwrap_err();
+        let location = e.location().unwrap();
+        assert_eq!((location.line, location.column, location.token.as_str()), (1, 33, "fastest"));
+
+        let e = "forward tcp 0.0.0.0:7 to {\n    pool\n}".parse::<Config>().unwrap_err();
+        assert_eq!(e.message(), "Pool: Needs at least one door");
+
+        let e = "\n  bogus line".parse::<Config>().unwrap_err();
+        let location = e.location().unwrap();
+        assert_eq!((location.line, location.column, location.token.as_str()), (2, 3, "bogus"));
+    }
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let config: Config = r#"forward udp 0.0.0.0:53 to /var/run/dns.door
+forward tcp 0.0.0.0:53 to /var/run/dns.door
+forward tcp 127.0.0.1:53 to /var/run/other.door
+forward https [::]:443 to {
+    map GET / to var/run/blog.door
+}
+forward http 0.0.0.0:80 to /var/run/blog.door
+forward tcp 0.0.0.0:0 to /var/run/a.door
+forward tcp 0.0.0.0:0 to /var/run/b.door
+set shutdown_timeout soon
+forward udp 0.0.0.0:7 overflow rst to /var/run/echo.door
+forward tcp 0.0.0.0:7 overflow 503 to /var/run/echo.door
+set probe_interval often
+forward http 0.0.0.0:8080 to { pool /var/run/blog.door }
+forward tcp 0.0.0.0:8081 name blog to /var/run/a.door
+forward tcp 0.0.0.0:8082 name blog to /var/run/b.door
+"#.parse().unwrap();
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec![
+            "line 10: shutdown_timeout must be a whole number of seconds",
+            "line 13: probe_interval must be a whole number of seconds",
+            "line 3: 127.0.0.1:53 is already bound by line 2",
+            "line 4: door path var/run/blog.door must be absolute",
+            "line 4: https needs the 'certificate' parameter",
+            "line 4: https needs the 'key' parameter",
+            "line 4: https needs the 'domain' parameter",
+            "line 7: http can only forward to an Atlas, not a door",
+            "line 11: udp cannot reset on overflow, only drop",
+            "line 12: tcp cannot answer 503 on overflow, only http can",
+            "line 14: http can only forward to an Atlas, not a pool",
+            "line 16: name blog is already used by line 15",
+        ]);
+    }
+
+    #[test]
+    fn the_metrics_endpoint_is_checked_like_a_listener() {
+        let config: Config = "set metrics_listen 127.0.0.1:9100\nforward tcp 0.0.0.0:9100 to /var/run/a.door\nforward udp 0.0.0.0:9100 to /var/run/a.door"
+            .parse().unwrap();
+        assert_eq!(config.metrics_listen(), Some("127.0.0.1:9100".parse().unwrap()));
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec!["line 2: 0.0.0.0:9100 is already bound by line 1"]);
+
+        let config: Config = "set metrics_listen localhost".parse().unwrap();
+        assert_eq!(config.metrics_listen(), None);
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec!["line 1: metrics_listen must be an address like 127.0.0.1:9100, not localhost"]);
+    }
+
+    #[test]
+    fn can_parse_peers() {
+        let digits = "0123456789abcdef".repeat(4);
+        let config: Config = format!("set peers {},{}", digits, digits.to_uppercase()).parse().unwrap();
+        let fingerprint: Fingerprint = digits.parse().unwrap();
+        assert_eq!(config.peers(), vec![fingerprint, fingerprint]);
+        assert!(fingerprint.to_string().starts_with("01:23:45:67:89:AB:CD:EF:01"));
+        assert_eq!(fingerprint.to_string().parse::<Fingerprint>().unwrap(), fingerprint);
+        assert!("01:23".parse::<Fingerprint>().is_err());
+    }
+
+    #[test]
+    fn privileges_need_a_user() {
+        let config: Config = "set group portunus\nset chroot var/empty".parse().unwrap();
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec![
+            "line 1: group needs the 'user' parameter",
+            "line 2: chroot needs the 'user' parameter",
+            "line 2: chroot var/empty must be absolute",
+        ]);
+
+        let config: Config = "set user portunus\nset group portunus\nset chroot /var/empty".parse().unwrap();
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn peers_need_certificates() {
+        let config: Config = r#"set peers 01:23
+forward peer 0.0.0.0:9443 to /var/run/echo.door
+forward tcp 0.0.0.0:7 to peer 10.0.0.2:9443
+forward peer 0.0.0.0:9444 to peer 10.0.0.2:9443
+forward http 0.0.0.0:80 to peer 10.0.0.2:9443
+"#.parse().unwrap();
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec![
+            "line 1: peers must be SHA-256 certificate fingerprints, not 01:23",
+            "line 2: peer needs the 'certificate' parameter",
+            "line 2: peer needs the 'key' parameter",
+            "line 3: tcp to a peer needs the 'certificate' parameter",
+            "line 3: tcp to a peer needs the 'key' parameter",
+            "line 4: peer can only forward to a door or a pool, not another peer",
+            "line 4: peer needs the 'certificate' parameter",
+            "line 4: peer needs the 'key' parameter",
+            "line 5: http can only forward to an Atlas, not a peer",
+        ]);
     }
 }
@@ -0,0 +1,1017 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Control Door Protocol
+//!
+//! `portunus` talks to a running `portunusd` through the control door. Every call carries one
+//! [`Request`], and every reply carries either a [`Response`] or a [`ControlError`]:
+//!
+//! ```text
+//! request:  | version | request code | (nothing else, for now)        |
+//! response: | version | 0            | request code | response body   |
+//! error:    | version | 1            | error code   | error body      |
+//! ```
+//!
+//! Integers are big-endian, and strings (including addresses and paths) are a `u16` length
+//! followed by that many bytes of UTF-8. Lists are a `u16` count followed by their items.
+//!
+//! [`Client`] places these calls. On the other side of the door, [`answer`] decodes each request,
+//! checks that the caller is allowed to make it, and hands it to a [`Controller`]. [`Daemon`] is
+//! the `Controller` that `portunusd` itself uses.
+//!
+//! ```
+//! use portunusd::control::{Request, Response};
+//!
+//! let request = Request::Drain.encode();
+//! assert_eq!(Request::decode(&request), Ok(Request::Drain));
+//!
+//! let reply = Response::encode(&Ok(Response::Drained{ abandoned: 2 }));
+//! assert_eq!(Response::decode(&reply), Ok(Ok(Response::Drained{ abandoned: 2 })));
+//! ```
+
+// Types
+use crate::config::{Config, ParseError, Problem, Protocol};
+use crate::health::Health;
+use crate::metrics::{self, Latency, Snapshot};
+use crate::supervisor::{Changes, Supervisor, SupervisorError};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// Macros
+use errors::define_error_enum;
+
+
+/// The protocol version spoken by this crate. Requests from any other version are refused.
+pub const VERSION: u8 = 1;
+
+const OK: u8 = 0;
+const ERR: u8 = 1;
+
+
+/// Something `portunus` can ask `portunusd` to do.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Request {
+    /// Is the server up, and for how long?
+    Status = 1,
+    /// How busy has each listener, and each door, been?
+    Stats = 2,
+    /// What is each listener bound to, and where does it forward?
+    Listeners = 3,
+    /// Re-read the config file, as if by SIGHUP.
+    Reload = 4,
+    /// Stop every listener, and wait for open connections to finish. The server stays up, and
+    /// a `Reload` brings the listeners back.
+    Drain = 5,
+    /// Drain, and then exit, as if by SIGTERM.
+    Stop = 6
+}
+
+impl Request {
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::Status),
+            2 => Some(Self::Stats),
+            3 => Some(Self::Listeners),
+            4 => Some(Self::Reload),
+            5 => Some(Self::Drain),
+            6 => Some(Self::Stop),
+            _ => None
+        }
+    }
+
+    /// Whether this request changes anything, and so needs an authorized caller.
+    pub fn is_privileged(&self) -> bool {
+        matches!(self, Self::Reload | Self::Drain | Self::Stop)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        vec![VERSION, *self as u8]
+    }
+
+    pub fn decode(message: &[u8]) -> Result<Self, ControlError> {
+        match message {
+            [VERSION, code, ..] => Self::from_code(*code).ok_or(ControlError::UnknownRequest(*code)),
+            [version, ..] => Err(ControlError::UnsupportedVersion(*version)),
+            [] => Err(ControlError::Malformed)
+        }
+    }
+}
+
+
+/// The answer to [`Request::Status`].
+#[derive(Debug,PartialEq,Clone)]
+pub struct Status {
+    /// The version of `portunusd` which is running
+    pub version: String,
+    pub pid: u32,
+    pub uptime: Duration,
+    /// How many listeners are running
+    pub listeners: u32,
+    pub config: PathBuf,
+    /// How each door in use has fared lately
+    pub doors: Vec<(PathBuf, Health)>
+}
+
+/// One entry in the answer to [`Request::Listeners`].
+#[derive(Debug,PartialEq,Clone)]
+pub struct Listener {
+    pub protocol: Protocol,
+    /// The address the listener is actually bound to
+    pub address: SocketAddr,
+    /// Every door the listener forwards to
+    pub doors: Vec<PathBuf>
+}
+
+/// The answer to [`Request::Stats`].
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct Stats {
+    pub listeners: Vec<ListenerStats>,
+    pub doors: Vec<DoorStats>
+}
+
+/// How busy a listener has been since it was first bound.
+#[derive(Debug,PartialEq,Clone)]
+pub struct ListenerStats {
+    pub protocol: Protocol,
+    pub address: SocketAddr,
+    pub metrics: Snapshot
+}
+
+/// How busy a door has been since a running listener first opened it.
+#[derive(Debug,PartialEq,Clone)]
+pub struct DoorStats {
+    pub path: PathBuf,
+    pub health: Health,
+    pub metrics: Snapshot
+}
+
+impl Stats {
+    /// Render as a JSON object, with `listeners` and `doors` arrays. Latencies are in
+    /// microseconds, and `latency_bounds_us` gives the upper bound of each latency bucket.
+    pub fn to_json(&self) -> String {
+        let listeners: Vec<String> = self.listeners.iter()
+            .map(|listener| format!("{{\"protocol\":{},\"address\":{},{}}}",
+                json_string(&listener.protocol.to_string()),
+                json_string(&listener.address.to_string()),
+                json_metrics(&listener.metrics)))
+            .collect();
+        let doors: Vec<String> = self.doors.iter()
+            .map(|door| format!("{{\"path\":{},\"health\":{},\"failures\":{},{}}}",
+                json_string(&door.path.to_string_lossy()), json_string(&door.health.to_string()),
+                door.health.failures(), json_metrics(&door.metrics)))
+            .collect();
+        let bounds: Vec<String> = metrics::LATENCY_BOUNDS.iter().map(|bound| bound.to_string()).collect();
+        format!("{{\"latency_bounds_us\":[{}],\"listeners\":[{}],\"doors\":[{}]}}",
+            bounds.join(","), listeners.join(","), doors.join(","))
+    }
+}
+
+fn json_metrics(metrics: &Snapshot) -> String {
+    let buckets: Vec<String> = metrics.latency.buckets.iter().map(|count| count.to_string()).collect();
+    let errors: Vec<String> = metrics.errors.iter()
+        .map(|(errno, count)| format!("\"{}\":{}", errno, count))
+        .collect();
+    format!("\"accepted\":{},\"active\":{},\"queued\":{},\"rejected\":{},\"latency\":{{\"buckets\":[{}],\"count\":{},\"sum_us\":{}}},\"errors\":{{{}}},\"abandoned\":{},\"bytes_in\":{},\"bytes_out\":{}",
+        metrics.accepted, metrics.active, metrics.queued, metrics.rejected, buckets.join(","), metrics.latency.count(),
+        metrics.latency.sum.as_micros(), errors.join(","), metrics.abandoned, metrics.bytes_in, metrics.bytes_out)
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c)
+        }
+    }
+    json.push('"');
+    json
+}
+
+
+/// A successful reply to a [`Request`].
+#[derive(Debug,PartialEq,Clone)]
+pub enum Response {
+    Status(Status),
+    Stats(Stats),
+    Listeners(Vec<Listener>),
+    Reloaded(Changes),
+    /// How many listeners were still busy when `shutdown_timeout` ran out
+    Drained{ abandoned: u32 },
+    Stopping
+}
+
+impl Response {
+    /// The request this is an answer to.
+    pub fn request(&self) -> Request {
+        match self {
+            Self::Status(_) => Request::Status,
+            Self::Stats(_) => Request::Stats,
+            Self::Listeners(_) => Request::Listeners,
+            Self::Reloaded(_) => Request::Reload,
+            Self::Drained{ .. } => Request::Drain,
+            Self::Stopping => Request::Stop
+        }
+    }
+
+    pub fn encode(reply: &Result<Self, ControlError>) -> Vec<u8> {
+        let mut message = Writer(vec![VERSION]);
+        match reply {
+            Ok(response) => {
+                message.u8(OK);
+                message.u8(response.request() as u8);
+                response.encode_body(&mut message);
+            },
+            Err(e) => {
+                message.u8(ERR);
+                e.encode(&mut message);
+            }
+        }
+        message.0
+    }
+
+    pub fn decode(message: &[u8]) -> Result<Result<Self, ControlError>, ControlError> {
+        let mut reader = Reader(message);
+        match reader.u8()? {
+            VERSION => {},
+            version => return Err(ControlError::UnsupportedVersion(version))
+        }
+        let reply = match reader.u8()? {
+            OK => Ok(Self::decode_body(&mut reader)?),
+            ERR => Err(ControlError::decode(&mut reader)?),
+            _ => return Err(ControlError::Malformed)
+        };
+        match reader.0 {
+            [] => Ok(reply),
+            _ => Err(ControlError::Malformed)
+        }
+    }
+
+    fn encode_body(&self, message: &mut Writer) {
+        match self {
+            Self::Status(status) => {
+                message.str(&status.version);
+                message.u32(status.pid);
+                message.u64(status.uptime.as_secs());
+                message.u32(status.listeners);
+                message.str(&status.config.to_string_lossy());
+                message.u16(status.doors.len() as u16);
+                for (path, health) in &status.doors {
+                    message.str(&path.to_string_lossy());
+                    message.health(*health);
+                }
+            },
+            Self::Stats(stats) => {
+                message.u16(stats.listeners.len() as u16);
+                for listener in &stats.listeners {
+                    message.str(&listener.protocol.to_string());
+                    message.str(&listener.address.to_string());
+                    message.snapshot(&listener.metrics);
+                }
+                message.u16(stats.doors.len() as u16);
+                for door in &stats.doors {
+                    message.str(&door.path.to_string_lossy());
+                    message.health(door.health);
+                    message.snapshot(&door.metrics);
+                }
+            },
+            Self::Listeners(listeners) => {
+                message.u16(listeners.len() as u16);
+                for listener in listeners {
+                    message.str(&listener.protocol.to_string());
+                    message.str(&listener.address.to_string());
+                    message.u16(listener.doors.len() as u16);
+                    for door in &listener.doors {
+                        message.str(&door.to_string_lossy());
+                    }
+                }
+            },
+            Self::Reloaded(changes) => {
+                for count in [changes.started, changes.rerouted, changes.stopped, changes.unchanged, changes.failed] {
+                    message.u32(count as u32);
+                }
+            },
+            Self::Drained{ abandoned } => message.u32(*abandoned),
+            Self::Stopping => {}
+        }
+    }
+
+    fn decode_body(message: &mut Reader) -> Result<Self, ControlError> {
+        let code = message.u8()?;
+        match Request::from_code(code) {
+            Some(Request::Status) => {
+                let mut status = Status{
+                    version: message.string()?,
+                    pid: message.u32()?,
+                    uptime: Duration::from_secs(message.u64()?),
+                    listeners: message.u32()?,
+                    config: message.string()?.into(),
+                    doors: vec![]
+                };
+                for _ in 0..message.u16()? {
+                    status.doors.push((message.string()?.into(), message.health()?));
+                }
+                Ok(Self::Status(status))
+            },
+            Some(Request::Stats) => {
+                let mut stats = Stats::default();
+                for _ in 0..message.u16()? {
+                    stats.listeners.push(ListenerStats{
+                        protocol: message.protocol()?,
+                        address: message.address()?,
+                        metrics: message.snapshot()?
+                    });
+                }
+                for _ in 0..message.u16()? {
+                    stats.doors.push(DoorStats{
+                        path: message.string()?.into(),
+                        health: message.health()?,
+                        metrics: message.snapshot()?
+                    });
+                }
+                Ok(Self::Stats(stats))
+            },
+            Some(Request::Listeners) => {
+                let mut listeners = vec![];
+                for _ in 0..message.u16()? {
+                    let protocol = message.protocol()?;
+                    let address = message.address()?;
+                    let mut doors = vec![];
+                    for _ in 0..message.u16()? {
+                        doors.push(message.string()?.into());
+                    }
+                    listeners.push(Listener{ protocol, address, doors });
+                }
+                Ok(Self::Listeners(listeners))
+            },
+            Some(Request::Reload) => Ok(Self::Reloaded(Changes{
+                started: message.u32()? as usize,
+                rerouted: message.u32()? as usize,
+                stopped: message.u32()? as usize,
+                unchanged: message.u32()? as usize,
+                failed: message.u32()? as usize
+            })),
+            Some(Request::Drain) => Ok(Self::Drained{ abandoned: message.u32()? }),
+            Some(Request::Stop) => Ok(Self::Stopping),
+            None => Err(ControlError::UnknownRequest(code))
+        }
+    }
+}
+
+
+/// Why a [`Request`] was not carried out.
+#[derive(Debug,PartialEq,Clone)]
+pub enum ControlError {
+    /// One side spoke a version of the protocol which the other does not understand.
+    UnsupportedVersion(u8),
+    /// The request code was not recognized.
+    UnknownRequest(u8),
+    /// The message could not be decoded.
+    Malformed,
+    /// Only root, and the user `portunusd` runs as, may make privileged requests.
+    PermissionDenied,
+    /// The config file could not be read.
+    Unreadable(String),
+    /// The config file could not be parsed.
+    Unparsable{ line: usize, column: usize, message: String },
+    /// The config file parsed, but cannot work.
+    Invalid(Vec<Problem>),
+    /// Something else went wrong while carrying out the request.
+    Failed(String)
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported control protocol version: {}", version),
+            Self::UnknownRequest(code) => write!(f, "Unknown control request: {}", code),
+            Self::Malformed => write!(f, "Malformed control message"),
+            Self::PermissionDenied => write!(f, "Permission denied"),
+            Self::Unreadable(reason) => write!(f, "Could not read the config file: {}", reason),
+            Self::Unparsable{ line, column, message } => write!(f, "line {}, column {}: {}", line, column, message),
+            Self::Invalid(problems) => {
+                let problems: Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+                write!(f, "{}", problems.join("\n"))
+            },
+            Self::Failed(reason) => write!(f, "{}", reason)
+        }
+    }
+}
+
+impl ControlError {
+    fn encode(&self, message: &mut Writer) {
+        match self {
+            Self::UnsupportedVersion(version) => { message.u8(1); message.u8(*version); },
+            Self::UnknownRequest(code) => { message.u8(2); message.u8(*code); },
+            Self::Malformed => message.u8(3),
+            Self::PermissionDenied => message.u8(4),
+            Self::Unreadable(reason) => { message.u8(5); message.str(reason); },
+            Self::Unparsable{ line, column, message: text } => {
+                message.u8(6);
+                message.u32(*line as u32);
+                message.u32(*column as u32);
+                message.str(text);
+            },
+            Self::Invalid(problems) => {
+                message.u8(7);
+                message.u16(problems.len() as u16);
+                for problem in problems {
+                    message.u32(problem.line as u32);
+                    message.str(&problem.message);
+                }
+            },
+            Self::Failed(reason) => { message.u8(8); message.str(reason); }
+        }
+    }
+
+    fn decode(message: &mut Reader) -> Result<Self, ControlError> {
+        match message.u8()? {
+            1 => Ok(Self::UnsupportedVersion(message.u8()?)),
+            2 => Ok(Self::UnknownRequest(message.u8()?)),
+            3 => Ok(Self::Malformed),
+            4 => Ok(Self::PermissionDenied),
+            5 => Ok(Self::Unreadable(message.string()?)),
+            6 => Ok(Self::Unparsable{
+                line: message.u32()? as usize,
+                column: message.u32()? as usize,
+                message: message.string()?
+            }),
+            7 => {
+                let mut problems = vec![];
+                for _ in 0..message.u16()? {
+                    problems.push(Problem{ line: message.u32()? as usize, message: message.string()? });
+                }
+                Ok(Self::Invalid(problems))
+            },
+            8 => Ok(Self::Failed(message.string()?)),
+            _ => Err(Self::Malformed)
+        }
+    }
+}
+
+impl From<ParseError> for ControlError {
+    fn from(e: ParseError) -> Self {
+        let (line, column) = e.location().map(|location| (location.line, location.column)).unwrap_or((0, 0));
+        Self::Unparsable{ line, column, message: e.message().to_owned() }
+    }
+}
+
+impl From<SupervisorError> for ControlError {
+    fn from(e: SupervisorError) -> Self {
+        match e {
+            SupervisorError::Invalid(problems) => Self::Invalid(problems),
+            e => Self::Failed(format!("{:?}", e))
+        }
+    }
+}
+
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        let value = &value.as_bytes()[..value.len().min(u16::MAX as usize)];
+        self.u16(value.len() as u16);
+        self.0.extend_from_slice(value);
+    }
+
+    fn health(&mut self, health: Health) {
+        self.u8(match health {
+            Health::Healthy => 0,
+            Health::Failing(_) => 1,
+            Health::Open(_) => 2
+        });
+        self.u32(health.failures());
+    }
+
+    fn snapshot(&mut self, metrics: &Snapshot) {
+        self.u64(metrics.accepted);
+        self.u64(metrics.active);
+        self.u64(metrics.queued);
+        self.u64(metrics.rejected);
+        self.u64(metrics.abandoned);
+        self.u16(metrics.latency.buckets.len() as u16);
+        for count in &metrics.latency.buckets {
+            self.u64(*count);
+        }
+        self.u64(metrics.latency.sum.as_micros() as u64);
+        self.u16(metrics.errors.len() as u16);
+        for (errno, count) in &metrics.errors {
+            self.u32(*errno as u32);
+            self.u64(*count);
+        }
+        self.u64(metrics.bytes_in);
+        self.u64(metrics.bytes_out);
+    }
+}
+
+struct Reader<'message>(&'message [u8]);
+
+impl<'message> Reader<'message> {
+    fn take(&mut self, length: usize) -> Result<&'message [u8], ControlError> {
+        if self.0.len() < length {
+            return Err(ControlError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ControlError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ControlError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ControlError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ControlError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, ControlError> {
+        let length = self.u16()? as usize;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ControlError::Malformed)
+    }
+
+    fn address(&mut self) -> Result<SocketAddr, ControlError> {
+        self.string()?.parse().map_err(|_| ControlError::Malformed)
+    }
+
+    fn protocol(&mut self) -> Result<Protocol, ControlError> {
+        self.string()?.parse().map_err(|_| ControlError::Malformed)
+    }
+
+    fn health(&mut self) -> Result<Health, ControlError> {
+        let code = self.u8()?;
+        let failures = self.u32()?;
+        match code {
+            0 => Ok(Health::Healthy),
+            1 => Ok(Health::Failing(failures)),
+            2 => Ok(Health::Open(failures)),
+            _ => Err(ControlError::Malformed)
+        }
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot, ControlError> {
+        let accepted = self.u64()?;
+        let active = self.u64()?;
+        let queued = self.u64()?;
+        let rejected = self.u64()?;
+        let abandoned = self.u64()?;
+        let mut buckets = vec![];
+        for _ in 0..self.u16()? {
+            buckets.push(self.u64()?);
+        }
+        let latency = Latency{ buckets, sum: Duration::from_micros(self.u64()?) };
+        let mut errors = vec![];
+        for _ in 0..self.u16()? {
+            errors.push((self.u32()? as i32, self.u64()?));
+        }
+        Ok(Snapshot{ accepted, active, queued, rejected, abandoned, latency, errors, bytes_in: self.u64()?, bytes_out: self.u64()? })
+    }
+}
+
+
+define_error_enum!(
+    pub enum ClientError {
+        Door(doors::Error),
+        Control(ControlError)
+    }
+);
+
+
+/// A connection to the control door of a running `portunusd`.
+pub struct Client {
+    door: doors::Client
+}
+
+impl Client {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, doors::Error> {
+        Ok(Self{ door: doors::Client::new(path)? })
+    }
+
+    /// Send `request`, and wait for the answer.
+    pub fn call(&self, request: Request) -> Result<Response, ClientError> {
+        let (_descriptors, reply) = self.door.call(vec![], &request.encode())?;
+        let response = Response::decode(&reply)??;
+        if response.request() != request {
+            return Err(ControlError::Malformed.into());
+        }
+        Ok(response)
+    }
+
+    pub fn status(&self) -> Result<Status, ClientError> {
+        match self.call(Request::Status)? {
+            Response::Status(status) => Ok(status),
+            _ => unreachable!("call checks the response type")
+        }
+    }
+
+    pub fn stats(&self) -> Result<Stats, ClientError> {
+        match self.call(Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            _ => unreachable!("call checks the response type")
+        }
+    }
+
+    pub fn listeners(&self) -> Result<Vec<Listener>, ClientError> {
+        match self.call(Request::Listeners)? {
+            Response::Listeners(listeners) => Ok(listeners),
+            _ => unreachable!("call checks the response type")
+        }
+    }
+
+    pub fn reload(&self) -> Result<Changes, ClientError> {
+        match self.call(Request::Reload)? {
+            Response::Reloaded(changes) => Ok(changes),
+            _ => unreachable!("call checks the response type")
+        }
+    }
+
+    /// Returns the number of listeners which were still busy when time ran out.
+    pub fn drain(&self) -> Result<u32, ClientError> {
+        match self.call(Request::Drain)? {
+            Response::Drained{ abandoned } => Ok(abandoned),
+            _ => unreachable!("call checks the response type")
+        }
+    }
+
+    pub fn stop(&self) -> Result<(), ClientError> {
+        self.call(Request::Stop)?;
+        Ok(())
+    }
+}
+
+
+/// Something which can carry out control requests.
+pub trait Controller {
+    fn status(&self) -> Status;
+    fn stats(&self) -> Stats;
+    fn listeners(&self) -> Vec<Listener>;
+    fn reload(&self) -> Result<Changes, ControlError>;
+    /// Returns the number of listeners which were still busy when time ran out.
+    fn drain(&self) -> usize;
+    /// Begin shutting down. This should return right away, so that the caller gets an answer.
+    fn stop(&self) -> Result<(), ControlError>;
+}
+
+/// Decode a control request, carry it out, and encode the reply. Call this from the control
+/// door's server procedure.
+///
+/// Anybody who can open the control door may ask questions, but only root and the user running
+/// `portunusd` may make privileged requests.
+pub fn answer(controller: &impl Controller, message: &[u8]) -> Vec<u8> {
+    let reply = Request::decode(message).and_then(|request| {
+        if request.is_privileged() && !authorized() {
+            return Err(ControlError::PermissionDenied);
+        }
+        match request {
+            Request::Status => Ok(Response::Status(controller.status())),
+            Request::Stats => Ok(Response::Stats(controller.stats())),
+            Request::Listeners => Ok(Response::Listeners(controller.listeners())),
+            Request::Reload => Ok(Response::Reloaded(controller.reload()?)),
+            Request::Drain => Ok(Response::Drained{ abandoned: controller.drain() as u32 }),
+            Request::Stop => controller.stop().map(|_| Response::Stopping)
+        }
+    });
+    Response::encode(&reply)
+}
+
+/// Whether the caller of the current door call is root, or the same user as us.
+fn authorized() -> bool {
+    match doors::caller() {
+        Ok(caller) => caller.uid == 0 || caller.uid == unsafe{ libc::geteuid() },
+        Err(_) => false
+    }
+}
+
+
+/// The [`Controller`] behind `portunusd`'s control door.
+pub struct Daemon {
+    supervisor: Mutex<Supervisor>,
+    config_path: PathBuf,
+    started: Instant,
+    /// The directory we have been locked into, if any
+    chroot: OnceLock<PathBuf>,
+    /// Called to begin shutting down
+    on_stop: Box<dyn Fn() + Send + Sync>
+}
+
+impl Daemon {
+    /// Take charge of `supervisor`, whose relays were started from the config at `config_path`.
+    ///
+    /// `on_stop` is called when somebody asks the daemon to stop. It should arrange for the
+    /// daemon to be drained and for the process to exit, but without waiting for either.
+    pub fn new<F>(supervisor: Supervisor, config_path: PathBuf, on_stop: F) -> Self
+    where F: Fn() + Send + Sync + 'static {
+        Self{
+            supervisor: Mutex::new(supervisor),
+            config_path,
+            started: Instant::now(),
+            chroot: OnceLock::new(),
+            on_stop: Box::new(on_stop)
+        }
+    }
+
+    /// Note that the daemon has been locked into `directory`, where the config file is out of
+    /// reach. From then on, reloads are refused rather than left to fail on a missing file.
+    pub fn confine(&self, directory: PathBuf) {
+        let _ = self.chroot.set(directory);
+    }
+
+    fn read_config(&self) -> Result<Config, ControlError> {
+        let text = fs::read_to_string(&self.config_path)
+            .map_err(|e| ControlError::Unreadable(format!("{}: {}", self.config_path.display(), e)))?;
+        let config = text.parse().map_err(|e: ParseError| e.in_file(&self.config_path))?;
+        Ok(config)
+    }
+
+    fn supervisor(&self) -> std::sync::MutexGuard<'_, Supervisor> {
+        // A panic while reloading leaves the relays as they were, so carry on regardless
+        self.supervisor.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Controller for Daemon {
+    fn status(&self) -> Status {
+        Status{
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            pid: std::process::id(),
+            uptime: Duration::from_secs(self.started.elapsed().as_secs()),
+            listeners: self.supervisor().relays().len() as u32,
+            config: self.config_path.clone(),
+            doors: metrics::doors().into_iter().map(|(path, health, _)| (path, health)).collect()
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        let listeners = self.supervisor().relays().iter()
+            .map(|relay| ListenerStats{
+                protocol: relay.statement.protocol.clone(),
+                address: relay.local_addr().unwrap_or(relay.statement.address),
+                metrics: relay.meter().snapshot()
+            })
+            .collect();
+        let doors = metrics::doors().into_iter()
+            .map(|(path, health, metrics)| DoorStats{ path, health, metrics })
+            .collect();
+        Stats{ listeners, doors }
+    }
+
+    fn listeners(&self) -> Vec<Listener> {
+        self.supervisor().relays().iter()
+            .map(|relay| Listener{
+                protocol: relay.statement.protocol.clone(),
+                address: relay.local_addr().unwrap_or(relay.statement.address),
+                doors: relay.statement.target.doors().into_iter().map(Path::to_path_buf).collect()
+            })
+            .collect()
+    }
+
+    fn reload(&self) -> Result<Changes, ControlError> {
+        if let Some(directory) = self.chroot.get() {
+            return Err(ControlError::Failed(format!(
+                "portunusd is locked into {}, so it cannot reload {}; restart it instead",
+                directory.display(), self.config_path.display()
+            )));
+        }
+        let config = self.read_config()?;
+        let mut supervisor = self.supervisor();
+        let plan = supervisor.plan(&config)?;
+        Ok(supervisor.commit(plan))
+    }
+
+    fn drain(&self) -> usize {
+        self.supervisor().drain()
+    }
+
+    fn stop(&self) -> Result<(), ControlError> {
+        (self.on_stop)();
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::tests::{install, Ping};
+    use std::net;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn everything_makes_the_round_trip() {
+        for request in [Request::Status, Request::Stats, Request::Listeners, Request::Reload, Request::Drain, Request::Stop] {
+            assert_eq!(Request::decode(&request.encode()), Ok(request));
+        }
+
+        let replies = vec![
+            Ok(Response::Status(Status{
+                version: "0.3.0".to_owned(),
+                pid: 4242,
+                uptime: Duration::from_secs(90),
+                listeners: 2,
+                config: "/opt/local/etc/portunusd.conf".into(),
+                doors: vec![("/var/run/a.door".into(), Health::Healthy), ("/var/run/b.door".into(), Health::Open(3))]
+            })),
+            Ok(Response::Stats(Stats{
+                listeners: vec![ListenerStats{
+                    protocol: Protocol::UDP,
+                    address: "[::1]:7".parse().unwrap(),
+                    metrics: Snapshot{ accepted: 12, bytes_in: 40, ..Default::default() }
+                }],
+                doors: vec![DoorStats{
+                    path: "/var/run/echo.door".into(),
+                    health: Health::Failing(2),
+                    metrics: Snapshot{
+                        accepted: 12,
+                        active: 1,
+                        queued: 2,
+                        rejected: 5,
+                        abandoned: 6,
+                        latency: Latency{ buckets: vec![3, 0, 8], sum: Duration::from_micros(1234) },
+                        errors: vec![(-1, 2), (libc::EINTR, 1)],
+                        bytes_in: 400,
+                        bytes_out: 40
+                    }
+                }]
+            })),
+            Ok(Response::Listeners(vec![Listener{
+                protocol: Protocol::HTTPS,
+                address: "0.0.0.0:443".parse().unwrap(),
+                doors: vec!["/var/run/a.door".into(), "/var/run/b.door".into()]
+            }])),
+            Ok(Response::Reloaded(Changes{ started: 1, rerouted: 2, stopped: 3, unchanged: 4, failed: 5 })),
+            Ok(Response::Drained{ abandoned: 0 }),
+            Ok(Response::Stopping),
+            Err(ControlError::PermissionDenied),
+            Err(ControlError::Unparsable{ line: 3, column: 9, message: "Unrecognized Protocol: ftp".to_owned() }),
+            Err(ControlError::Invalid(vec![Problem{ line: 2, message: "tls needs the 'key' parameter".to_owned() }])),
+            Err(ControlError::Failed("oh no".to_owned()))
+        ];
+        for reply in replies {
+            assert_eq!(Response::decode(&Response::encode(&reply)), Ok(reply));
+        }
+    }
+
+    #[test]
+    fn stats_can_be_rendered_as_json() {
+        let stats = Stats{
+            listeners: vec![ListenerStats{
+                protocol: Protocol::TCP,
+                address: "127.0.0.1:7".parse().unwrap(),
+                metrics: Snapshot{ accepted: 3, ..Default::default() }
+            }],
+            doors: vec![DoorStats{
+                path: "/var/run/\"quoted\".door".into(),
+                health: Health::Open(3),
+                metrics: Snapshot{
+                    accepted: 3,
+                    latency: Latency{ buckets: vec![1, 2], sum: Duration::from_micros(300) },
+                    errors: vec![(libc::EBADF, 1)],
+                    ..Default::default()
+                }
+            }]
+        };
+        let json = stats.to_json();
+        assert!(json.starts_with("{\"latency_bounds_us\":[100,250,"));
+        assert!(json.contains(r#""listeners":[{"protocol":"tcp","address":"127.0.0.1:7","accepted":3,"active":0,"#));
+        assert!(json.contains(&format!(
+            r#""doors":[{{"path":"/var/run/\"quoted\".door","health":"circuit open (3 failures in a row)","failures":3,"accepted":3,"active":0,"queued":0,"rejected":0,"latency":{{"buckets":[1,2],"count":3,"sum_us":300}},"errors":{{"{}":1}},"abandoned":0,"bytes_in":0,"bytes_out":0}}]}}"#,
+            libc::EBADF
+        )));
+    }
+
+    #[test]
+    fn bad_messages_are_rejected() {
+        assert_eq!(Request::decode(&[]), Err(ControlError::Malformed));
+        assert_eq!(Request::decode(&[2, 1]), Err(ControlError::UnsupportedVersion(2)));
+        assert_eq!(Request::decode(&[VERSION, 99]), Err(ControlError::UnknownRequest(99)));
+        assert_eq!(Response::decode(&[VERSION, OK, 5, 0, 0]), Err(ControlError::Malformed));
+        assert_eq!(Response::decode(&[VERSION, OK, 6, 0]), Err(ControlError::Malformed));
+        assert_eq!(Response::decode(&[2, OK, 6]), Err(ControlError::UnsupportedVersion(2)));
+    }
+
+    fn temp(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn daemons_can_be_controlled_through_their_door() {
+        let (hello_path, _hello) = install::<Ping>("portunusd_control_test.0b3d5e");
+        let config_path = temp("portunusd_control_test.conf");
+        fs::write(&config_path, format!("forward tcp 127.0.0.1:0 to {}", hello_path.display())).unwrap();
+
+        let mut supervisor = Supervisor::new();
+        let config: Config = fs::read_to_string(&config_path).unwrap().parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        supervisor.commit(plan);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let daemon = {
+            let stopped = Arc::clone(&stopped);
+            Daemon::new(supervisor, config_path.clone(), move|| stopped.store(true, Ordering::SeqCst))
+        };
+
+        let control_path = temp("portunusd_control_test.7c41a9");
+        let _control = doors::Server::from_fn(control_path.to_str().unwrap(), move |request: doors::Request| {
+            doors::Response::new(answer(&daemon, request.data))
+        }).unwrap();
+        let client = Client::new(&control_path).unwrap();
+
+        let status = client.status().unwrap();
+        assert_eq!(status.pid, std::process::id());
+        assert_eq!(status.listeners, 1);
+        assert_eq!(status.config, config_path);
+        assert!(status.doors.contains(&(hello_path.clone(), Health::Healthy)));
+
+        let listeners = client.listeners().unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].protocol, Protocol::TCP);
+        assert_eq!(listeners[0].doors, vec![hello_path.clone()]);
+
+        let address = listeners[0].address;
+        drop(net::TcpStream::connect(address).unwrap());
+        let mut stats = client.stats().unwrap();
+        while stats.listeners[0].metrics.latency.count() == 0 {
+            // The attendant may not have finished with our connection yet
+            std::thread::sleep(Duration::from_millis(10));
+            stats = client.stats().unwrap();
+        }
+        assert_eq!(stats.listeners[0].address, address);
+        assert_eq!(stats.listeners[0].metrics.accepted, 1);
+        let door = stats.doors.iter().find(|door| door.path == hello_path).unwrap();
+        assert_eq!(door.metrics.accepted, 1);
+        assert_eq!(door.metrics.active, 0);
+
+        // Bad configs come back as problems, not strings
+        fs::write(&config_path, format!("forward tls 127.0.0.1:0 to {}", hello_path.display())).unwrap();
+        match client.reload() {
+            Err(ClientError::Control(ControlError::Invalid(problems))) => assert_eq!(problems[0].line, 1),
+            other => panic!("expected problems, got {:?}", other)
+        }
+        fs::write(&config_path, "forward tcp 127.0.0.1 to /var/run/hello.door").unwrap();
+        match client.reload() {
+            Err(ClientError::Control(ControlError::Unparsable{ line, column, .. })) => assert_eq!((line, column), (1, 13)),
+            other => panic!("expected a parse error, got {:?}", other)
+        }
+
+        fs::write(&config_path, format!("forward tcp 127.0.0.1:0 to {}", hello_path.display())).unwrap();
+        assert_eq!(client.reload().unwrap(), Changes{ unchanged: 1, ..Default::default() });
+        assert_eq!(client.drain().unwrap(), 0);
+        assert!(client.listeners().unwrap().is_empty());
+        assert!(net::TcpStream::connect(address).is_err());
+
+        assert!(client.stop().is_ok());
+        assert!(stopped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn confined_daemons_refuse_to_reload() {
+        let config_path = temp("portunusd_control_test.3f9a6c.conf");
+        fs::write(&config_path, "").unwrap();
+        let daemon = Daemon::new(Supervisor::new(), config_path.clone(), || {});
+        assert_eq!(daemon.reload().unwrap(), Changes::default());
+
+        daemon.confine("/var/empty".into());
+        match daemon.reload() {
+            Err(ControlError::Failed(reason)) => assert!(reason.contains("/var/empty")),
+            other => panic!("expected a refusal, got {:?}", other)
+        }
+    }
+}
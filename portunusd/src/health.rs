@@ -0,0 +1,361 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Door Health
+//!
+//! When a door application dies, its jamb can stay behind, and calls to it fail (or hang) in ways
+//! that are hard to tell apart from a busy application. So every door PortunusD forwards to is a
+//! [`Door`], shared by every relay which forwards to the same path, and each one keeps a circuit
+//! breaker. After [`FAILURE_THRESHOLD`] failed calls in a row, the circuit opens, and calls fail
+//! fast with [`CircuitOpen`] instead of piling up behind an application that isn't there.
+//!
+//! If the config sets `probe_interval`, a [`Prober`] checks on every door every so often by
+//! calling it with an empty request (see [`doors::envelope::is_probe`]). A door which fails its
+//! probe is reopened from its path, in case the application has re-created it, and the circuit
+//! closes as soon as a probe succeeds. If nobody is probing, an open circuit lets one call through
+//! every [`RETRY_AFTER`] to see whether things have improved. Calls which follow a failure reopen
+//! the door first, just like probes do.
+
+// Types
+use crate::metrics::Meter;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// How many calls (or probes) in a row must fail before the circuit opens.
+pub const FAILURE_THRESHOLD: u32 = 3;
+
+/// How long an open circuit waits before letting a call through to try its luck.
+pub const RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// How long a door may take to answer a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+
+/// How a door has fared lately.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub enum Health {
+    /// The last call (or probe) succeeded
+    #[default]
+    Healthy,
+    /// This many calls (or probes) in a row have failed, which is not yet enough to open the
+    /// circuit
+    Failing(u32),
+    /// The circuit is open after this many failures in a row, so calls fail fast
+    Open(u32)
+}
+
+impl Health {
+    /// How many calls (or probes) in a row have failed.
+    pub fn failures(&self) -> u32 {
+        match self {
+            Self::Healthy => 0,
+            Self::Failing(failures) | Self::Open(failures) => *failures
+        }
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Healthy => write!(f, "healthy"),
+            Self::Failing(failures) => write!(f, "failing ({} in a row)", failures),
+            Self::Open(failures) => write!(f, "circuit open ({} failures in a row)", failures)
+        }
+    }
+}
+
+
+/// A circuit breaker's state.
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    /// When the circuit opened, or last let a call through to try its luck
+    opened: Option<Instant>
+}
+
+impl Breaker {
+    fn record(&mut self, succeeded: bool) {
+        if succeeded {
+            *self = Self::default();
+            return;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= FAILURE_THRESHOLD {
+            self.opened = Some(Instant::now());
+        }
+    }
+
+    /// Whether an open circuit is ready to let a call through to try its luck.
+    fn retry_due(&self) -> bool {
+        matches!(self.opened, Some(opened) if opened.elapsed() >= RETRY_AFTER)
+    }
+
+    /// Whether a call may go through. An open circuit lets one call through every
+    /// [`RETRY_AFTER`].
+    fn allows(&mut self) -> bool {
+        match self.opened {
+            None => true,
+            Some(opened) if opened.elapsed() >= RETRY_AFTER => {
+                self.opened = Some(Instant::now());
+                true
+            },
+            Some(_) => false
+        }
+    }
+
+    fn health(&self) -> Health {
+        match (self.failures, self.opened) {
+            (0, _) => Health::Healthy,
+            (failures, None) => Health::Failing(failures),
+            (failures, Some(_)) => Health::Open(failures)
+        }
+    }
+}
+
+
+/// A call was turned away without reaching the door, because the door's circuit is open.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct CircuitOpen;
+
+
+/// A door PortunusD forwards to, and how it has fared.
+pub struct Door {
+    path: PathBuf,
+    /// Replaced when the door is reopened. Calls already underway keep the old client alive.
+    client: Mutex<Arc<doors::Client>>,
+    breaker: Mutex<Breaker>,
+    meter: Arc<Meter>
+}
+
+impl Door {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Counts the calls placed to this door from every listener.
+    pub fn meter(&self) -> &Arc<Meter> {
+        &self.meter
+    }
+
+    pub fn health(&self) -> Health {
+        self.breaker().health()
+    }
+
+    /// Whether the door's circuit is open, but ready to let a call through to try its luck.
+    pub fn retry_due(&self) -> bool {
+        self.breaker().retry_due()
+    }
+
+    /// The client to call the door with, unless the circuit is open.
+    ///
+    /// If the last call failed, the door is reopened from its path first, in case the application
+    /// has re-created it. Report how the call went with [`Door::record`].
+    pub fn client(&self) -> Result<Arc<doors::Client>, CircuitOpen> {
+        let failed = {
+            let mut breaker = self.breaker();
+            if !breaker.allows() {
+                return Err(CircuitOpen);
+            }
+            breaker.failures > 0
+        };
+        if failed {
+            self.reopen();
+        }
+        Ok(Arc::clone(&self.client.lock().unwrap_or_else(|poisoned| poisoned.into_inner())))
+    }
+
+    /// Open the door from its path again, keeping the old client if that fails.
+    fn reopen(&self) {
+        if let Ok(reopened) = doors::Client::new(&self.path) {
+            *self.client.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(reopened);
+        }
+    }
+
+    /// Let the next call through straight away, as if [`RETRY_AFTER`] had passed.
+    #[cfg(test)]
+    pub fn expire(&self) {
+        if let Some(opened) = &mut self.breaker().opened {
+            *opened -= RETRY_AFTER;
+        }
+    }
+
+    /// Count a call as a success or a failure.
+    pub fn record<T>(&self, result: &Result<T, doors::Error>) {
+        self.breaker().record(result.is_ok());
+    }
+
+    /// Call the door with an empty request, and reopen it from its path if that fails.
+    pub fn probe(&self) -> Health {
+        let client = Arc::clone(&self.client.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        let mut succeeded = client.call_with_timeout(vec![], &[], PROBE_TIMEOUT).is_ok();
+        if !succeeded {
+            if let Ok(reopened) = doors::Client::new(&self.path) {
+                if reopened.call_with_timeout(vec![], &[], PROBE_TIMEOUT).is_ok() {
+                    *self.client.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(reopened);
+                    succeeded = true;
+                }
+            }
+        }
+        let mut breaker = self.breaker();
+        breaker.record(succeeded);
+        breaker.health()
+    }
+
+    fn breaker(&self) -> MutexGuard<'_, Breaker> {
+        self.breaker.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+
+/// Every door which is still in use, by path.
+static DOORS: Mutex<BTreeMap<PathBuf, Weak<Door>>> = Mutex::new(BTreeMap::new());
+
+/// The door at `path`, shared with everything else which uses that door.
+///
+/// A door which is already in use is shared as it is, even if its circuit is open, so that its
+/// health and its meter carry over. Otherwise the door must open successfully.
+pub fn open(path: &Path) -> Result<Arc<Door>, doors::Error> {
+    let mut registry = DOORS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(door) = registry.get(path).and_then(Weak::upgrade) {
+        return Ok(door);
+    }
+    let door = Arc::new(Door {
+        path: path.to_path_buf(),
+        client: Mutex::new(Arc::new(doors::Client::new(path)?)),
+        breaker: Mutex::default(),
+        meter: Arc::default()
+    });
+    registry.insert(path.to_path_buf(), Arc::downgrade(&door));
+    Ok(door)
+}
+
+/// Every door which is still in use, in order of path.
+pub fn doors() -> Vec<Arc<Door>> {
+    let mut registry = DOORS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    registry.retain(|_, door| door.strong_count() > 0);
+    registry.values().filter_map(Weak::upgrade).collect()
+}
+
+
+/// A thread which probes every door in use, every so often.
+pub struct Prober {
+    pub interval: Duration,
+    stop: mpsc::Sender<()>,
+    join_handle: thread::JoinHandle<()>
+}
+
+impl Prober {
+    pub fn start(interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let join_handle = thread::spawn(move|| {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                for door in doors() {
+                    let before = door.health();
+                    let after = door.probe();
+                    // Only mention the circuit opening or closing, rather than every blip
+                    if matches!(before, Health::Open(_)) != matches!(after, Health::Open(_)) {
+                        eprintln!("{}: {}", door.path().display(), after);
+                    }
+                }
+            }
+        });
+        Self{ interval, stop, join_handle }
+    }
+
+    /// Stop probing, and wait for any probe underway to finish.
+    pub fn stop(self) {
+        drop(self.stop);
+        let _ = self.join_handle.join();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::tests::{install, Ping};
+    use doors::ServerProcedure;
+
+    #[test]
+    fn circuits_open_after_repeated_failures() {
+        let mut breaker = Breaker::default();
+        for failures in 1..FAILURE_THRESHOLD {
+            breaker.record(false);
+            assert_eq!(breaker.health(), Health::Failing(failures));
+            assert!(breaker.allows());
+        }
+        breaker.record(false);
+        assert_eq!(breaker.health(), Health::Open(FAILURE_THRESHOLD));
+        assert!(!breaker.allows());
+
+        // After a while, one call may try its luck
+        breaker.opened = Some(Instant::now() - RETRY_AFTER);
+        assert!(breaker.allows());
+        assert!(!breaker.allows());
+
+        breaker.record(true);
+        assert_eq!(breaker.health(), Health::Healthy);
+        assert!(breaker.allows());
+    }
+
+    #[test]
+    fn doors_are_reopened_when_their_application_comes_back() {
+        let (path, server) = install::<Ping>("portunusd_health_test.71c0d9");
+
+        let door = open(&path).unwrap();
+        assert!(Arc::ptr_eq(&door, &open(&path).unwrap()));
+        assert_eq!(door.probe(), Health::Healthy);
+
+        // The application goes away, and its door with it
+        drop(server);
+        for failures in 1..FAILURE_THRESHOLD {
+            assert_eq!(door.probe(), Health::Failing(failures));
+        }
+        assert_eq!(door.probe(), Health::Open(FAILURE_THRESHOLD));
+        assert!(matches!(door.client(), Err(CircuitOpen)));
+
+        // The application comes back, and the next probe finds it
+        let _server = Ping::install(path.to_str().unwrap()).unwrap();
+        assert_eq!(door.probe(), Health::Healthy);
+        let result = door.client().unwrap().call(vec![], &[]);
+        assert!(result.is_ok());
+
+        drop(door);
+        assert!(doors().iter().all(|door| door.path() != path));
+    }
+
+    #[test]
+    fn doors_are_reopened_without_a_prober() {
+        let (path, server) = install::<Ping>("portunusd_health_test.52ae0f");
+        let door = open(&path).unwrap();
+
+        // The application goes away, and calls fail until the circuit opens
+        drop(server);
+        for _ in 0..FAILURE_THRESHOLD {
+            let result = door.client().unwrap().call(vec![], &[]);
+            door.record(&result);
+        }
+        assert_eq!(door.health(), Health::Open(FAILURE_THRESHOLD));
+        assert!(!door.retry_due());
+        assert!(matches!(door.client(), Err(CircuitOpen)));
+
+        // The application comes back, and the next trial call finds it
+        let _server = Ping::install(path.to_str().unwrap()).unwrap();
+        door.expire();
+        assert!(door.retry_due());
+        let result = door.client().unwrap().call(vec![], &[]);
+        door.record(&result);
+        assert!(result.is_ok());
+        assert_eq!(door.health(), Health::Healthy);
+    }
+}
@@ -0,0 +1,402 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! HTTP/1.1 Front End
+//!
+//! PortunusD only needs to understand enough HTTP to pick a door: the method and path from the
+//! request line, and enough of the headers to know where the body ends. Everything else is passed
+//! along to the door application untouched.
+//!
+//! Requests are normalized before they are forwarded. A chunked body is reassembled, and the door
+//! sees a plain `Content-Length` instead of `Transfer-Encoding: chunked`. That way, door
+//! applications never have to deal with chunked encoding themselves.
+
+
+// Types
+use crate::config::Method;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+
+
+/// The most bytes we will read for the request line and headers combined.
+pub const MAX_HEAD: u64 = 64 * 1024;
+
+/// The largest request body we will forward to a door.
+pub const MAX_BODY: u64 = 1024 * 1024;
+
+
+/// An HTTP request, ready to be forwarded.
+#[derive(Debug,PartialEq)]
+pub struct Request {
+    pub method: Method,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String,String)>,
+    pub body: Vec<u8>
+}
+
+
+/// Why a request could not be read.
+///
+/// Apart from `Io`, each of these corresponds to an HTTP status code which should be sent back to
+/// the client. See [`HttpError::status`].
+#[derive(Debug)]
+pub enum HttpError {
+    Io(io::Error),
+    BadRequest(String),
+    HeadersTooLarge,
+    PayloadTooLarge,
+    NotImplemented(String),
+    VersionNotSupported(String)
+}
+
+impl From<io::Error> for HttpError {
+    fn from(other: io::Error) -> Self {
+        Self::Io(other)
+    }
+}
+
+impl HttpError {
+    /// The status code that best describes this problem, if the client is still there to hear it.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Io(_) => None,
+            Self::BadRequest(_) => Some(400),
+            Self::HeadersTooLarge => Some(431),
+            Self::PayloadTooLarge => Some(413),
+            Self::NotImplemented(_) => Some(501),
+            Self::VersionNotSupported(_) => Some(505)
+        }
+    }
+}
+
+macro_rules! bad_request {
+    ( $fmtstr:expr $(, $parameters:expr)* ) => {
+        Err(HttpError::BadRequest(format!($fmtstr $(, $parameters)*)))
+    };
+}
+
+
+impl Request {
+    /// Read a single request from a client.
+    ///
+    /// This reads exactly as much as the request claims to contain, so it is safe to call again
+    /// on the same reader for a pipelined request.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Self,HttpError> {
+        let mut budget = MAX_HEAD;
+
+        let request_line = read_line(reader, &mut budget)?;
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v), None) if !m.is_empty() && !t.is_empty() => (m, t, v),
+            _ => return bad_request!("Malformed request line: {}", request_line)
+        };
+        let method: Method = method.parse()
+            .map_err(|_| HttpError::NotImplemented(format!("Unsupported method: {}", method)))?;
+        if !target.starts_with('/') {
+            return bad_request!("Unsupported request target: {}", target);
+        }
+        if version != "HTTP/1.1" && version != "HTTP/1.0" {
+            if version.starts_with("HTTP/") {
+                return Err(HttpError::VersionNotSupported(version.to_owned()));
+            }
+            return bad_request!("Malformed version: {}", version);
+        }
+        let target = target.to_owned();
+        let version = version.to_owned();
+
+        let mut headers = vec![];
+        loop {
+            let line = read_line(reader, &mut budget)?;
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with(' ') || line.starts_with('\t') {
+                return bad_request!("Obsolete line folding is not allowed");
+            }
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) if is_token(name) => (name, value.trim()),
+                _ => return bad_request!("Malformed header: {}", line)
+            };
+            headers.push((name.to_owned(), value.to_owned()));
+        }
+
+        let mut request = Self{ method, target, version, headers, body: vec![] };
+        request.body = request.read_body(reader)?;
+        Ok(request)
+    }
+
+    /// The path portion of the request target, without any query string.
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _query)) => path,
+            None => &self.target
+        }
+    }
+
+    /// The value of the first header with this (case-insensitive) name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Serialize this request for a door.
+    ///
+    /// The body is always described by `Content-Length`, regardless of how the client sent it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("{} {} {}\r\n", self.method, self.target, self.version).into_bytes();
+        for (name, value) in &self.headers {
+            if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+                continue;
+            }
+            bytes.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if !self.body.is_empty() {
+            bytes.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    fn read_body<R: BufRead>(&self, reader: &mut R) -> Result<Vec<u8>,HttpError> {
+        let lengths: Vec<&str> = self.headers.iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("Content-Length"))
+            .map(|(_, v)| v.as_str())
+            .collect();
+        let encodings: Vec<&str> = self.headers.iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("Transfer-Encoding"))
+            .flat_map(|(_, v)| v.split(','))
+            .map(|v| v.trim())
+            .collect();
+
+        if !encodings.is_empty() {
+            // Allowing both is how requests get smuggled past proxies
+            if !lengths.is_empty() {
+                return bad_request!("Both Content-Length and Transfer-Encoding were given");
+            }
+            if encodings.len() != 1 || !encodings[0].eq_ignore_ascii_case("chunked") {
+                return Err(HttpError::NotImplemented(format!("Unsupported Transfer-Encoding: {}", encodings.join(", "))));
+            }
+            return read_chunked(reader);
+        }
+
+        let length = match lengths.split_first() {
+            None => return Ok(vec![]),
+            Some((first, rest)) => {
+                if rest.iter().any(|other| other != first) {
+                    return bad_request!("Conflicting Content-Length headers");
+                }
+                match first.parse::<u64>() {
+                    Ok(length) if first.bytes().all(|b| b.is_ascii_digit()) => length,
+                    _ => return bad_request!("Invalid Content-Length: {}", first)
+                }
+            }
+        };
+        if length > MAX_BODY {
+            return Err(HttpError::PayloadTooLarge);
+        }
+
+        let mut body = vec![0u8; length as usize];
+        reader.read_exact(&mut body)?;
+        Ok(body)
+    }
+}
+
+
+/// Reassemble a chunked body, discarding any extensions and trailers.
+///
+/// Chunk sizes, extensions, and trailers count against `MAX_BODY` along with the chunks
+/// themselves, so neither tiny chunks nor endless trailers can keep us reading forever.
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>,HttpError> {
+    let mut body = vec![];
+    let mut budget = MAX_BODY;
+    loop {
+        let line = read_framing(reader, &mut budget)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = match u64::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_) => return bad_request!("Invalid chunk size: {}", line)
+        };
+        if size == 0 {
+            break;
+        }
+        if size > budget {
+            return Err(HttpError::PayloadTooLarge);
+        }
+        budget -= size;
+        let start = body.len();
+        body.resize(start + size as usize, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_framing(reader, &mut budget)?.is_empty() {
+            return bad_request!("Chunk was longer than its size");
+        }
+    }
+
+    // Trailers
+    while !read_framing(reader, &mut budget)?.is_empty() {}
+    Ok(body)
+}
+
+/// Read a line of chunked framing, which is part of the body rather than the head.
+fn read_framing<R: BufRead>(reader: &mut R, budget: &mut u64) -> Result<String,HttpError> {
+    match read_line(reader, budget) {
+        Err(HttpError::HeadersTooLarge) => Err(HttpError::PayloadTooLarge),
+        result => result
+    }
+}
+
+
+/// Read a CRLF- (or bare LF-) terminated line, charging its length against `budget`.
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut u64) -> Result<String,HttpError> {
+    let mut line = vec![];
+    let read = reader.by_ref().take(*budget).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Err(HttpError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    if line.last() != Some(&b'\n') {
+        if read as u64 == *budget {
+            return Err(HttpError::HeadersTooLarge);
+        }
+        return Err(HttpError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    *budget -= read as u64;
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).or(bad_request!("Request head is not valid UTF-8"))
+}
+
+/// Header names are RFC 9110 tokens.
+fn is_token(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+
+/// The standard reason phrase for the status codes PortunusD generates itself.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown"
+    }
+}
+
+/// Build a complete, bodiless response for errors that PortunusD answers on its own.
+pub fn response(status: u16, headers: &[(&str,String)]) -> Vec<u8> {
+    let mut bytes = format!("HTTP/1.1 {} {}\r\n", status, reason(status)).into_bytes();
+    for (name, value) in headers {
+        bytes.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    bytes.extend_from_slice(b"Content-Length: 0\r\nConnection: close\r\n\r\n");
+    bytes
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request,HttpError> {
+        Request::read_from(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn can_parse_simple_request() {
+        let request = parse("GET /primes?limit=10 HTTP/1.1\r\nHost: portunusd.net\r\n\r\n").unwrap();
+        assert_eq!(request.method, Method::GET);
+        assert_eq!(request.target, "/primes?limit=10");
+        assert_eq!(request.path(), "/primes");
+        assert_eq!(request.header("host"), Some("portunusd.net"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn can_parse_content_length_body() {
+        let request = parse("POST /signup HTTP/1.1\r\nContent-Length: 5\r\n\r\nalice").unwrap();
+        assert_eq!(request.body, b"alice");
+    }
+
+    #[test]
+    fn can_parse_chunked_body() {
+        let raw = "POST /signup HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;ext=1\r\nali\r\n2\r\nce\r\n0\r\nTrailer: x\r\n\r\n";
+        let request = parse(raw).unwrap();
+        assert_eq!(request.body, b"alice");
+        assert_eq!(
+            String::from_utf8(request.to_bytes()).unwrap(),
+            "POST /signup HTTP/1.1\r\nContent-Length: 5\r\n\r\nalice"
+        );
+    }
+
+    #[test]
+    fn reads_pipelined_requests_one_at_a_time() {
+        let raw = "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let mut reader = raw.as_bytes();
+        assert_eq!(Request::read_from(&mut reader).unwrap().target, "/a");
+        assert_eq!(Request::read_from(&mut reader).unwrap().target, "/b");
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(parse("GET /\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("BREW /pot HTTP/1.1\r\n\r\n").unwrap_err().status(), Some(501));
+        assert_eq!(parse("GET / HTTP/2.0\r\n\r\n").unwrap_err().status(), Some(505));
+        assert_eq!(parse("GET / HTTP/1.1\r\nBad Header\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("GET / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("GET / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").unwrap_err().status(), Some(501));
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        assert_eq!(parse(&raw).unwrap_err().status(), Some(413));
+
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nA\r\nffffffffffffffff\r\n";
+        assert_eq!(parse(raw).unwrap_err().status(), Some(413));
+
+        let raw = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(MAX_HEAD as usize));
+        assert_eq!(parse(&raw).unwrap_err().status(), Some(431));
+
+        let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1;{}\r\n", "x".repeat(MAX_BODY as usize));
+        assert_eq!(parse(&raw).unwrap_err().status(), Some(413));
+    }
+
+    #[test]
+    fn chunk_framing_is_not_part_of_the_head() {
+        let chunks = MAX_HEAD as usize / 4;
+        let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}0\r\n\r\n", "1\r\nA\r\n".repeat(chunks));
+        assert_eq!(parse(&raw).unwrap().body.len(), chunks);
+    }
+
+    #[test]
+    fn truncated_requests_are_io_errors() {
+        assert!(matches!(parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nab"), Err(HttpError::Io(_))));
+        assert!(matches!(parse(""), Err(HttpError::Io(_))));
+    }
+
+    #[test]
+    fn can_build_error_responses() {
+        let allow = vec![("Allow", "GET, POST".to_owned())];
+        assert_eq!(
+            String::from_utf8(response(405, &allow)).unwrap(),
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, POST\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
@@ -15,6 +15,17 @@
 //! throwing away all the luxuries of the operating system.
 
 
+pub mod activation;
 pub mod attendant;
+pub mod balance;
 pub mod config;
+pub mod control;
 pub mod counter;
+pub mod health;
+pub mod http;
+pub mod metrics;
+pub mod peer;
+pub mod privilege;
+pub mod relay;
+pub mod supervisor;
+pub mod tls;
@@ -7,23 +7,27 @@
  */
 //! Portunus Daemon
 //!
+//! Read the config file, bind every forwarding statement, and then answer the control door until
+//! somebody tells us to stop. Send SIGHUP to re-read the config file, and SIGTERM (or `portunus
+//! stop`) to shut down gracefully. With `set user`, we stop being root as soon as everything is
+//! bound; see [`portunusd::privilege`].
 
 // Types
-use std::any;
+use portunusd::activation::{self, ActivationError};
+use portunusd::config::{Config, ParseError};
+use portunusd::control::{self, Controller, Daemon};
+use portunusd::privilege::{PrivilegeError, Privileges};
+use portunusd::supervisor::{Supervisor, SupervisorError};
+use std::fs;
 use std::io;
-use std::sync::mpsc;
-use std::net;
 use std::path;
-use std::os::fd;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 // Macros
-use doors::derive_server_procedure;
 use errors::define_error_enum;
 
 // Traits
 use clap::Parser;
-use doors::ServerProcedure;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -31,35 +35,157 @@ struct Cli {
     /// Override custom door file
     #[arg(short, long, value_name = "FILE")]
     door: Option<path::PathBuf>,
+
+    /// Override config file
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<path::PathBuf>,
+
+    /// Use an already-bound socket, rather than binding one (may be repeated)
+    #[arg(long, value_name = "NAME=FD")]
+    inherit_fd: Vec<String>,
 }
 
 define_error_enum!(
     pub enum MainError {
         Io(io::Error),
         Door(doors::Error),
-        Send(mpsc::SendError<net::TcpStream>),
-        Join(Box<dyn any::Any + Send>)
+        Parse(ParseError),
+        Supervisor(SupervisorError),
+        Activation(ActivationError),
+        Privilege(PrivilegeError)
     }
 );
 
-fn hello(_descriptors: &[fd::RawFd], request: &[u8]) -> (Vec<fd::RawFd>, Vec<u8>) {
-    static COUNTER: AtomicUsize = AtomicUsize::new(65);
-    if request.len() == 1 {
-        if request[0] == 69 {
-            std::process::exit(0);
+/// Read and parse the config file.
+fn read_config(config_path: &path::Path) -> Result<Config, MainError> {
+    let config: Config = fs::read_to_string(config_path)?.parse()
+        .map_err(|e: ParseError| e.in_file(config_path))?;
+    Ok(config)
+}
+
+/// Take over any sockets passed by systemd, or named with `--inherit-fd`.
+fn inherit(flags: &[String]) -> Result<Vec<activation::Inherited>, MainError> {
+    let mut inherited = activation::from_env()?;
+    for flag in flags {
+        inherited.push(activation::from_flag(flag)?);
+    }
+    Ok(inherited)
+}
+
+/// Explain why a config could not be loaded.
+fn complain(e: MainError) {
+    match e {
+        MainError::Parse(e) => eprintln!("{}", e),
+        MainError::Supervisor(SupervisorError::Invalid(problems)) => {
+            for problem in problems {
+                eprintln!("{}", problem);
+            }
+        },
+        e => eprintln!("{:?}", e)
+    }
+}
+
+/// Block the signals we care about, so that they are only delivered through `sigwait`.
+///
+/// Threads inherit the signal mask of the thread which spawned them, so this must happen before
+/// any other threads are started.
+fn block_signals() -> io::Result<libc::sigset_t> {
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGHUP);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        match libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) {
+            0 => Ok(signals),
+            errno => Err(io::Error::from_raw_os_error(errno))
         }
     }
+}
 
-    (vec![], vec![0xF0, 0x9F, 0xA6, 0x80, 32, COUNTER.fetch_add(1, Ordering::Relaxed).try_into().unwrap()])
+/// Wait for signals, and act on them. Returns once it is time to shut down.
+fn handle_signals(signals: libc::sigset_t, daemon: &Daemon, config_path: &path::Path) {
+    loop {
+        let mut signal: libc::c_int = 0;
+        if unsafe{ libc::sigwait(&signals, &mut signal) } != 0 {
+            continue;
+        }
+        match signal {
+            libc::SIGHUP => {
+                match daemon.reload() {
+                    Ok(changes) => eprintln!("Reloaded {}: {}", config_path.display(), changes),
+                    Err(e) => eprintln!("Could not reload {}: {}", config_path.display(), e)
+                }
+            },
+            libc::SIGTERM => return,
+            _ => {}
+        }
+    }
 }
-derive_server_procedure!(hello as Hello);
 
 fn main() -> Result<(),MainError> {
     let cli = Cli::parse();
     let door_path = cli.door.unwrap_or(path::Path::new("/var/run/portunusd.door").to_path_buf());
+    let config_path = cli.config.unwrap_or(path::Path::new("/opt/local/etc/portunusd.conf").to_path_buf());
+
+    // The daemon works from /, so a relative config path would stop working for reloads
+    let config_path = fs::canonicalize(&config_path).unwrap_or(config_path);
     println!("PortunusD is booting up!");
-    let door_path_str = door_path.to_str().ok_or(io::Error::new(io::ErrorKind::Other, "invalid door path"))?;
-    unsafe{ libc::daemon(0,0) };
-    let hello_server = Hello::install(door_path_str)?;
-    hello_server.park(); // No return from here
+    let door_path_str = door_path.to_str().ok_or(io::Error::other("invalid door path"))?;
+
+    // Bind everything while we can still complain to the terminal
+    let mut supervisor = Supervisor::new();
+    let prepared = inherit(&cli.inherit_fd).and_then(|inherited| {
+        supervisor.inherit(inherited);
+        read_config(&config_path)
+    }).and_then(|config| {
+        let privileges = Privileges::from_config(&config)?;
+        Ok((supervisor.plan(&config)?, privileges))
+    });
+    let (plan, privileges) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            complain(e);
+            std::process::exit(1);
+        }
+    };
+
+    // Threads do not survive the fork, so only start them once we have daemonized. Standard error
+    // stays open, so later complaints reach whatever log the service manager keeps for us.
+    unsafe{ libc::daemon(0,1) };
+    let signals = block_signals()?;
+    supervisor.commit(plan);
+
+    // Asking the control door to stop is just another way of sending SIGTERM
+    let daemon = Arc::new(Daemon::new(supervisor, config_path.clone(), || unsafe{ libc::kill(libc::getpid(), libc::SIGTERM); }));
+    // Once we are no longer root, we cannot remove our own door on the way out, so the last one
+    // may still be there
+    doors::Server::remove_stale(door_path_str)?;
+    let control_server = {
+        let daemon = Arc::clone(&daemon);
+        doors::Server::from_fn(door_path_str, move |request: doors::Request| {
+            doors::Response::new(control::answer(&*daemon, request.data))
+        })?
+    };
+
+    // Every socket is bound and every door is open, so root has nothing left to offer
+    if let Some(privileges) = privileges {
+        if let Err(e) = privileges.relinquish() {
+            eprintln!("Could not give up root: {:?}", e);
+            std::process::exit(1);
+        }
+        if let Some(directory) = privileges.chroot {
+            daemon.confine(directory);
+        }
+    }
+
+    // The door answers calls on threads of its own, so the main thread is free to wait
+    handle_signals(signals, &daemon, &config_path);
+
+    // Stop accepting, let in-flight connections finish, and only then hang up the control door
+    let abandoned = daemon.drain();
+    if abandoned > 0 {
+        eprintln!("Gave up waiting on {} relays", abandoned);
+    }
+    drop(control_server);
+    Ok(())
 }
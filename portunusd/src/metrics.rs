@@ -0,0 +1,674 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Runtime Metrics
+//!
+//! Every listener and every door has a [`Meter`], which counts what has passed through it. A
+//! listener's meter counts the connections (or datagrams) it accepts, the door calls placed on
+//! their behalf, and the bytes exchanged with clients, where PortunusD can see them. (Plain `tcp`
+//! connections are handed straight to the door, so their bytes are invisible to us.) A door's
+//! meter counts the calls placed to it from every listener, and the bytes of their requests and
+//! responses.
+//!
+//! Door calls are placed through a [`MeteredDoor`], which keeps both meters up to date, abandons
+//! calls that take longer than the statement's timeout, and tells the door's circuit breaker how
+//! each call went. Door meters belong to a [`health::Door`], so they are shared by every relay
+//! which forwards to the same path, and last for as long as any relay is using them; see
+//! [`doors`]. Listener meters are shared by a relay and whichever relay replaces it; see
+//! [`listener`] and [`listeners`].
+//!
+//! If the config says `set metrics_listen <address>`, an [`Exporter`] serves every meter in
+//! OpenMetrics text format at `/metrics`, for Prometheus and friends to scrape.
+
+// Types
+use crate::config::Protocol;
+use crate::health::{self, CircuitOpen, Health};
+use crate::http;
+use crate::relay;
+use doors::Descriptor;
+use std::collections::BTreeMap;
+use std::io;
+use std::net;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Macros
+use errors::define_error_enum;
+
+// Traits
+use std::fmt::Write as _;
+use std::io::Write;
+
+
+/// The upper bounds of the latency histogram's buckets, in microseconds. Anything slower than the
+/// last bound lands in one final, unbounded bucket.
+pub const LATENCY_BOUNDS: [u64; 14] = [
+    100, 250, 500,
+    1_000, 2_500, 5_000,
+    10_000, 25_000, 50_000,
+    100_000, 250_000, 500_000,
+    1_000_000, 5_000_000
+];
+
+
+/// Door call latencies, sorted into [`LATENCY_BOUNDS`].
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BOUNDS.len() + 1],
+    /// In microseconds
+    sum: AtomicU64
+}
+
+impl Histogram {
+    fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = LATENCY_BOUNDS.iter().position(|bound| micros <= *bound).unwrap_or(LATENCY_BOUNDS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Latency {
+        Latency{
+            buckets: self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+            sum: Duration::from_micros(self.sum.load(Ordering::Relaxed))
+        }
+    }
+}
+
+
+/// A copy of a latency histogram.
+#[derive(Debug,PartialEq,Eq,Clone,Default)]
+pub struct Latency {
+    /// How many calls finished within each of the [`LATENCY_BOUNDS`], but not within the one
+    /// before. The last bucket counts everything slower.
+    pub buckets: Vec<u64>,
+    /// The total time spent in every call
+    pub sum: Duration
+}
+
+impl Latency {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// An upper bound on the latency of the fastest `quantile` (between 0 and 1) of calls, or
+    /// `None` if there have been no calls, or if the answer is beyond the last bound.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let wanted = ((count as f64) * quantile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BOUNDS) {
+            seen += bucket;
+            if seen >= wanted {
+                return Some(Duration::from_micros(bound));
+            }
+        }
+        None
+    }
+}
+
+
+/// Counters for one listener or one door.
+#[derive(Default)]
+pub struct Meter {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    queued: AtomicU64,
+    rejected: AtomicU64,
+    abandoned: AtomicU64,
+    latency: Histogram,
+    errors: Mutex<BTreeMap<i32, u64>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64
+}
+
+impl Meter {
+    /// Count a new connection (or datagram, or door call).
+    pub fn accept(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection (or datagram) handed to an attendant. Call this before handing it
+    /// over, and [`Meter::dequeue`] once the attendant has picked it up.
+    pub fn enqueue(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dequeue(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection (or datagram) turned away because the attendant's queue was full.
+    pub fn reject(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count bytes received from a client (or sent to a door).
+    pub fn received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count bytes sent to a client (or returned by a door).
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn begin(&self) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn end(&self, latency: Duration, error: Option<i32>) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        self.latency.record(latency);
+        if let Some(errno) = error {
+            self.fail(errno);
+        }
+    }
+
+    /// Count a failed door call by errno.
+    fn fail(&self, errno: i32) {
+        let mut errors = self.errors.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *errors.entry(errno).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let errors = self.errors.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        Snapshot{
+            accepted: self.accepted.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            abandoned: self.abandoned.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+            errors: errors.iter().map(|(errno, count)| (*errno, *count)).collect(),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed)
+        }
+    }
+}
+
+
+/// A copy of a [`Meter`]'s counters.
+#[derive(Debug,PartialEq,Eq,Clone,Default)]
+pub struct Snapshot {
+    /// Connections (or datagrams) accepted by a listener, or calls placed to a door
+    pub accepted: u64,
+    /// Door calls which have not returned yet
+    pub active: u64,
+    /// Connections (or datagrams) waiting for a listener's attendant. Always 0 for doors.
+    pub queued: u64,
+    /// Connections (or datagrams) turned away by a listener's full queue. Always 0 for doors.
+    pub rejected: u64,
+    /// Door calls which took longer than their timeout, and were given up on
+    pub abandoned: u64,
+    pub latency: Latency,
+    /// Failed door calls, by errno, in ascending order of errno
+    pub errors: Vec<(i32, u64)>,
+    pub bytes_in: u64,
+    pub bytes_out: u64
+}
+
+
+define_error_enum!(
+    pub enum CallError {
+        Door(doors::Error),
+        CircuitOpen(CircuitOpen)
+    }
+);
+
+impl CallError {
+    /// Whether the call was abandoned for taking longer than its timeout.
+    pub fn timed_out(&self) -> bool {
+        matches!(self, CallError::Door(e) if e.timed_out())
+    }
+}
+
+
+/// A door client which keeps a [`Meter`] and a circuit breaker up to date.
+#[derive(Clone)]
+pub struct MeteredDoor {
+    door: Arc<health::Door>,
+    timeout: Option<Duration>
+}
+
+impl MeteredDoor {
+    pub fn new(door: Arc<health::Door>) -> Self {
+        Self{ door, timeout: None }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.door.path()
+    }
+
+    pub fn health(&self) -> Health {
+        self.door.health()
+    }
+
+    /// Whether the door's circuit is open, but ready to let a call through to try its luck.
+    pub fn retry_due(&self) -> bool {
+        self.door.retry_due()
+    }
+
+    /// How many calls to the door are underway, from every listener.
+    pub fn inflight(&self) -> u64 {
+        self.door.meter().active.load(Ordering::Relaxed)
+    }
+
+    /// Abandon calls which take longer than `timeout`, if there is one.
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        Self{ timeout, ..self }
+    }
+
+    /// Call the door on behalf of `listener`, and record the call in both meters.
+    ///
+    /// If the door's circuit is open, the call fails straight away with [`CircuitOpen`]. That
+    /// counts as neither a call nor an error, since the door never saw it; the door's health
+    /// says as much.
+    pub fn call(&self, listener: &Meter, descriptors: Vec<Descriptor<'_>>, request: &[u8]) -> Result<(Vec<OwnedFd>, Vec<u8>), CallError> {
+        let meter = self.door.meter();
+        let client = self.door.client()?;
+
+        meter.accept();
+        meter.received(request.len());
+        meter.begin();
+        listener.begin();
+
+        let started = Instant::now();
+        let result = match self.timeout {
+            Some(timeout) => client.call_with_timeout(descriptors, request, timeout),
+            None => client.call(descriptors, request)
+        };
+        let latency = started.elapsed();
+        self.door.record(&result);
+
+        let error = result.as_ref().err().map(errno);
+        meter.end(latency, error);
+        listener.end(latency, error);
+        match &result {
+            Err(e) if e.timed_out() => {
+                meter.abandoned.fetch_add(1, Ordering::Relaxed);
+                listener.abandoned.fetch_add(1, Ordering::Relaxed);
+            },
+            Ok((_, response)) => meter.sent(response.len()),
+            Err(_) => {}
+        }
+        Ok(result?)
+    }
+}
+
+/// The errno behind a door error, or 0 if there isn't one.
+fn errno(e: &doors::Error) -> i32 {
+    match e {
+        doors::Error::InvalidPath(_) => 0,
+        doors::Error::OpenDoor(e) => e.raw_os_error().unwrap_or(0),
+        doors::Error::InstallJamb(errno)
+        | doors::Error::AttachDoor(errno)
+        | doors::Error::DoorCall(errno)
+        | doors::Error::CreateDoor(errno)
+        | doors::Error::Caller(errno) => *errno
+    }
+}
+
+
+/// The health and a snapshot of every door which is still in use, in order of path.
+pub fn doors() -> Vec<(PathBuf, Health, Snapshot)> {
+    health::doors().into_iter()
+        .map(|door| (door.path().to_path_buf(), door.health(), door.meter().snapshot()))
+        .collect()
+}
+
+
+/// Every listener meter which is still in use, in the order they were created.
+static LISTENERS: Mutex<Vec<(Protocol, net::SocketAddr, Weak<Meter>)>> = Mutex::new(vec![]);
+
+/// A new meter for a listener bound to `address`.
+pub fn listener(protocol: Protocol, address: net::SocketAddr) -> Arc<Meter> {
+    let mut meters = LISTENERS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    meters.retain(|(_, _, meter)| meter.strong_count() > 0);
+    let meter = Arc::new(Meter::default());
+    meters.push((protocol, address, Arc::downgrade(&meter)));
+    meter
+}
+
+/// Snapshots of every listener meter which is still in use.
+pub fn listeners() -> Vec<(Protocol, net::SocketAddr, Snapshot)> {
+    let meters = LISTENERS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    meters.iter()
+        .filter_map(|(protocol, address, meter)| Some((protocol.clone(), *address, meter.upgrade()?.snapshot())))
+        .collect()
+}
+
+
+/// Render snapshots in the OpenMetrics text format.
+///
+/// See <https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md>
+pub fn openmetrics(listeners: &[(Protocol, net::SocketAddr, Snapshot)], doors: &[(PathBuf, Health, Snapshot)]) -> String {
+    let listeners: Vec<Series> = listeners.iter()
+        .map(|(protocol, address, snapshot)| {
+            (format!("protocol=\"{}\",address=\"{}\"", protocol, address), snapshot)
+        })
+        .collect();
+    let health: Vec<(String, Health)> = doors.iter()
+        .map(|(path, health, _)| (format!("door=\"{}\"", escape(&path.to_string_lossy())), *health))
+        .collect();
+    let doors: Vec<Series> = doors.iter()
+        .map(|(path, _, snapshot)| (format!("door=\"{}\"", escape(&path.to_string_lossy())), snapshot))
+        .collect();
+
+    let mut text = String::new();
+    let out = &mut text;
+    family(out, "listener_accepted", "counter", "Connections or datagrams accepted", &listeners, |s| s.accepted);
+    family(out, "listener_active_calls", "gauge", "Door calls in progress for this listener", &listeners, |s| s.active);
+    family(out, "attendant_queued", "gauge", "Connections or datagrams waiting for an attendant", &listeners, |s| s.queued);
+    family(out, "listener_rejected", "counter", "Connections or datagrams turned away by a full queue", &listeners, |s| s.rejected);
+    histogram(out, "listener_call_latency_seconds", "Door call latency for this listener", &listeners);
+    errors(out, "listener_call_errors", "Failed door calls for this listener, by errno", &listeners);
+    family(out, "listener_abandoned_calls", "counter", "Door calls for this listener which timed out", &listeners, |s| s.abandoned);
+    family(out, "listener_received_bytes", "counter", "Bytes received from clients, where visible", &listeners, |s| s.bytes_in);
+    family(out, "listener_sent_bytes", "counter", "Bytes sent to clients, where visible", &listeners, |s| s.bytes_out);
+    family(out, "door_calls", "counter", "Door calls placed", &doors, |s| s.accepted);
+    family(out, "door_active_calls", "gauge", "Door calls in progress", &doors, |s| s.active);
+    histogram(out, "door_call_latency_seconds", "Door call latency", &doors);
+    errors(out, "door_call_errors", "Failed door calls, by errno", &doors);
+    family(out, "door_abandoned_calls", "counter", "Door calls which timed out", &doors, |s| s.abandoned);
+    family(out, "door_request_bytes", "counter", "Bytes sent to the door", &doors, |s| s.bytes_in);
+    family(out, "door_response_bytes", "counter", "Bytes returned by the door", &doors, |s| s.bytes_out);
+    gauge(out, "door_healthy", "Whether the door's last call or probe succeeded", &health, |h| (h == Health::Healthy) as u64);
+    gauge(out, "door_consecutive_failures", "Door calls or probes which have failed in a row", &health, |h| h.failures() as u64);
+    gauge(out, "door_circuit_open", "Whether calls to the door are failing fast", &health, |h| matches!(h, Health::Open(_)) as u64);
+    text.push_str("# EOF\n");
+    text
+}
+
+/// The labels which identify one listener or door, and its counters.
+type Series<'snapshot> = (String, &'snapshot Snapshot);
+
+fn metadata(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# TYPE portunusd_{} {}", name, kind);
+    let _ = writeln!(text, "# HELP portunusd_{} {}.", name, help);
+}
+
+/// A counter or gauge, with one sample per series.
+fn family(text: &mut String, name: &str, kind: &str, help: &str, series: &[Series], value: fn(&Snapshot) -> u64) {
+    metadata(text, name, kind, help);
+    let suffix = if kind == "counter" { "_total" } else { "" };
+    for (labels, snapshot) in series {
+        let _ = writeln!(text, "portunusd_{}{}{{{}}} {}", name, suffix, labels, value(snapshot));
+    }
+}
+
+/// A gauge describing each door's health.
+fn gauge(text: &mut String, name: &str, help: &str, doors: &[(String, Health)], value: fn(Health) -> u64) {
+    metadata(text, name, "gauge", help);
+    for (labels, health) in doors {
+        let _ = writeln!(text, "portunusd_{}{{{}}} {}", name, labels, value(*health));
+    }
+}
+
+fn histogram(text: &mut String, name: &str, help: &str, series: &[Series]) {
+    metadata(text, name, "histogram", help);
+    for (labels, snapshot) in series {
+        let latency = &snapshot.latency;
+        let mut cumulative = 0;
+        for (count, bound) in latency.buckets.iter().zip(LATENCY_BOUNDS) {
+            cumulative += count;
+            let le = seconds(Duration::from_micros(bound));
+            let _ = writeln!(text, "portunusd_{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, cumulative);
+        }
+        let _ = writeln!(text, "portunusd_{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, latency.count());
+        let _ = writeln!(text, "portunusd_{}_count{{{}}} {}", name, labels, latency.count());
+        let _ = writeln!(text, "portunusd_{}_sum{{{}}} {}", name, labels, seconds(latency.sum));
+    }
+}
+
+/// A counter with one sample per errno per series.
+fn errors(text: &mut String, name: &str, help: &str, series: &[Series]) {
+    metadata(text, name, "counter", help);
+    for (labels, snapshot) in series {
+        for (errno, count) in &snapshot.errors {
+            let _ = writeln!(text, "portunusd_{}_total{{{},errno=\"{}\"}} {}", name, labels, errno, count);
+        }
+    }
+}
+
+/// OpenMetrics wants floats to look like floats, even when they are whole.
+fn seconds(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+    match seconds.fract() == 0.0 {
+        true => format!("{:.1}", seconds),
+        false => format!("{}", seconds)
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+/// How long a scraper may take to send its request.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A bound, but not yet serving, OpenMetrics endpoint.
+///
+/// Like a [`relay::Relay`], the socket is bound first so that problems can be reported before any
+/// threads start, and then [`Exporter::start`] starts serving.
+pub struct Exporter {
+    /// The configured address, which differs from the bound one if it asked for port 0
+    pub address: net::SocketAddr,
+    listener: net::TcpListener
+}
+
+impl Exporter {
+    pub fn bind(address: net::SocketAddr) -> io::Result<Self> {
+        Ok(Self{ address, listener: net::TcpListener::bind(address)? })
+    }
+
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Answer scrapes in a background thread, one at a time, until the returned handle is
+    /// dropped.
+    pub fn start(self) -> io::Result<RunningExporter> {
+        let (wake, alarm) = relay::pipe()?;
+        let local = self.listener.local_addr()?;
+        self.listener.set_nonblocking(true)?;
+        let listener = self.listener;
+        let join_handle = thread::spawn(move|| {
+            while relay::ready(&listener, &wake) {
+                if let Ok((stream, _)) = listener.accept() {
+                    if let Err(e) = scrape(stream) {
+                        eprintln!("{}: could not serve metrics: {}", local, e);
+                    }
+                }
+            }
+        });
+        Ok(RunningExporter{ address: self.address, local, alarm, join_handle })
+    }
+}
+
+/// A running OpenMetrics endpoint. Dropping it stops the endpoint and closes its socket.
+pub struct RunningExporter {
+    pub address: net::SocketAddr,
+    local: net::SocketAddr,
+    /// Closing this wakes the exporter thread up and tells it to stop
+    alarm: OwnedFd,
+    join_handle: thread::JoinHandle<()>
+}
+
+impl RunningExporter {
+    /// The address the endpoint is actually listening on.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local
+    }
+
+    /// Stop serving, and wait for the socket to close.
+    pub fn stop(self) {
+        let Self{ alarm, join_handle, .. } = self;
+        drop(alarm);
+        let _ = join_handle.join();
+    }
+}
+
+/// Answer one scrape.
+fn scrape(mut stream: net::TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    let request = match http::Request::read_from(&mut io::BufReader::new(&mut stream)) {
+        Ok(request) => request,
+        Err(e) => match e.status() {
+            Some(status) => return stream.write_all(&http::response(status, &[])),
+            None => return Ok(())
+        }
+    };
+    if request.path() != "/metrics" {
+        return stream.write_all(&http::response(404, &[]));
+    }
+    let body = openmetrics(&listeners(), &doors());
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latencies_land_in_the_right_buckets() {
+        let histogram = Histogram::default();
+        histogram.record(Duration::from_micros(50));
+        histogram.record(Duration::from_micros(100));
+        histogram.record(Duration::from_micros(101));
+        histogram.record(Duration::from_millis(3));
+        histogram.record(Duration::from_secs(60));
+
+        let latency = histogram.snapshot();
+        assert_eq!(latency.count(), 5);
+        assert_eq!(&latency.buckets[..6], &[2, 1, 0, 0, 0, 1]);
+        assert_eq!(latency.buckets[LATENCY_BOUNDS.len()], 1);
+        assert_eq!(latency.sum, Duration::from_micros(50 + 100 + 101 + 3_000 + 60_000_000));
+
+        assert_eq!(latency.quantile(0.4), Some(Duration::from_micros(100)));
+        assert_eq!(latency.quantile(0.5), Some(Duration::from_micros(250)));
+        assert_eq!(latency.quantile(0.8), Some(Duration::from_micros(5_000)));
+        assert_eq!(latency.quantile(1.0), None);
+        assert_eq!(Latency::default().quantile(0.5), None);
+    }
+
+    #[test]
+    fn snapshots_render_as_openmetrics() {
+        let mut latency = Latency{ buckets: vec![0; LATENCY_BOUNDS.len() + 1], sum: Duration::from_micros(5_000_300) };
+        latency.buckets[0] = 1;
+        latency.buckets[2] = 2;
+        latency.buckets[LATENCY_BOUNDS.len()] = 1;
+        let listener = Snapshot{ accepted: 4, queued: 1, rejected: 3, latency, errors: vec![(libc::EBADF, 1)], ..Default::default() };
+        let door = Snapshot{ accepted: 4, active: 2, abandoned: 1, bytes_in: 10, bytes_out: 20, ..Default::default() };
+        let text = openmetrics(
+            &[(Protocol::TCP, "127.0.0.1:7".parse().unwrap(), listener)],
+            &[("/var/run/\"echo\".door".into(), Health::Open(3), door)]
+        );
+
+        let labels = r#"protocol="tcp",address="127.0.0.1:7""#;
+        for line in [
+            "# TYPE portunusd_listener_accepted counter".to_owned(),
+            format!("portunusd_listener_accepted_total{{{}}} 4", labels),
+            format!("portunusd_attendant_queued{{{}}} 1", labels),
+            format!("portunusd_listener_rejected_total{{{}}} 3", labels),
+            "# TYPE portunusd_listener_call_latency_seconds histogram".to_owned(),
+            format!(r#"portunusd_listener_call_latency_seconds_bucket{{{},le="0.0001"}} 1"#, labels),
+            format!(r#"portunusd_listener_call_latency_seconds_bucket{{{},le="0.00025"}} 1"#, labels),
+            format!(r#"portunusd_listener_call_latency_seconds_bucket{{{},le="0.0005"}} 3"#, labels),
+            format!(r#"portunusd_listener_call_latency_seconds_bucket{{{},le="5.0"}} 3"#, labels),
+            format!(r#"portunusd_listener_call_latency_seconds_bucket{{{},le="+Inf"}} 4"#, labels),
+            format!("portunusd_listener_call_latency_seconds_count{{{}}} 4", labels),
+            format!("portunusd_listener_call_latency_seconds_sum{{{}}} 5.0003", labels),
+            format!(r#"portunusd_listener_call_errors_total{{{},errno="{}"}} 1"#, labels, libc::EBADF),
+            r#"portunusd_door_active_calls{door="/var/run/\"echo\".door"} 2"#.to_owned(),
+            r#"portunusd_door_abandoned_calls_total{door="/var/run/\"echo\".door"} 1"#.to_owned(),
+            r#"portunusd_door_response_bytes_total{door="/var/run/\"echo\".door"} 20"#.to_owned(),
+            r#"portunusd_door_healthy{door="/var/run/\"echo\".door"} 0"#.to_owned(),
+            r#"portunusd_door_consecutive_failures{door="/var/run/\"echo\".door"} 3"#.to_owned(),
+        ] {
+            assert!(text.lines().any(|actual| actual == line), "missing {}", line);
+        }
+        assert!(text.ends_with("portunusd_door_circuit_open{door=\"/var/run/\\\"echo\\\".door\"} 1\n# EOF\n"));
+    }
+
+    fn echo(_descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        if request == b"slowly" {
+            thread::sleep(Duration::from_millis(500));
+        }
+        (vec![], request.to_vec())
+    }
+    doors::derive_server_procedure!(echo as Echo);
+
+    #[test]
+    fn door_calls_are_metered() {
+        let (path, server) = crate::relay::tests::install::<Echo>("portunusd_metrics_test.5a0e71");
+
+        let listener = Meter::default();
+        let door = MeteredDoor::new(health::open(&path).unwrap());
+        door.call(&listener, vec![], b"hello").unwrap();
+        door.call(&listener, vec![], b"hi").unwrap();
+
+        let (metered, health, snapshot) = doors().into_iter().find(|(metered, _, _)| metered == &path).unwrap();
+        assert_eq!((metered, health), (path.clone(), Health::Healthy));
+        assert_eq!(snapshot.accepted, 2);
+        assert_eq!(snapshot.active, 0);
+        assert_eq!(snapshot.latency.count(), 2);
+        assert_eq!((snapshot.bytes_in, snapshot.bytes_out), (7, 7));
+        assert!(snapshot.errors.is_empty());
+
+        // The listener sees the calls, but it counts its own connections and bytes
+        let snapshot = listener.snapshot();
+        assert_eq!(snapshot.latency.count(), 2);
+        assert_eq!((snapshot.accepted, snapshot.bytes_in), (0, 0));
+
+        // Calls which take too long are abandoned, and count against the door until one succeeds
+        let impatient = door.clone().with_timeout(Some(Duration::from_millis(50)));
+        assert!(impatient.call(&listener, vec![], b"slowly").unwrap_err().timed_out());
+        let (_, health, snapshot) = doors().into_iter().find(|(metered, _, _)| metered == &path).unwrap();
+        assert_eq!((snapshot.abandoned, health), (1, Health::Failing(1)));
+        assert_eq!(listener.snapshot().abandoned, 1);
+        impatient.call(&listener, vec![], b"quickly").unwrap();
+        let (_, health, snapshot) = doors().into_iter().find(|(metered, _, _)| metered == &path).unwrap();
+        assert_eq!((snapshot.abandoned, health), (1, Health::Healthy));
+        drop(impatient);
+
+        // Calls to a door which has gone away are counted by errno
+        drop(server);
+        assert!(door.call(&listener, vec![], b"anybody?").is_err());
+        assert_eq!(listener.snapshot().errors.len(), 2);
+        assert_eq!(listener.snapshot().active, 0);
+
+        // Until the circuit opens, and calls fail fast without reaching the door
+        for _ in 1..health::FAILURE_THRESHOLD {
+            assert!(door.call(&listener, vec![], b"anybody?").is_err());
+        }
+        let accepted = listener.snapshot().latency.count();
+        let refused = door.call(&listener, vec![], b"anybody?").unwrap_err();
+        assert!(matches!(refused, CallError::CircuitOpen(CircuitOpen)));
+        assert_eq!(listener.snapshot().latency.count(), accepted);
+        assert_eq!(listener.snapshot().errors.len(), 2);
+
+        // Once nothing uses the door, its meter goes away
+        drop(door);
+        assert!(doors().iter().all(|(metered, _, _)| metered != &path));
+    }
+}
@@ -0,0 +1,352 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Peer Forwarding
+//!
+//! When one host runs out of room, it can forward traffic to another PortunusD host, which
+//! delivers it to a door of its own. The receiving host listens with a `peer` statement, and the
+//! sending host forwards to it with a `peer` target:
+//!
+//! ```portunusd
+//! # On 10.0.0.2
+//! forward peer 0.0.0.0:9443 to /var/run/echo.door
+//!
+//! # On 10.0.0.1
+//! forward tcp 0.0.0.0:7 to peer 10.0.0.2:9443
+//! ```
+//!
+//! Both hosts identify themselves with their `certificate` and `key`, and each only talks to hosts
+//! whose certificate's SHA-256 [`Fingerprint`] is listed in its `peers` parameter. Pinning the
+//! fingerprint takes the place of a certificate authority, so self-signed certificates are fine.
+//!
+//! After the (mutual) TLS handshake, the sending host writes one frame: a `u32` length, followed by
+//! the request it would have given a local door, envelope and all. For `tcp` and `tls` requests,
+//! the connection then carries the client's stream in both directions, and the receiving door
+//! gets a socket just as it would for a local client. Otherwise, the receiving host answers with
+//! one frame holding the door's response, and hangs up.
+
+// Types
+use crate::config::Fingerprint;
+use crate::http;
+use crate::tls::{self, TlsError};
+use doors::envelope::{self, Envelope, EnvelopeError};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, DistinguishedName};
+use rustls::{ServerConfig, SignatureScheme, StreamOwned};
+use std::io;
+use std::net;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Macros
+use errors::define_error_enum;
+
+// Traits
+use rustls::pki_types::pem::PemObject;
+use std::io::{Read, Write};
+
+
+define_error_enum!(
+    pub enum PeerError {
+        Io(io::Error),
+        Tls(TlsError),
+        Rustls(rustls::Error),
+        Envelope(EnvelopeError)
+    }
+);
+
+
+/// A connection to a peer, after the handshake.
+pub type PeerStream = StreamOwned<ClientConnection, net::TcpStream>;
+
+
+/// The largest request a peer will accept: the biggest HTTP request we read, behind the biggest
+/// envelope there can be.
+pub const MAX_REQUEST: usize = (http::MAX_HEAD + http::MAX_BODY) as usize + envelope::MAX_LENGTH;
+
+/// The largest response a peer will accept, which matches the largest door response.
+pub const MAX_RESPONSE: usize = doors::MAX_PAYLOAD as usize;
+
+/// How long to wait for a peer to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// The fingerprint of a DER-encoded certificate.
+pub fn fingerprint(certificate: &CertificateDer<'_>) -> Fingerprint {
+    let digest = ring::digest::digest(&ring::digest::SHA256, certificate.as_ref());
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(digest.as_ref());
+    Fingerprint(fingerprint)
+}
+
+
+/// Trust exactly the certificates with these fingerprints, in either direction.
+#[derive(Debug)]
+struct Pinned {
+    peers: Vec<Fingerprint>,
+    algorithms: WebPkiSupportedAlgorithms
+}
+
+impl Pinned {
+    fn check(&self, certificate: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        match self.peers.contains(&fingerprint(certificate)) {
+            true => Ok(()),
+            false => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for Pinned {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+
+/// How to accept connections from peers, and how to make connections to them.
+#[derive(Clone)]
+pub struct PeerSettings {
+    pub server: Arc<ServerConfig>,
+    pub client: Arc<ClientConfig>
+}
+
+/// Identify ourselves with the PEM files `certificate` and `key`, and trust only `peers`.
+pub fn settings(certificate: &Path, key: &Path, peers: Vec<Fingerprint>) -> Result<PeerSettings, TlsError> {
+    let chain = CertificateDer::pem_file_iter(certificate)?
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let pinned = Arc::new(Pinned{ peers, algorithms: provider.signature_verification_algorithms });
+    let server = ServerConfig::builder_with_provider(Arc::clone(&provider) as Arc<CryptoProvider>)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(Arc::clone(&pinned) as Arc<dyn ClientCertVerifier>)
+        .with_single_cert(chain.clone(), key.clone_key())?;
+    let client = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(pinned)
+        .with_client_auth_cert(chain, key)?;
+    Ok(PeerSettings{ server: Arc::new(server), client: Arc::new(client) })
+}
+
+
+/// Another PortunusD host, to which a statement forwards its traffic.
+#[derive(Clone)]
+pub struct Peer {
+    address: net::SocketAddr,
+    config: Arc<ClientConfig>,
+    /// How long to wait for a response, as in the statement's `timeout`
+    timeout: Option<Duration>
+}
+
+impl Peer {
+    pub fn new(address: net::SocketAddr, config: Arc<ClientConfig>, timeout: Option<Duration>) -> Self {
+        Self{ address, config, timeout }
+    }
+
+    pub fn address(&self) -> net::SocketAddr {
+        self.address
+    }
+
+    /// Connect to the peer, and send it `request`, which begins with an envelope.
+    ///
+    /// For `tcp` and `tls` requests, the returned stream should then be spliced to the client.
+    pub fn send(&self, request: &[u8]) -> Result<PeerStream, PeerError> {
+        let mut stream = net::TcpStream::connect_timeout(&self.address, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(tls::HANDSHAKE_TIMEOUT))?;
+        let name = ServerName::IpAddress(self.address.ip().into());
+        let mut connection = ClientConnection::new(Arc::clone(&self.config), name)?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        stream.set_read_timeout(None)?;
+
+        let mut stream = StreamOwned::new(connection, stream);
+        write_frame(&mut stream, request, MAX_REQUEST)?;
+        Ok(stream)
+    }
+
+    /// Send `request`, and wait for the response.
+    pub fn call(&self, request: &[u8]) -> Result<Vec<u8>, PeerError> {
+        let mut stream = self.send(request)?;
+        stream.sock.set_read_timeout(self.timeout)?;
+        Ok(read_frame(&mut stream, MAX_RESPONSE)?)
+    }
+}
+
+
+/// Read a request forwarded by a peer, and decode its envelope.
+pub fn receive(stream: &mut tls::TlsStream) -> Result<(Envelope, Vec<u8>), PeerError> {
+    stream.sock.set_read_timeout(Some(tls::HANDSHAKE_TIMEOUT))?;
+    let request = read_frame(stream, MAX_REQUEST)?;
+    stream.sock.set_read_timeout(None)?;
+    let (envelope, _) = Envelope::decode(&request)?;
+    Ok((envelope, request))
+}
+
+/// Answer a request forwarded by a peer, and hang up.
+pub fn respond(mut stream: tls::TlsStream, response: &[u8]) -> Result<(), PeerError> {
+    write_frame(&mut stream, response, MAX_RESPONSE)?;
+    stream.conn.send_close_notify();
+    stream.conn.complete_io(&mut stream.sock)?;
+    Ok(())
+}
+
+/// Write `frame` behind its length, unless it is bigger than `limit`.
+pub fn write_frame(stream: &mut impl Write, frame: &[u8], limit: usize) -> io::Result<()> {
+    if frame.len() > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame is too large for a peer"));
+    }
+    stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+    stream.write_all(frame)?;
+    stream.flush()
+}
+
+/// Read a frame, unless its length says it is bigger than `limit`.
+pub fn read_frame(stream: &mut impl Read, limit: usize) -> io::Result<Vec<u8>> {
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame from peer is too large"));
+    }
+    let mut frame = vec![0u8; length];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::tests::self_signed;
+    use std::thread;
+
+    fn fingerprint_of(certificate: &Path) -> Fingerprint {
+        fingerprint(&CertificateDer::from_pem_file(certificate).unwrap())
+    }
+
+    #[test]
+    fn fingerprints_round_trip_through_text() {
+        let (certificate, _) = self_signed("portunusd_peer_test.0c4e1a");
+        let expected = fingerprint_of(&certificate);
+        let text = expected.to_string();
+        assert_eq!(text.len(), 32 * 3 - 1);
+        assert_eq!(text.parse::<Fingerprint>().unwrap(), expected);
+        assert_eq!(text.replace(':', "").to_lowercase().parse::<Fingerprint>().unwrap(), expected);
+        assert!("AB:CD".parse::<Fingerprint>().is_err());
+        let signed = format!("+{}", &text[1..]);
+        assert!(signed.parse::<Fingerprint>().is_err());
+    }
+
+    #[test]
+    fn frames_are_held_to_their_limit() {
+        let mut frame = vec![];
+        write_frame(&mut frame, b"hello", 5).unwrap();
+        assert_eq!(read_frame(&mut &frame[..], 5).unwrap(), b"hello");
+        assert_eq!(read_frame(&mut &frame[..], 4).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(write_frame(&mut vec![], b"hello", 4).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(MAX_REQUEST > (http::MAX_HEAD + http::MAX_BODY) as usize);
+    }
+
+    /// Accept one connection with `server`, and echo back one frame.
+    fn echo_one(server: Arc<ServerConfig>) -> (net::SocketAddr, thread::JoinHandle<Result<(), PeerError>>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let join_handle = thread::spawn(move|| {
+            let (stream, _) = listener.accept()?;
+            let mut stream = tls::accept(&server, stream)?;
+            let (_, request) = receive(&mut stream)?;
+            respond(stream, &request)
+        });
+        (address, join_handle)
+    }
+
+    #[test]
+    fn peers_must_know_each_other() {
+        let (alice_certificate, alice_key) = self_signed("portunusd_peer_test.alice");
+        let (bob_certificate, bob_key) = self_signed("portunusd_peer_test.bob");
+        let (eve_certificate, eve_key) = self_signed("portunusd_peer_test.eve");
+        let (alice, bob) = (fingerprint_of(&alice_certificate), fingerprint_of(&bob_certificate));
+
+        let alice_settings = settings(&alice_certificate, &alice_key, vec![bob]).unwrap();
+        let bob_settings = settings(&bob_certificate, &bob_key, vec![alice]).unwrap();
+        let eve_settings = settings(&eve_certificate, &eve_key, vec![alice, bob]).unwrap();
+        let request = Envelope::default().encode(b"hello");
+
+        let (address, bob_server) = echo_one(Arc::clone(&bob_settings.server));
+        let peer = Peer::new(address, Arc::clone(&alice_settings.client), Some(Duration::from_secs(5)));
+        assert_eq!(peer.address(), address);
+        assert_eq!(peer.call(&request).unwrap(), request);
+        bob_server.join().unwrap().unwrap();
+
+        // Bob doesn't know Eve, so he refuses to talk to her
+        let (address, bob_server) = echo_one(Arc::clone(&bob_settings.server));
+        let peer = Peer::new(address, Arc::clone(&eve_settings.client), Some(Duration::from_secs(5)));
+        assert!(peer.call(&request).is_err());
+        assert!(bob_server.join().unwrap().is_err());
+
+        // And Alice won't believe that Eve is Bob
+        let (address, eve_server) = echo_one(Arc::clone(&eve_settings.server));
+        let peer = Peer::new(address, Arc::clone(&alice_settings.client), Some(Duration::from_secs(5)));
+        assert!(peer.call(&request).is_err());
+        assert!(eve_server.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,183 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Privilege Separation
+//!
+//! PortunusD starts as root so that it can bind ports like 80 and 443, but it has no use for root
+//! once every socket is bound and every door is open. With `set user portunus` (and, optionally,
+//! `set group portunus`), it then becomes that user for good:
+//!
+//! ```portunusd
+//! set user portunus
+//! set group portunus
+//! set chroot /var/empty
+//! ```
+//!
+//! With `set chroot`, it also locks itself into an empty directory first, since only root may call
+//! `chroot(2)`. Doors which are already open keep working, but paths outside the new root (the
+//! config file, certificates, and any door which has not been opened yet) can no longer be found,
+//! so a chrooted PortunusD refuses to reload. Without a chroot it can still reload, but as an
+//! ordinary user it cannot bind new ports below 1024.
+//!
+//! Nor can the control door be removed on the way out, since root created it. PortunusD removes
+//! it the next time it starts, once it is sure the old one is no longer answering.
+
+// Types
+use crate::config::Config;
+use connected_fork::SetCredsError;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Macros
+use errors::define_error_enum;
+
+// Traits
+use std::os::unix::ffi::OsStrExt;
+
+
+define_error_enum!(
+    pub enum PrivilegeError {
+        Io(io::Error),
+        Creds(SetCredsError),
+        Unknown(String),
+        NotEmpty(PathBuf)
+    }
+);
+
+
+/// The user and group PortunusD should become, and where it should lock itself in.
+#[derive(Debug,PartialEq)]
+pub struct Privileges {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub chroot: Option<PathBuf>
+}
+
+impl Privileges {
+    /// What the `user`, `group`, and `chroot` parameters ask us to become, if anything.
+    ///
+    /// The group defaults to the user's primary group. The chroot directory must exist and be
+    /// empty, so that nothing useful is within reach once we are inside it.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, PrivilegeError> {
+        let user = match config.parameter("user") {
+            Some(user) => user,
+            None => return Ok(None)
+        };
+        let (uid, primary_gid) = lookup_user(user)?;
+        let gid = match config.parameter("group") {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid
+        };
+        let chroot = config.parameter("chroot").map(PathBuf::from);
+        if let Some(directory) = &chroot {
+            if fs::read_dir(directory)?.next().is_some() {
+                return Err(PrivilegeError::NotEmpty(directory.clone()));
+            }
+        }
+        Ok(Some(Self{ uid, gid, chroot }))
+    }
+
+    /// Lock ourselves into the chroot directory (if any), and become the configured user.
+    ///
+    /// This applies to every thread in the process, and cannot be undone.
+    pub fn relinquish(&self) -> Result<(), PrivilegeError> {
+        if let Some(directory) = &self.chroot {
+            enter(directory)?;
+        }
+        connected_fork::set_creds(self.uid, self.gid)?;
+        Ok(())
+    }
+}
+
+
+/// The uid and primary gid of the user called `name`.
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), PrivilegeError> {
+    let unknown = || PrivilegeError::Unknown(format!("user {}", name));
+    let c_name = CString::new(name).map_err(|_| unknown())?;
+    let mut entry: libc::passwd = unsafe{ std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut found: *mut libc::passwd = std::ptr::null_mut();
+    let result = unsafe{
+        libc::getpwnam_r(c_name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found)
+    };
+    match (result, found.is_null()) {
+        (0, false) => Ok((entry.pw_uid, entry.pw_gid)),
+        (0, true) => Err(unknown()),
+        (errno, _) => Err(io::Error::from_raw_os_error(errno).into())
+    }
+}
+
+/// The gid of the group called `name`.
+fn lookup_group(name: &str) -> Result<libc::gid_t, PrivilegeError> {
+    let unknown = || PrivilegeError::Unknown(format!("group {}", name));
+    let c_name = CString::new(name).map_err(|_| unknown())?;
+    let mut entry: libc::group = unsafe{ std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut found: *mut libc::group = std::ptr::null_mut();
+    let result = unsafe{
+        libc::getgrnam_r(c_name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found)
+    };
+    match (result, found.is_null()) {
+        (0, false) => Ok(entry.gr_gid),
+        (0, true) => Err(unknown()),
+        (errno, _) => Err(io::Error::from_raw_os_error(errno).into())
+    }
+}
+
+/// Make `directory` our root, and move into it.
+fn enter(directory: &Path) -> io::Result<()> {
+    let c_directory = CString::new(directory.as_os_str().as_bytes())?;
+    if unsafe{ libc::chroot(c_directory.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe{ libc::chdir(c"/".as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_to_give_up_without_a_user() {
+        let config: Config = "set group nobody".parse().unwrap();
+        assert_eq!(Privileges::from_config(&config).unwrap(), None);
+    }
+
+    #[test]
+    fn looks_up_users_and_groups() {
+        let config: Config = "set user root".parse().unwrap();
+        let privileges = Privileges::from_config(&config).unwrap().unwrap();
+        assert_eq!((privileges.uid, privileges.gid, privileges.chroot), (0, 0, None));
+
+        let config: Config = "set user portunusd_no_such_user".parse().unwrap();
+        assert!(matches!(Privileges::from_config(&config), Err(PrivilegeError::Unknown(user)) if user == "user portunusd_no_such_user"));
+
+        let config: Config = "set user root\nset group portunusd_no_such_group".parse().unwrap();
+        assert!(matches!(Privileges::from_config(&config), Err(PrivilegeError::Unknown(group)) if group == "group portunusd_no_such_group"));
+    }
+
+    #[test]
+    fn the_chroot_must_be_empty() {
+        let mut directory = std::env::temp_dir();
+        directory.push("portunusd_privilege_test.3a9d71");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir(&directory).unwrap();
+
+        let config: Config = format!("set user root\nset chroot {}", directory.display()).parse().unwrap();
+        let privileges = Privileges::from_config(&config).unwrap().unwrap();
+        assert_eq!(privileges.chroot, Some(directory.clone()));
+
+        fs::write(directory.join("secrets"), "hunter2").unwrap();
+        assert!(matches!(Privileges::from_config(&config), Err(PrivilegeError::NotEmpty(_))));
+    }
+}
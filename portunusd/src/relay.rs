@@ -0,0 +1,933 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Forwarding Engine
+//!
+//! A [`Relay`] is the running form of a [`ForwardingStatement`]: a bound socket, an open door, and
+//! the threads which shuttle connections from one to the other. Relays are built in two steps so
+//! that every socket can be bound and every door opened (and every mistake reported) before
+//! PortunusD detaches from the terminal and starts any threads. Starting a relay yields a
+//! [`Running`] handle, which can stop it, or hand its socket to a replacement.
+
+// Types
+use crate::attendant::{Datagram, DatagramAttendant, Destination, DoorAttendant, HttpAttendant, PeerAttendant, TlsAttendant};
+use crate::balance::Balancer;
+use crate::config::ForwardingStatement;
+use crate::config::ForwardingTarget;
+use crate::config::Overflow;
+use crate::config::Protocol;
+use crate::config::Strategy;
+use crate::health;
+use crate::http;
+use crate::metrics::{self, Meter, MeteredDoor};
+use crate::peer::Peer;
+use crate::tls::{TlsError, TlsSettings};
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::io;
+use std::net;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Traits
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+
+// Macros
+use errors::define_error_enum;
+
+
+define_error_enum!(
+    pub enum RelayError {
+        Io(io::Error),
+        Door(doors::Error),
+        Tls(TlsError),
+        Unsupported(String)
+    }
+);
+
+
+/// The largest UDP payload we are prepared to receive.
+const MAX_DATAGRAM: usize = 65_507;
+
+/// How much of a turned-away HTTP request we will read, so that closing the connection does not
+/// reset it before the client sees our 503.
+const MAX_DISCARD: usize = 16_384;
+
+/// How long we will wait for the rest of a turned-away HTTP request, all told.
+const DISCARD_TIMEOUT: Duration = Duration::from_millis(100);
+
+
+/// A bound socket, ready to receive traffic.
+pub enum Listener {
+    Tcp(net::TcpListener),
+    Udp(net::UdpSocket)
+}
+
+impl Listener {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(listener) => Ok(Self::Tcp(listener.try_clone()?)),
+            Self::Udp(socket) => Ok(Self::Udp(socket.try_clone()?))
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            Self::Udp(socket) => socket.local_addr()
+        }
+    }
+}
+
+
+/// The doors a relay forwards to, by path.
+type Doors = HashMap<PathBuf, Arc<health::Door>>;
+
+/// Everything a relay needs besides its socket: doors, a server config for `tls`, `https`, and
+/// `peer` statements, and a peer for statements which forward to one.
+type Opened = (Doors, Option<Arc<ServerConfig>>, Option<Peer>);
+
+
+/// A forwarding statement whose socket is bound and whose doors are open.
+pub struct Relay {
+    pub statement: ForwardingStatement,
+    listener: Listener,
+    doors: Doors,
+    tls: Option<Arc<ServerConfig>>,
+    /// Where to send traffic, if the statement forwards to a peer
+    peer: Option<Peer>,
+    /// Shared with any relay which replaces this one
+    meter: Arc<Meter>
+}
+
+impl Relay {
+    /// Open the target door(s) and bind the address described by `statement`.
+    ///
+    /// `tcp`, `udp`, and `tls` statements must forward to a door, a pool, or a peer; `peer`
+    /// statements to a door or a pool; and `http` and `https` statements to an Atlas. `tls`,
+    /// `https`, and `peer` statements, and statements which forward to a peer, also need `tls`
+    /// settings; see [`crate::tls::from_config`].
+    pub fn bind(statement: ForwardingStatement, tls: Option<&TlsSettings>) -> Result<Self, RelayError> {
+        let (doors, tls, peer) = Self::open(&statement, tls)?;
+        let listener = match statement.protocol {
+            Protocol::UDP => Listener::Udp(net::UdpSocket::bind(statement.address)?),
+            _ => Listener::Tcp(net::TcpListener::bind(statement.address)?)
+        };
+        let meter = metrics::listener(statement.protocol.clone(), listener.local_addr()?);
+        Ok(Self{ statement, listener, doors, tls, peer, meter })
+    }
+
+    /// Like [`Relay::bind`], but use a socket which is already bound, such as one inherited from
+    /// a supervisor (see [`crate::activation`]).
+    pub fn adopt(statement: ForwardingStatement, listener: Listener, tls: Option<&TlsSettings>) -> Result<Self, RelayError> {
+        let (doors, tls, peer) = Self::open(&statement, tls)?;
+        if matches!(listener, Listener::Udp(_)) != (statement.protocol == Protocol::UDP) {
+            let problem = format!("{}: {:?} cannot use a socket of the wrong type", statement.address, statement.protocol);
+            return Err(RelayError::Unsupported(problem));
+        }
+        let meter = metrics::listener(statement.protocol.clone(), listener.local_addr()?);
+        Ok(Self{ statement, listener, doors, tls, peer, meter })
+    }
+
+    /// Check that `statement` makes sense, and open its doors.
+    fn open(
+        statement: &ForwardingStatement,
+        tls: Option<&TlsSettings>
+    ) -> Result<Opened, RelayError> {
+        let unsupported = |problem: &str| {
+            Err(RelayError::Unsupported(format!("{}: {:?} {}", statement.address, statement.protocol, problem)))
+        };
+        let door_paths: Vec<PathBuf> = match (&statement.protocol, &statement.target) {
+            (Protocol::TCP | Protocol::UDP | Protocol::TLS | Protocol::Peer, ForwardingTarget::Door(_) | ForwardingTarget::Pool(_))
+            | (Protocol::HTTP | Protocol::HTTPS, ForwardingTarget::Atlas(_)) => {
+                statement.target.doors().into_iter().map(Path::to_path_buf).collect()
+            },
+            (Protocol::TCP | Protocol::UDP | Protocol::TLS, ForwardingTarget::Peer(_)) => vec![],
+            (Protocol::TCP | Protocol::UDP | Protocol::TLS | Protocol::Peer, ForwardingTarget::Atlas(_)) => {
+                return unsupported("can only forward to a door or a pool");
+            },
+            (Protocol::Peer, ForwardingTarget::Peer(_)) => {
+                return unsupported("cannot forward to another peer");
+            },
+            (Protocol::HTTP | Protocol::HTTPS, ForwardingTarget::Door(_) | ForwardingTarget::Pool(_) | ForwardingTarget::Peer(_)) => {
+                return unsupported("can only forward to an Atlas");
+            }
+        };
+
+        let server_config = match &statement.protocol {
+            Protocol::TLS => tls.and_then(|settings| settings.tls.clone()),
+            Protocol::HTTPS => tls.and_then(|settings| settings.https.clone()),
+            Protocol::Peer => tls.and_then(|settings| settings.peer.as_ref()).map(|peer| Arc::clone(&peer.server)),
+            _ => None
+        };
+        if matches!(statement.protocol, Protocol::TLS | Protocol::HTTPS) && server_config.is_none() {
+            return unsupported("needs a certificate, key, and domain");
+        }
+        if statement.protocol == Protocol::Peer && server_config.is_none() {
+            return unsupported("needs a certificate, key, and peers");
+        }
+
+        let peer = match &statement.target {
+            ForwardingTarget::Peer(address) => match tls.and_then(|settings| settings.peer.as_ref()) {
+                Some(settings) => Some(Peer::new(*address, Arc::clone(&settings.client), statement.limits.timeout)),
+                None => return unsupported("to a peer needs a certificate, key, and peers")
+            },
+            _ => None
+        };
+
+        let mut doors = HashMap::new();
+        for path in door_paths {
+            let door = health::open(&path)?;
+            doors.insert(path, door);
+        }
+        Ok((doors, server_config, peer))
+    }
+
+    /// The address this relay is actually listening on.
+    ///
+    /// This differs from the configured address if the configuration asked for port 0.
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start forwarding traffic to the door.
+    ///
+    /// Each accepted TCP connection is handed to a [`DoorAttendant`], which passes the stream
+    /// itself to the door application. Each UDP datagram is handed to a [`DatagramAttendant`],
+    /// which forwards its payload and replies with whatever the door returns. Each HTTP
+    /// connection is handed to an [`HttpAttendant`], which reads the request and routes it. TLS
+    /// connections are decrypted by a [`TlsAttendant`] (or by the `HttpAttendant`, for HTTPS).
+    ///
+    /// Each attendant has as many threads as the statement's `max_inflight`, and a queue as deep
+    /// as its `queue_depth`. Traffic which arrives while the queue is full is turned away as the
+    /// statement's [`Overflow`] says.
+    pub fn start(self) -> Result<Running, RelayError> {
+        let (wake, alarm) = pipe()?;
+        let (closed_sender, closed) = mpsc::channel();
+        let statement = self.statement.clone();
+        let listener = self.listener.try_clone()?;
+        let meter = Arc::clone(&self.meter);
+
+        // Nobody can wait for a connection and a wake-up at the same time if accept() blocks
+        match &self.listener {
+            Listener::Tcp(listener) => listener.set_nonblocking(true)?,
+            Listener::Udp(socket) => socket.set_nonblocking(true)?
+        }
+
+        let join_handle = thread::spawn(move|| self.run(wake, closed_sender));
+        Ok(Running{ statement, listener, alarm, closed, meter, join_handle })
+    }
+
+    /// Forward traffic until the alarm sounds, then close the socket, and wait for the
+    /// attendant to finish any connections it has already accepted.
+    fn run(self, wake: OwnedFd, closed: mpsc::Sender<()>) {
+        let Self{ statement, listener, doors, tls, peer, meter } = self;
+        let address = statement.address;
+        let limits = &statement.limits;
+        let overflow = statement.overflow();
+        let metered = |door: &Arc<health::Door>| {
+            MeteredDoor::new(Arc::clone(door)).with_timeout(limits.timeout)
+        };
+        let pool = || match &statement.target {
+            ForwardingTarget::Pool(pool) => {
+                Balancer::new(pool.strategy, pool.doors.iter().map(|path| metered(&doors[path])).collect())
+            },
+            target => Balancer::new(Strategy::default(), target.doors().into_iter().map(|path| metered(&doors[path])).collect())
+        };
+        let destination = || match &peer {
+            Some(peer) => Destination::Peer(peer.clone()),
+            None => Destination::Pool(pool())
+        };
+
+        match (listener, &statement.target) {
+            (Listener::Tcp(listener), ForwardingTarget::Atlas(atlas)) => {
+                let doors = doors.iter()
+                    .map(|(path, door)| (path.clone(), metered(door)))
+                    .collect();
+                let attendant = HttpAttendant::new(atlas.clone(), doors, tls, Arc::clone(&meter), limits);
+                accept(address, &listener, &wake, &meter, overflow, |stream| attendant.send(stream));
+                drop(listener);
+                let _ = closed.send(());
+                let _ = attendant.join();
+            },
+            (Listener::Tcp(listener), _) if statement.protocol == Protocol::Peer => {
+                let Some(tls) = tls else { return };
+                let attendant = PeerAttendant::new(pool(), tls, Arc::clone(&meter), limits);
+                accept(address, &listener, &wake, &meter, overflow, |stream| attendant.send(stream));
+                drop(listener);
+                let _ = closed.send(());
+                let _ = attendant.join();
+            },
+            (Listener::Tcp(listener), _) => match tls {
+                Some(tls) => {
+                    let attendant = TlsAttendant::new(destination(), tls, Arc::clone(&meter), limits);
+                    accept(address, &listener, &wake, &meter, overflow, |stream| attendant.send(stream));
+                    drop(listener);
+                    let _ = closed.send(());
+                    let _ = attendant.join();
+                },
+                None => {
+                    let attendant = DoorAttendant::new(destination(), Arc::clone(&meter), limits);
+                    accept(address, &listener, &wake, &meter, overflow, |stream| attendant.send(stream));
+                    drop(listener);
+                    let _ = closed.send(());
+                    let _ = attendant.join();
+                }
+            },
+            (Listener::Udp(socket), _) => {
+                let replies = match socket.try_clone() {
+                    Ok(replies) => replies,
+                    Err(e) => {
+                        eprintln!("{}: could not clone socket: {}", address, e);
+                        return;
+                    }
+                };
+                let attendant = DatagramAttendant::new(destination(), replies, Arc::clone(&meter), limits);
+                receive(address, &socket, &wake, &meter, |datagram| attendant.send(datagram));
+                drop(socket);
+                let _ = closed.send(());
+                let _ = attendant.join();
+            }
+        }
+    }
+}
+
+
+/// A relay whose threads have been started.
+///
+/// Dropping this handle stops the relay as well, though without waiting for its socket to close.
+pub struct Running {
+    pub statement: ForwardingStatement,
+    /// Our own reference to the relay's socket, for handing over to a replacement
+    listener: Listener,
+    /// Closing this wakes the relay thread up and tells it to stop
+    alarm: OwnedFd,
+    /// Tells us when the relay thread has closed its socket
+    closed: mpsc::Receiver<()>,
+    meter: Arc<Meter>,
+    join_handle: thread::JoinHandle<()>
+}
+
+impl Running {
+    /// The address this relay is actually listening on.
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// What this relay, and any relay it replaced, has been up to.
+    pub fn meter(&self) -> &Meter {
+        &self.meter
+    }
+
+    /// Build a new relay for `statement` around this relay's socket.
+    ///
+    /// The new relay can be started before this one is stopped, so that the socket is never
+    /// closed, and nothing waiting in its backlog is lost.
+    pub fn reroute(&self, statement: ForwardingStatement, tls: Option<&TlsSettings>) -> Result<Relay, RelayError> {
+        let (doors, tls, peer) = Relay::open(&statement, tls)?;
+        let listener = self.listener.try_clone()?;
+        Ok(Relay{ statement, listener, doors, tls, peer, meter: Arc::clone(&self.meter) })
+    }
+
+    /// Stop accepting new traffic, and wait for the socket to close.
+    ///
+    /// Connections that have already been accepted are finished in the background, and then the
+    /// relay thread exits. Use [`Running::join`] to wait for that, too.
+    pub fn stop(self) -> thread::JoinHandle<()> {
+        let Self{ listener, alarm, closed, join_handle, .. } = self;
+        drop(alarm);
+        let _ = closed.recv();
+        drop(listener);
+        join_handle
+    }
+
+    /// Stop the relay, and wait for every connection it accepted to be finished.
+    pub fn join(self) -> thread::Result<()> {
+        self.stop().join()
+    }
+}
+
+
+/// Create a pipe for waking a relay thread. The relay polls the first descriptor, and wakes up
+/// when the second one is closed.
+pub(crate) fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut descriptors: [RawFd; 2] = [-1, -1];
+    if unsafe{ libc::pipe(descriptors.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe{ (OwnedFd::from_raw_fd(descriptors[0]), OwnedFd::from_raw_fd(descriptors[1])) })
+}
+
+/// Wait until `socket` is readable, or until `wake` is closed. Returns false in the latter case.
+pub(crate) fn ready(socket: &impl AsRawFd, wake: &OwnedFd) -> bool {
+    let mut descriptors = [
+        libc::pollfd{ fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        libc::pollfd{ fd: wake.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+    ];
+    loop {
+        if unsafe{ libc::poll(descriptors.as_mut_ptr(), 2, -1) } == -1 {
+            match io::Error::last_os_error().kind() {
+                io::ErrorKind::Interrupted => continue,
+                _ => return false
+            }
+        }
+        return descriptors[1].revents == 0;
+    }
+}
+
+fn accept<F>(address: net::SocketAddr, listener: &net::TcpListener, wake: &OwnedFd, meter: &Meter, overflow: Overflow, send: F)
+where F: Fn(net::TcpStream) -> Result<(), mpsc::TrySendError<net::TcpStream>> {
+    while ready(listener, wake) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                meter.accept();
+                // Some platforms let accepted sockets inherit the listener's O_NONBLOCK
+                if let Err(e) = stream.set_nonblocking(false) {
+                    eprintln!("{}: could not accept: {}", address, e);
+                    continue;
+                }
+                meter.enqueue();
+                match send(stream) {
+                    Ok(()) => {},
+                    Err(mpsc::TrySendError::Full(stream)) => {
+                        meter.dequeue();
+                        meter.reject();
+                        turn_away(stream, overflow);
+                    },
+                    Err(mpsc::TrySendError::Disconnected(_)) => {
+                        meter.dequeue();
+                        eprintln!("{}: attendant has gone away", address);
+                        break;
+                    }
+                }
+            },
+            // Somebody else got there first, or the client gave up
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(e) => eprintln!("{}: could not accept: {}", address, e)
+        }
+    }
+}
+
+/// Close a connection which the attendant has no room for.
+fn turn_away(stream: net::TcpStream, overflow: Overflow) {
+    match overflow {
+        Overflow::Reset => {
+            // Closing a socket which lingers for zero seconds sends RST instead of FIN
+            let linger = libc::linger{ l_onoff: 1, l_linger: 0 };
+            unsafe{ libc::setsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &linger as *const libc::linger as *const libc::c_void,
+                std::mem::size_of::<libc::linger>() as libc::socklen_t
+            ) };
+        },
+        Overflow::Unavailable => {
+            // Don't let a slow client hold up the relay thread, on either the write or the read
+            let _ = stream.set_nonblocking(true);
+            let mut stream = &stream;
+            let _ = stream.write_all(&http::response(503, &[]));
+            let _ = stream.shutdown(net::Shutdown::Write);
+            let _ = stream.set_nonblocking(false);
+            let deadline = Instant::now() + DISCARD_TIMEOUT;
+            let mut discarded = 0;
+            let mut buffer = [0u8; 4096];
+            while discarded < MAX_DISCARD {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() || stream.set_read_timeout(Some(left)).is_err() {
+                    break;
+                }
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => discarded += read
+                }
+            }
+        },
+        Overflow::Drop => {}
+    }
+}
+
+fn receive<F>(address: net::SocketAddr, socket: &net::UdpSocket, wake: &OwnedFd, meter: &Meter, send: F)
+where F: Fn(Datagram) -> Result<(), mpsc::TrySendError<Datagram>> {
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    while ready(socket, wake) {
+        match socket.recv_from(&mut buffer) {
+            Ok((size, peer)) => {
+                meter.accept();
+                meter.received(size);
+                let datagram = Datagram{ peer, payload: buffer[..size].to_vec() };
+                meter.enqueue();
+                match send(datagram) {
+                    Ok(()) => {},
+                    // UDP has nobody to tell, so the datagram is simply dropped
+                    Err(mpsc::TrySendError::Full(_)) => {
+                        meter.dequeue();
+                        meter.reject();
+                    },
+                    Err(mpsc::TrySendError::Disconnected(_)) => {
+                        meter.dequeue();
+                        eprintln!("{}: attendant has gone away", address);
+                        break;
+                    }
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(e) => eprintln!("{}: could not receive: {}", address, e)
+        }
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use doors::ServerProcedure;
+    use doors::envelope::Envelope;
+    use std::io::{Read, Write};
+    use doors::Descriptor;
+    use std::os::fd::OwnedFd;
+
+    /// Install `P` at `name` in the temp directory, in place of anything left there before.
+    pub fn install<P: ServerProcedure>(name: &str) -> (PathBuf, doors::Server) {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        let server = P::install(path.to_str().unwrap()).unwrap();
+        (path, server)
+    }
+
+    /// Answers every call with nothing at all.
+    pub struct Ping;
+
+    impl ServerProcedure for Ping {
+        fn rust_wrapper(_descriptors: Vec<OwnedFd>, _request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+            (vec![], vec![])
+        }
+    }
+
+    /// Answers every call with its request, envelope and all.
+    pub struct Echo;
+
+    impl ServerProcedure for Echo {
+        fn rust_wrapper(_descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+            (vec![], request.to_vec())
+        }
+    }
+
+    fn greet(descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        let mut stream = net::TcpStream::from(descriptors.into_iter().next().unwrap());
+        let mut name = [0u8; 5];
+        stream.read_exact(&mut name).unwrap();
+        write!(stream, "Hello, {}!", String::from_utf8_lossy(&name)).unwrap();
+        let (envelope, _) = Envelope::decode(request).unwrap();
+        if let Some(server_name) = envelope.server_name {
+            write!(stream, " Welcome to {}.", server_name).unwrap();
+        }
+        (vec![], vec![])
+    }
+    doors::derive_server_procedure!(greet as Greet);
+
+    #[test]
+    fn forwards_tcp_connections_to_a_door() {
+        let (door_path, _server) = install::<Greet>("portunusd_relay_test.93ac1e");
+
+        let statement = format!("forward tcp 127.0.0.1:0 to {}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), None).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _relay = relay.start().unwrap();
+
+        let mut client = net::TcpStream::connect(address).unwrap();
+        client.write_all(b"Crabs").unwrap();
+        let mut greeting = String::new();
+        client.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "Hello, Crabs!");
+    }
+
+    #[test]
+    fn forwards_udp_datagrams_to_a_door() {
+        let (door_path, _server) = install::<Echo>("portunusd_relay_test.c07f42");
+
+        let statement = format!("forward udp 127.0.0.1:0 to {}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), None).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _relay = relay.start().unwrap();
+
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"ping", address).unwrap();
+        let mut buffer = [0u8; 64];
+        let (size, from) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(from, address);
+
+        // The echo door sends back the envelope it was given, followed by the datagram
+        let (envelope, payload) = Envelope::decode(&buffer[..size]).unwrap();
+        assert_eq!(envelope.protocol, Some(doors::envelope::Protocol::Udp));
+        assert_eq!(envelope.peer, Some(client.local_addr().unwrap()));
+        assert_eq!(envelope.local, Some(address));
+        assert_eq!(payload, b"ping");
+    }
+
+    fn first(_descriptors: Vec<OwnedFd>, _request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        (vec![], b"first".to_vec())
+    }
+    doors::derive_server_procedure!(first as First);
+
+    fn second(_descriptors: Vec<OwnedFd>, _request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        (vec![], b"second".to_vec())
+    }
+    doors::derive_server_procedure!(second as Second);
+
+    #[test]
+    fn takes_turns_across_a_pool_of_doors() {
+        let (first_path, _first) = install::<First>("portunusd_relay_test.2a6c90");
+        let (second_path, _second) = install::<Second>("portunusd_relay_test.2a6c91");
+
+        let statement = format!("forward udp 127.0.0.1:0 to {{ pool {} {} }}", first_path.display(), second_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), None).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _relay = relay.start().unwrap();
+
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut replies = vec![];
+        for _ in 0..4 {
+            client.send_to(b"ping", address).unwrap();
+            let mut buffer = [0u8; 64];
+            let size = client.recv(&mut buffer).unwrap();
+            replies.push(String::from_utf8_lossy(&buffer[..size]).into_owned());
+        }
+        assert_eq!(replies, ["first", "second", "first", "second"]);
+    }
+
+    fn hello_http(_descriptors: Vec<OwnedFd>, request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        let (envelope, request) = Envelope::decode(request).unwrap();
+        let request = String::from_utf8_lossy(request);
+        let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+        let greeting = match envelope.server_name {
+            Some(name) => format!("Hello, {} (via {})!", body, name),
+            None => format!("Hello, {}!", body)
+        };
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", greeting.len(), greeting);
+        (vec![], response.into_bytes())
+    }
+    doors::derive_server_procedure!(hello_http as HelloHttp);
+
+    fn http_exchange(address: net::SocketAddr, request: &str) -> String {
+        let mut client = net::TcpStream::connect(address).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn routes_http_requests_through_an_atlas() {
+        let (door_path, _server) = install::<HelloHttp>("portunusd_relay_test.e1d7b5");
+
+        let statement = format!("forward http 127.0.0.1:0 to {{ map POST /hello to {} }}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), None).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _relay = relay.start().unwrap();
+
+        let response = http_exchange(address, "POST /hello/world HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nCrabs\r\n0\r\n\r\n");
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\nHello, Crabs!");
+
+        let response = http_exchange(address, "GET /hello HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\nAllow: POST\r\n"));
+
+        let response = http_exchange(address, "POST /goodbye HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = http_exchange(address, "nonsense\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    /// Settings for `localhost`, and the certificate a client should trust.
+    fn tls_settings(name: &str) -> (TlsSettings, PathBuf) {
+        let (certificate, key) = crate::tls::tests::self_signed(name);
+        let config = format!(
+            "set certificate {}\nset key {}\nset domain localhost\nforward tls 127.0.0.1:0 to /var/run/x.door",
+            certificate.display(), key.display()
+        );
+        (crate::tls::from_config(&config.parse().unwrap()).unwrap().unwrap(), certificate)
+    }
+
+    #[test]
+    fn terminates_tls_for_a_door() {
+        let (door_path, _server) = install::<Greet>("portunusd_relay_test.5b20d8");
+        let (settings, certificate) = tls_settings("portunusd_relay_test.5b20d8");
+
+        let statement = format!("forward tls 127.0.0.1:0 to {}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), Some(&settings)).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _relay = relay.start().unwrap();
+
+        let mut client = crate::tls::tests::connect(address, &certificate, "localhost");
+        client.write_all(b"Crabs").unwrap();
+        let mut greeting = String::new();
+        client.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "Hello, Crabs! Welcome to localhost.");
+    }
+
+    #[test]
+    fn routes_https_requests_through_an_atlas() {
+        let (door_path, _server) = install::<HelloHttp>("portunusd_relay_test.a4c3f0");
+        let (settings, certificate) = tls_settings("portunusd_relay_test.a4c3f0");
+
+        let statement = format!("forward https 127.0.0.1:0 to {{ map POST /hello to {} }}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), Some(&settings)).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _relay = relay.start().unwrap();
+
+        let mut client = crate::tls::tests::connect(address, &certificate, "localhost");
+        client.write_all(b"POST /hello HTTP/1.1\r\nContent-Length: 5\r\n\r\nCrabs").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 29\r\n\r\nHello, Crabs (via localhost)!");
+    }
+
+    /// Wait until `relay` is in the middle of `calls` door calls.
+    fn wait_for_calls(relay: &Running, calls: u64) {
+        while relay.meter().snapshot().active < calls {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn runs_several_attendants_at_once() {
+        let (door_path, _server) = install::<Greet>("portunusd_relay_test.6f01d2");
+
+        let statement = format!("forward tcp 127.0.0.1:0 max_inflight 2 to {}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), None).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _relay = relay.start().unwrap();
+
+        // With only one attendant, the second client would wait for the first to speak
+        let mut first = net::TcpStream::connect(address).unwrap();
+        let mut second = net::TcpStream::connect(address).unwrap();
+        let mut greeting = String::new();
+        second.write_all(b"Squid").unwrap();
+        second.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "Hello, Squid!");
+
+        greeting.clear();
+        first.write_all(b"Crabs").unwrap();
+        first.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "Hello, Crabs!");
+    }
+
+    #[test]
+    fn resets_connections_when_the_queue_is_full() {
+        let (door_path, _server) = install::<Greet>("portunusd_relay_test.d1e550");
+
+        let statement = format!("forward tcp 127.0.0.1:0 queue_depth 1 to {}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), None).unwrap();
+        let address = relay.local_addr().unwrap();
+        let relay = relay.start().unwrap();
+
+        // One connection keeps the attendant busy, and the next one fills the queue
+        let mut busy = net::TcpStream::connect(address).unwrap();
+        wait_for_calls(&relay, 1);
+        let mut queued = net::TcpStream::connect(address).unwrap();
+        let mut rejected = net::TcpStream::connect(address).unwrap();
+        let e = rejected.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(relay.meter().snapshot().rejected, 1);
+
+        for (client, name) in [(&mut busy, "Crabs"), (&mut queued, "Squid")] {
+            let mut greeting = String::new();
+            client.write_all(name.as_bytes()).unwrap();
+            client.read_to_string(&mut greeting).unwrap();
+            assert_eq!(greeting, format!("Hello, {}!", name));
+        }
+    }
+
+    fn stall_http(_descriptors: Vec<OwnedFd>, _request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        thread::sleep(std::time::Duration::from_secs(1));
+        (vec![], b"HTTP/1.1 204 No Content\r\n\r\n".to_vec())
+    }
+    doors::derive_server_procedure!(stall_http as StallHttp);
+
+    #[test]
+    fn answers_503_when_the_queue_is_full() {
+        let (door_path, _server) = install::<StallHttp>("portunusd_relay_test.503b1f");
+
+        let statement = format!("forward http 127.0.0.1:0 queue_depth 1 to {{ map GET / to {} }}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), None).unwrap();
+        let address = relay.local_addr().unwrap();
+        let relay = relay.start().unwrap();
+
+        let busy = thread::spawn(move|| http_exchange(address, "GET / HTTP/1.1\r\n\r\n"));
+        wait_for_calls(&relay, 1);
+        let queued = thread::spawn(move|| http_exchange(address, "GET / HTTP/1.1\r\n\r\n"));
+        while relay.meter().snapshot().queued < 1 {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let mut rejected = net::TcpStream::connect(address).unwrap();
+        let mut response = String::new();
+        rejected.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(relay.meter().snapshot().rejected, 1);
+
+        assert_eq!(busy.join().unwrap(), "HTTP/1.1 204 No Content\r\n\r\n");
+        assert_eq!(queued.join().unwrap(), "HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[test]
+    fn answers_504_when_the_door_is_too_slow() {
+        let (door_path, _server) = install::<StallHttp>("portunusd_relay_test.504c2e");
+
+        let statement = format!("forward http 127.0.0.1:0 timeout 0.1 to {{ map GET / to {} }}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), None).unwrap();
+        let address = relay.local_addr().unwrap();
+        let relay = relay.start().unwrap();
+
+        let response = http_exchange(address, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert_eq!(relay.meter().snapshot().abandoned, 1);
+    }
+
+    #[test]
+    fn hangs_up_when_the_door_is_too_slow() {
+        // Any door that ignores the connection and takes its time will do
+        let (door_path, _server) = install::<StallHttp>("portunusd_relay_test.0b7d43");
+
+        let statement = format!("forward tcp 127.0.0.1:0 timeout 0.1 to {}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), None).unwrap();
+        let address = relay.local_addr().unwrap();
+        let relay = relay.start().unwrap();
+
+        let mut client = net::TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(std::time::Duration::from_millis(500))).unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+        assert!(received.is_empty());
+        assert_eq!(relay.meter().snapshot().abandoned, 1);
+    }
+
+    /// Settings for two hosts which trust each other, and whose certificates are named after them.
+    fn peer_settings(here: &str, there: &str) -> (TlsSettings, TlsSettings) {
+        let (here_certificate, here_key) = crate::tls::tests::self_signed(here);
+        let (there_certificate, there_key) = crate::tls::tests::self_signed(there);
+        let fingerprint = |certificate: &Path| {
+            use rustls::pki_types::pem::PemObject;
+            let der = rustls::pki_types::CertificateDer::from_pem_file(certificate).unwrap();
+            crate::peer::fingerprint(&der)
+        };
+        let settings = |certificate: &Path, key: &Path, other: &Path| {
+            let config = format!(
+                "set certificate {}\nset key {}\nset peers {}\nforward peer 127.0.0.1:0 to /var/run/x.door",
+                certificate.display(), key.display(), fingerprint(other)
+            );
+            crate::tls::from_config(&config.parse().unwrap()).unwrap().unwrap()
+        };
+        (
+            settings(&here_certificate, &here_key, &there_certificate),
+            settings(&there_certificate, &there_key, &here_certificate)
+        )
+    }
+
+    #[test]
+    fn forwards_tcp_connections_to_a_peer() {
+        let (door_path, _server) = install::<Greet>("portunusd_relay_test.e1d7a2");
+        let (here, there) = peer_settings("portunusd_relay_test.e1d7a3", "portunusd_relay_test.e1d7a4");
+
+        let statement = format!("forward peer 127.0.0.1:0 to {}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), Some(&there)).unwrap();
+        let peer = relay.local_addr().unwrap();
+        let _there = relay.start().unwrap();
+
+        let statement = format!("forward tcp 127.0.0.1:0 to peer {}", peer);
+        let relay = Relay::bind(statement.parse().unwrap(), Some(&here)).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _here = relay.start().unwrap();
+
+        let mut client = net::TcpStream::connect(address).unwrap();
+        client.write_all(b"Crabs").unwrap();
+        let mut greeting = String::new();
+        client.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "Hello, Crabs!");
+    }
+
+    #[test]
+    fn forwards_udp_datagrams_to_a_peer() {
+        let (door_path, _server) = install::<Echo>("portunusd_relay_test.e1d7a5");
+        let (here, there) = peer_settings("portunusd_relay_test.e1d7a6", "portunusd_relay_test.e1d7a7");
+
+        let statement = format!("forward peer 127.0.0.1:0 to {}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), Some(&there)).unwrap();
+        let peer = relay.local_addr().unwrap();
+        let _there = relay.start().unwrap();
+
+        let statement = format!("forward udp 127.0.0.1:0 to peer {}", peer);
+        let relay = Relay::bind(statement.parse().unwrap(), Some(&here)).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _here = relay.start().unwrap();
+
+        // The door on the far side still sees who originally sent the datagram
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"ping", address).unwrap();
+        let mut buffer = [0u8; 64];
+        let (size, from) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(from, address);
+        let (envelope, payload) = Envelope::decode(&buffer[..size]).unwrap();
+        assert_eq!(payload, b"ping");
+        assert_eq!(envelope.peer, Some(client.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn refuses_peers_it_does_not_know() {
+        let (door_path, _server) = install::<Greet>("portunusd_relay_test.e1d7a8");
+        let (_, there) = peer_settings("portunusd_relay_test.e1d7a9", "portunusd_relay_test.e1d7aa");
+        let (stranger, _) = peer_settings("portunusd_relay_test.e1d7ab", "portunusd_relay_test.e1d7ac");
+
+        let statement = format!("forward peer 127.0.0.1:0 to {}", door_path.display());
+        let relay = Relay::bind(statement.parse().unwrap(), Some(&there)).unwrap();
+        let peer = relay.local_addr().unwrap();
+        let _there = relay.start().unwrap();
+
+        let statement = format!("forward tcp 127.0.0.1:0 to peer {}", peer);
+        let relay = Relay::bind(statement.parse().unwrap(), Some(&stranger)).unwrap();
+        let address = relay.local_addr().unwrap();
+        let _here = relay.start().unwrap();
+
+        let mut client = net::TcpStream::connect(address).unwrap();
+        let _ = client.write_all(b"Crabs");
+        let mut greeting = String::new();
+        let _ = client.read_to_string(&mut greeting);
+        assert_eq!(greeting, "");
+    }
+
+    #[test]
+    fn refuses_unsupported_statements() {
+        let statement = "forward tcp 127.0.0.1:0 to { map GET / to /var/run/x.door }";
+        assert!(matches!(
+            Relay::bind(statement.parse().unwrap(), None),
+            Err(RelayError::Unsupported(_))
+        ));
+
+        let statement = "forward http 127.0.0.1:0 to /var/run/x.door";
+        assert!(matches!(
+            Relay::bind(statement.parse().unwrap(), None),
+            Err(RelayError::Unsupported(_))
+        ));
+
+        let statement = "forward tls 127.0.0.1:0 to /var/run/x.door";
+        assert!(matches!(
+            Relay::bind(statement.parse().unwrap(), None),
+            Err(RelayError::Unsupported(_))
+        ));
+
+        let statement = "forward tcp 127.0.0.1:0 to peer 127.0.0.1:9443";
+        assert!(matches!(
+            Relay::bind(statement.parse().unwrap(), None),
+            Err(RelayError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,584 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! Relay Supervision
+//!
+//! The [`Supervisor`] keeps track of every running [`Relay`], and knows how to bring them in line
+//! with a new [`Config`]. This is how PortunusD reloads its configuration without restarting:
+//!
+//! * statements which have not changed keep running, untouched;
+//! * statements which bind the same address but forward somewhere else are rerouted: a new relay
+//!   is started on the old socket before the old relay is stopped, so the socket never closes;
+//! * statements which have gone away are stopped; and
+//! * statements which are new are bound and started, unless a socket inherited from whoever
+//!   started PortunusD is already bound for them (see [`crate::activation`]).
+//!
+//! Reloading happens in two steps, just like starting a relay. [`Supervisor::plan`] binds every
+//! new socket and opens every door, but changes nothing; if anything goes wrong, the running
+//! relays stay in service. [`Supervisor::commit`] then swaps the new relays in.
+//!
+//! The supervisor also runs the OpenMetrics [`Exporter`], if the config asks for one, and moves it
+//! whenever `metrics_listen` changes. Likewise, it runs the [`Prober`] which checks on every door
+//! each `probe_interval`.
+//!
+//! When it is time to shut down, [`Supervisor::drain`] stops every relay, and gives connections
+//! which are already underway a little while to finish.
+
+// Types
+use crate::activation::Inherited;
+use crate::config::{Config, ForwardingStatement, ForwardingTarget, Problem, Protocol, DEFAULT_SHUTDOWN_TIMEOUT};
+use crate::health::Prober;
+use crate::metrics::{Exporter, RunningExporter};
+use crate::relay::{Relay, RelayError, Running};
+use crate::tls::{self, TlsError};
+use std::fmt;
+use std::io;
+use std::net;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Macros
+use errors::define_error_enum;
+
+
+define_error_enum!(
+    pub enum SupervisorError {
+        Invalid(Vec<Problem>),
+        Io(io::Error),
+        Relay(RelayError),
+        Tls(TlsError)
+    }
+);
+
+
+/// Everything needed to bring the running relays in line with a new config.
+pub struct Plan {
+    /// One step per statement in the new config, in order
+    steps: Vec<Step>,
+    /// Indices of the running relays which are no longer wanted
+    retire: Vec<usize>,
+    /// `None` to keep the running exporter (or lack of one), or else its replacement
+    exporter: Option<Option<Exporter>>,
+    shutdown_timeout: Duration,
+    /// `None` if doors should not be probed
+    probe_interval: Option<Duration>
+}
+
+enum Step {
+    /// Leave the running relay with this index alone
+    Keep(usize),
+    /// Start this relay on the socket of the running relay with this index, and then stop it
+    Replace(usize, Relay),
+    /// Start a brand new relay
+    Start(Relay)
+}
+
+
+/// What [`Supervisor::commit`] actually did.
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct Changes {
+    pub started: usize,
+    pub rerouted: usize,
+    pub stopped: usize,
+    pub unchanged: usize,
+    /// Relays which could not be started. Any relay they were meant to replace is left running.
+    pub failed: usize
+}
+
+impl fmt::Display for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} started, {} rerouted, {} stopped, {} unchanged, {} failed",
+            self.started, self.rerouted, self.stopped, self.unchanged, self.failed)
+    }
+}
+
+
+/// The owner of every running relay.
+pub struct Supervisor {
+    running: Vec<Running>,
+    exporter: Option<RunningExporter>,
+    shutdown_timeout: Duration,
+    prober: Option<Prober>,
+    /// Sockets bound by somebody else, until the first commit
+    inherited: Vec<Inherited>
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self{
+            running: vec![],
+            exporter: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            prober: None,
+            inherited: vec![]
+        }
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use these sockets, rather than binding new ones, for statements which match them.
+    ///
+    /// Sockets which the next committed plan does not use are closed.
+    pub fn inherit(&mut self, sockets: Vec<Inherited>) {
+        self.inherited.extend(sockets);
+    }
+
+    /// The relays which are currently running, in config file order.
+    pub fn relays(&self) -> &[Running] {
+        &self.running
+    }
+
+    /// The address the OpenMetrics endpoint is actually listening on, if there is one.
+    pub fn metrics_addr(&self) -> Option<net::SocketAddr> {
+        self.exporter.as_ref().map(RunningExporter::local_addr)
+    }
+
+    /// Validate `config`, and prepare every relay it needs.
+    ///
+    /// A running relay is matched with a new statement if they bind the same configured address
+    /// with the same transport protocol (UDP, or TCP for everything else). Matched relays are
+    /// kept if their statements are identical, and rerouted otherwise. Relays which terminate TLS
+    /// or talk to peers are always rerouted, so that they pick up any new certificate or peers.
+    pub fn plan(&self, config: &Config) -> Result<Plan, SupervisorError> {
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(SupervisorError::Invalid(problems));
+        }
+        let tls = tls::from_config(config)?;
+
+        let running = self.exporter.as_ref().map(|exporter| exporter.address);
+        let exporter = match config.metrics_listen() {
+            wanted if wanted == running => None,
+            Some(address) => Some(Some(Exporter::bind(address)?)),
+            None => Some(None)
+        };
+
+        let mut matched = vec![false; self.running.len()];
+        let mut claimed = vec![false; self.inherited.len()];
+        let mut steps = vec![];
+        for statement in &config.statements {
+            let existing = self.running.iter().enumerate()
+                .position(|(index, running)| !matched[index] && same_socket(&running.statement, statement));
+            let step = match existing {
+                Some(index) => {
+                    matched[index] = true;
+                    let running = &self.running[index];
+                    let uses_tls = matches!(statement.protocol, Protocol::TLS | Protocol::HTTPS | Protocol::Peer)
+                        || matches!(statement.target, ForwardingTarget::Peer(_));
+                    if &running.statement == statement && !uses_tls {
+                        Step::Keep(index)
+                    } else {
+                        Step::Replace(index, running.reroute(statement.clone(), tls.as_ref())?)
+                    }
+                },
+                None => {
+                    let inherited = self.inherited.iter().enumerate()
+                        .position(|(index, inherited)| !claimed[index] && inherited.matches(statement));
+                    match inherited {
+                        Some(index) => {
+                            claimed[index] = true;
+                            let listener = self.inherited[index].listener.try_clone()?;
+                            Step::Start(Relay::adopt(statement.clone(), listener, tls.as_ref())?)
+                        },
+                        None => Step::Start(Relay::bind(statement.clone(), tls.as_ref())?)
+                    }
+                }
+            };
+            steps.push(step);
+        }
+
+        let retire = matched.iter().enumerate()
+            .filter(|(_, matched)| !**matched)
+            .map(|(index, _)| index)
+            .collect();
+        Ok(Plan{
+            steps,
+            retire,
+            exporter,
+            shutdown_timeout: config.shutdown_timeout(),
+            probe_interval: config.probe_interval()
+        })
+    }
+
+    /// Start the relays prepared by `plan`, and stop the ones it replaces.
+    ///
+    /// Stopped relays finish any connections they have already accepted in the background.
+    pub fn commit(&mut self, plan: Plan) -> Changes {
+        let mut changes = Changes::default();
+        let mut old: Vec<Option<Running>> = self.running.drain(..).map(Some).collect();
+
+        for step in plan.steps {
+            match step {
+                Step::Keep(index) => {
+                    self.running.extend(old[index].take());
+                    changes.unchanged += 1;
+                },
+                Step::Replace(index, relay) => {
+                    let address = relay.statement.address;
+                    match relay.start() {
+                        Ok(running) => {
+                            self.running.push(running);
+                            if let Some(retired) = old[index].take() {
+                                retired.stop();
+                            }
+                            changes.rerouted += 1;
+                        },
+                        Err(e) => {
+                            eprintln!("{}: could not reroute: {:?}", address, e);
+                            self.running.extend(old[index].take());
+                            changes.failed += 1;
+                        }
+                    }
+                },
+                Step::Start(relay) => {
+                    let address = relay.statement.address;
+                    match relay.start() {
+                        Ok(running) => {
+                            self.running.push(running);
+                            changes.started += 1;
+                        },
+                        Err(e) => {
+                            eprintln!("{}: could not start: {:?}", address, e);
+                            changes.failed += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        for index in plan.retire {
+            if let Some(retired) = old[index].take() {
+                retired.stop();
+                changes.stopped += 1;
+            }
+        }
+        for inherited in self.inherited.drain(..) {
+            if !self.running.iter().any(|running| inherited.matches(&running.statement)) {
+                eprintln!("{}: no statement uses this inherited socket, so closing it", inherited.describe());
+            }
+        }
+        if let Some(exporter) = plan.exporter {
+            if let Some(retired) = self.exporter.take() {
+                retired.stop();
+            }
+            self.exporter = exporter.and_then(|exporter| {
+                let address = exporter.address;
+                exporter.start()
+                    .map_err(|e| eprintln!("{}: could not serve metrics: {}", address, e))
+                    .ok()
+            });
+        }
+        self.shutdown_timeout = plan.shutdown_timeout;
+        if self.prober.as_ref().map(|prober| prober.interval) != plan.probe_interval {
+            if let Some(retired) = self.prober.take() {
+                retired.stop();
+            }
+            self.prober = plan.probe_interval.map(Prober::start);
+        }
+        changes
+    }
+
+    /// Stop every relay, and wait for the connections they have already accepted.
+    ///
+    /// Waits no longer than the config's `shutdown_timeout`. Returns the number of relays which
+    /// were still busy when time ran out; their threads are abandoned.
+    pub fn drain(&mut self) -> usize {
+        let deadline = Instant::now() + self.shutdown_timeout;
+        if let Some(prober) = self.prober.take() {
+            prober.stop();
+        }
+        let mut busy: Vec<thread::JoinHandle<()>> = self.running.drain(..)
+            .map(|running| running.stop())
+            .collect();
+        loop {
+            busy.retain(|relay| !relay.is_finished());
+            if busy.is_empty() || Instant::now() >= deadline {
+                return busy.len();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+
+/// Whether two statements would bind the same socket.
+fn same_socket(a: &ForwardingStatement, b: &ForwardingStatement) -> bool {
+    let datagram = |statement: &ForwardingStatement| statement.protocol == Protocol::UDP;
+    a.address == b.address && datagram(a) == datagram(b)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use doors::ServerProcedure;
+    use std::io::{Read, Write};
+    use std::net;
+    use doors::Descriptor;
+    use std::os::fd::OwnedFd;
+
+    /// Prompt for a name, and then greet it.
+    fn converse(descriptors: Vec<OwnedFd>, door: &str) {
+        let mut stream = net::TcpStream::from(descriptors.into_iter().next().unwrap());
+        write!(stream, "{}? ", door).unwrap();
+        let mut name = [0u8; 5];
+        stream.read_exact(&mut name).unwrap();
+        write!(stream, "{}: Hello, {}!", door, String::from_utf8_lossy(&name)).unwrap();
+    }
+
+    fn alpha(descriptors: Vec<OwnedFd>, _request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        converse(descriptors, "alpha");
+        (vec![], vec![])
+    }
+    doors::derive_server_procedure!(alpha as Alpha);
+
+    fn beta(descriptors: Vec<OwnedFd>, _request: &[u8]) -> (Vec<Descriptor<'static>>, Vec<u8>) {
+        converse(descriptors, "beta");
+        (vec![], vec![])
+    }
+    doors::derive_server_procedure!(beta as Beta);
+
+    fn door(name: &str) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(name);
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_owned()
+    }
+
+    /// Read until we see the prompt, so that we know which door has our connection.
+    fn prompt(client: &mut net::TcpStream) -> String {
+        let mut prompt = vec![];
+        let mut byte = [0u8; 1];
+        while !prompt.ends_with(b"? ") {
+            client.read_exact(&mut byte).unwrap();
+            prompt.push(byte[0]);
+        }
+        String::from_utf8(prompt).unwrap()
+    }
+
+    #[test]
+    fn reloads_without_dropping_connections() {
+        let alpha_path = door("portunusd_supervisor_test.1f9e0a");
+        let _alpha = Alpha::install(&alpha_path).unwrap();
+        let beta_path = door("portunusd_supervisor_test.2c77b4");
+        let _beta = Beta::install(&beta_path).unwrap();
+
+        let mut supervisor = Supervisor::new();
+        let config: Config = format!(
+            "forward tcp 127.0.0.1:0 to {}\nforward udp 127.0.0.1:0 to {}",
+            alpha_path, alpha_path
+        ).parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        let changes = supervisor.commit(plan);
+        assert_eq!(changes, Changes{ started: 2, ..Default::default() });
+        let address = supervisor.relays()[0].local_addr().unwrap();
+
+        // Start a conversation with alpha, but don't finish it yet
+        let mut early = net::TcpStream::connect(address).unwrap();
+        assert_eq!(prompt(&mut early), "alpha? ");
+
+        // Point the TCP statement at beta instead, and drop the UDP statement
+        let config: Config = format!("forward tcp 127.0.0.1:0 to {}", beta_path).parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        let changes = supervisor.commit(plan);
+        assert_eq!(changes, Changes{ rerouted: 1, stopped: 1, ..Default::default() });
+        assert_eq!(supervisor.relays()[0].local_addr().unwrap(), address);
+
+        // New connections go to beta, on the same socket
+        let mut late = net::TcpStream::connect(address).unwrap();
+        assert_eq!(prompt(&mut late), "beta? ");
+        late.write_all(b"Crabs").unwrap();
+        let mut greeting = String::new();
+        late.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "beta: Hello, Crabs!");
+
+        // ...but the conversation with alpha carries on
+        early.write_all(b"Crabs").unwrap();
+        let mut greeting = String::new();
+        early.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "alpha: Hello, Crabs!");
+
+        // Reloading the same config again changes nothing
+        let plan = supervisor.plan(&config).unwrap();
+        let changes = supervisor.commit(plan);
+        assert_eq!(changes, Changes{ unchanged: 1, ..Default::default() });
+
+        // Once every relay is gone, its attendants wind down too
+        let plan = supervisor.plan(&"".parse().unwrap()).unwrap();
+        supervisor.commit(plan);
+        assert!(supervisor.relays().is_empty());
+    }
+
+    #[test]
+    fn stopped_relays_finish_their_connections() {
+        let alpha_path = door("portunusd_supervisor_test.4b61d2");
+        let _alpha = Alpha::install(&alpha_path).unwrap();
+
+        let mut supervisor = Supervisor::new();
+        let config: Config = format!("forward tcp 127.0.0.1:0 to {}", alpha_path).parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        supervisor.commit(plan);
+        let address = supervisor.relays()[0].local_addr().unwrap();
+
+        let mut early = net::TcpStream::connect(address).unwrap();
+        assert_eq!(prompt(&mut early), "alpha? ");
+
+        // Stop the relay from another thread, since it waits for alpha to finish
+        let running = supervisor.running.pop().unwrap();
+        let joined = std::thread::spawn(move|| running.join().is_ok());
+        early.write_all(b"Crabs").unwrap();
+        let mut greeting = String::new();
+        early.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "alpha: Hello, Crabs!");
+        assert!(joined.join().unwrap());
+        assert!(net::TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn draining_waits_for_connections_until_the_deadline() {
+        let alpha_path = door("portunusd_supervisor_test.e55a17");
+        let _alpha = Alpha::install(&alpha_path).unwrap();
+
+        let mut supervisor = Supervisor::new();
+        let config: Config = format!("set shutdown_timeout 0\nforward tcp 127.0.0.1:0 to {}", alpha_path).parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        supervisor.commit(plan);
+        let address = supervisor.relays()[0].local_addr().unwrap();
+
+        // Alpha is waiting for a name that never comes, so this relay can't finish in time
+        let mut stalled = net::TcpStream::connect(address).unwrap();
+        assert_eq!(prompt(&mut stalled), "alpha? ");
+        assert_eq!(supervisor.drain(), 1);
+        assert!(supervisor.relays().is_empty());
+        assert!(net::TcpStream::connect(address).is_err());
+
+        // ...but it is never cut off, either
+        stalled.write_all(b"Crabs").unwrap();
+        let mut greeting = String::new();
+        stalled.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "alpha: Hello, Crabs!");
+
+        // Idle relays are drained right away
+        let config: Config = format!("forward tcp 127.0.0.1:0 to {}", alpha_path).parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        supervisor.commit(plan);
+        assert_eq!(supervisor.drain(), 0);
+    }
+
+    /// Fetch a path from the metrics endpoint, and return the whole response.
+    fn scrape(address: net::SocketAddr, path: &str) -> String {
+        let mut client = net::TcpStream::connect(address).unwrap();
+        write!(client, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_metrics_where_the_config_says() {
+        let alpha_path = door("portunusd_supervisor_test.a90f3c");
+        let _alpha = Alpha::install(&alpha_path).unwrap();
+
+        let mut supervisor = Supervisor::new();
+        let config: Config = format!("set metrics_listen 127.0.0.1:0\nforward tcp 127.0.0.1:0 to {}", alpha_path).parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        supervisor.commit(plan);
+        let address = supervisor.relays()[0].local_addr().unwrap();
+        let metrics = supervisor.metrics_addr().unwrap();
+
+        let mut client = net::TcpStream::connect(address).unwrap();
+        assert_eq!(prompt(&mut client), "alpha? ");
+
+        let response = scrape(metrics, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text"));
+        let accepted = format!(r#"portunusd_listener_accepted_total{{protocol="tcp",address="{}"}} 1"#, address);
+        assert!(response.lines().any(|line| line == accepted));
+        let active = format!(r#"portunusd_door_active_calls{{door="{}"}} 1"#, alpha_path);
+        assert!(response.lines().any(|line| line == active));
+        assert!(response.ends_with("# EOF\n"));
+        assert!(scrape(metrics, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // Reloading the same config leaves the endpoint alone, and dropping the parameter stops it
+        let plan = supervisor.plan(&config).unwrap();
+        supervisor.commit(plan);
+        assert_eq!(supervisor.metrics_addr(), Some(metrics));
+        let config: Config = format!("forward tcp 127.0.0.1:0 to {}", alpha_path).parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        supervisor.commit(plan);
+        assert_eq!(supervisor.metrics_addr(), None);
+        assert!(net::TcpStream::connect(metrics).is_err());
+
+        client.write_all(b"Crabs").unwrap();
+        let mut greeting = String::new();
+        client.read_to_string(&mut greeting).unwrap();
+    }
+
+    #[test]
+    fn bad_configs_leave_the_old_relays_running() {
+        let alpha_path = door("portunusd_supervisor_test.8d0c3e");
+        let _alpha = Alpha::install(&alpha_path).unwrap();
+
+        let mut supervisor = Supervisor::new();
+        let config: Config = format!("forward tcp 127.0.0.1:0 to {}", alpha_path).parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        supervisor.commit(plan);
+        let address = supervisor.relays()[0].local_addr().unwrap();
+
+        let config: Config = format!(
+            "forward tcp 127.0.0.1:7 to {}\nforward tls 127.0.0.1:7 to {}",
+            alpha_path, alpha_path
+        ).parse().unwrap();
+        assert!(matches!(supervisor.plan(&config), Err(SupervisorError::Invalid(_))));
+
+        let config: Config = "forward tcp 127.0.0.1:0 to /nonexistent/portunusd.door".parse().unwrap();
+        assert!(matches!(supervisor.plan(&config), Err(SupervisorError::Relay(_))));
+
+        let mut client = net::TcpStream::connect(address).unwrap();
+        assert_eq!(prompt(&mut client), "alpha? ");
+    }
+
+    #[test]
+    fn uses_inherited_sockets_instead_of_binding() {
+        use std::os::fd::IntoRawFd;
+        let alpha_path = door("portunusd_supervisor_test.7e4b19");
+        let _alpha = Alpha::install(&alpha_path).unwrap();
+
+        // Somebody else bound these, and handed them to us
+        let by_name = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let by_name_address = by_name.local_addr().unwrap();
+        let by_address = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let by_address_address = by_address.local_addr().unwrap();
+        let unclaimed = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let unclaimed_address = unclaimed.local_addr().unwrap();
+        let mut supervisor = Supervisor::new();
+        supervisor.inherit(vec![
+            crate::activation::from_flag(&format!("web={}", by_name.into_raw_fd())).unwrap(),
+            crate::activation::from_flag(&format!("other={}", by_address.into_raw_fd())).unwrap(),
+            crate::activation::from_flag(&format!("spare={}", unclaimed.into_raw_fd())).unwrap()
+        ]);
+
+        let config: Config = format!(
+            "forward tcp 127.0.0.1:0 name web to {}\nforward tcp {} to {}",
+            alpha_path, by_address_address, alpha_path
+        ).parse().unwrap();
+        let plan = supervisor.plan(&config).unwrap();
+        assert_eq!(supervisor.commit(plan).started, 2);
+        assert_eq!(supervisor.relays()[0].local_addr().unwrap(), by_name_address);
+        assert_eq!(supervisor.relays()[1].local_addr().unwrap(), by_address_address);
+
+        for address in [by_name_address, by_address_address] {
+            let mut client = net::TcpStream::connect(address).unwrap();
+            assert_eq!(prompt(&mut client), "alpha? ");
+            client.write_all(b"Crabs").unwrap();
+        }
+        assert!(net::TcpStream::connect(unclaimed_address).is_err());
+    }
+}
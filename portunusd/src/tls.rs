@@ -0,0 +1,367 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2023 Robert D. French
+ */
+//! TLS Termination
+//!
+//! PortunusD terminates TLS itself so that door applications never have to handle key material.
+//! The certificate chain and private key are read (as PEM) from the `certificate` and `key`
+//! [`Parameter`](crate::config::Parameter)s, and only connections for the configured `domain` are
+//! served.
+//!
+//! Doors behind a `tls` statement still receive a stream descriptor, just as they would for `tcp`.
+//! The descriptor is one end of a UNIX socket pair, and a [`splice`] thread shuttles plaintext
+//! between it and the encrypted client connection.
+//!
+//! The same certificate and key also identify this host to its peers; see [`crate::peer`].
+
+// Types
+use crate::config::{Config, ForwardingTarget, Protocol};
+use crate::metrics::Meter;
+use crate::peer::{self, PeerSettings};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Connection, ServerConfig, ServerConnection, StreamOwned};
+use std::io;
+use std::net;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Macros
+use errors::define_error_enum;
+
+// Traits
+use rustls::pki_types::pem::PemObject;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+
+
+define_error_enum!(
+    pub enum TlsError {
+        Io(io::Error),
+        Pem(pem::Error),
+        Rustls(rustls::Error),
+        MissingParameter(String)
+    }
+);
+
+
+/// An encrypted client connection, after the handshake.
+pub type TlsStream = StreamOwned<ServerConnection, net::TcpStream>;
+
+
+/// How long a client may take to complete its handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// Serve one certificate, but only to clients who ask for our domain.
+///
+/// Clients which do not send SNI at all (for example, those connecting by IP address) are given the
+/// benefit of the doubt.
+#[derive(Debug)]
+struct DomainResolver {
+    domain: String,
+    certified_key: Arc<CertifiedKey>
+}
+
+impl ResolvesServerCert for DomainResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        match client_hello.server_name() {
+            Some(name) if !name.eq_ignore_ascii_case(&self.domain) => None,
+            _ => Some(Arc::clone(&self.certified_key))
+        }
+    }
+}
+
+
+/// Build a server configuration from PEM files.
+///
+/// `certificate` should contain the full chain, leaf first. `alpn` lists the application protocols
+/// we are willing to negotiate (for example, `http/1.1`), if any.
+pub fn server_config(certificate: &Path, key: &Path, domain: &str, alpn: &[&[u8]]) -> Result<Arc<ServerConfig>, TlsError> {
+    let chain = CertificateDer::pem_file_iter(certificate)?
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let signing_key = provider.key_provider.load_private_key(key)?;
+    let certified_key = Arc::new(CertifiedKey::new(chain, signing_key));
+    let resolver = DomainResolver{ domain: domain.to_owned(), certified_key };
+
+    let mut config = ServerConfig::builder_with_provider(Arc::clone(&provider) as Arc<CryptoProvider>)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+
+/// The TLS settings needed by a config's `tls`, `https`, and `peer` statements, and by statements
+/// which forward to a peer.
+///
+/// Returns `None` if no statement needs TLS, in which case the `certificate` and `key` parameters
+/// are not required. `domain` is only required for `tls` and `https`, and `peers` only for peers.
+pub fn from_config(config: &Config) -> Result<Option<TlsSettings>, TlsError> {
+    let terminates_tls = config.statements.iter()
+        .any(|statement| matches!(statement.protocol, Protocol::TLS | Protocol::HTTPS));
+    let talks_to_peers = config.statements.iter()
+        .any(|statement| statement.protocol == Protocol::Peer || matches!(statement.target, ForwardingTarget::Peer(_)));
+    if !terminates_tls && !talks_to_peers {
+        return Ok(None);
+    }
+
+    let parameter = |key: &str| config.parameter(key)
+        .ok_or_else(|| TlsError::MissingParameter(key.to_owned()));
+    let certificate = Path::new(parameter("certificate")?);
+    let key = Path::new(parameter("key")?);
+
+    let mut settings = TlsSettings::default();
+    if terminates_tls {
+        let domain = parameter("domain")?;
+        settings.tls = Some(server_config(certificate, key, domain, &[])?);
+        settings.https = Some(server_config(certificate, key, domain, &[b"http/1.1"])?);
+    }
+    if talks_to_peers {
+        parameter("peers")?;
+        settings.peer = Some(peer::settings(certificate, key, config.peers())?);
+    }
+    Ok(Some(settings))
+}
+
+
+/// Server configurations for raw `tls` and for `https`, and mutual TLS for peers.
+///
+/// `tls` and `https` differ only in which ALPN protocols they will negotiate. Each is `None` if no
+/// statement needs it.
+#[derive(Clone,Default)]
+pub struct TlsSettings {
+    pub tls: Option<Arc<ServerConfig>>,
+    pub https: Option<Arc<ServerConfig>>,
+    pub peer: Option<PeerSettings>
+}
+
+
+/// Complete a TLS handshake on a freshly accepted connection.
+pub fn accept(config: &Arc<ServerConfig>, mut stream: net::TcpStream) -> Result<TlsStream, TlsError> {
+    let previous_timeout = stream.read_timeout()?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut connection = ServerConnection::new(Arc::clone(config))?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    stream.set_read_timeout(previous_timeout)?;
+    Ok(StreamOwned::new(connection, stream))
+}
+
+
+/// Shuttle plaintext between a TLS connection and a local socket until the local side hangs up.
+///
+/// This runs in its own thread, so that the door application can treat its end of `local` just
+/// like a `tcp` connection. Plaintext is counted in `meter` as it passes through: bytes from the
+/// TLS side as received, and bytes from the local side as sent.
+///
+/// The TLS side may be either a server or a client connection, and the local side may be any
+/// socket (for example, one end of a UNIX socket pair, or a plain TCP connection).
+pub fn splice<C, L>(tls: StreamOwned<C, net::TcpStream>, local: L, meter: Arc<Meter>) -> thread::JoinHandle<()>
+where C: Into<Connection> + Send + 'static, L: Read + Write + AsRawFd + Send + 'static {
+    thread::spawn(move|| shuttle(tls, local, &meter))
+}
+
+/// Like [`splice`], but on the current thread, returning once the local side hangs up.
+pub fn shuttle<C, L>(tls: StreamOwned<C, net::TcpStream>, mut local: L, meter: &Meter)
+where C: Into<Connection>, L: Read + Write + AsRawFd {
+    let (mut connection, mut network) = (tls.conn.into(), tls.sock);
+    if let Err(e) = pump(&mut connection, &mut network, &mut local, meter) {
+        if e.kind() != io::ErrorKind::BrokenPipe && e.kind() != io::ErrorKind::ConnectionReset {
+            eprintln!("TLS error: {}", e);
+        }
+    }
+    let _ = network.shutdown(net::Shutdown::Both);
+}
+
+fn pump<L: Read + Write + AsRawFd>(connection: &mut Connection, network: &mut net::TcpStream, local: &mut L, meter: &Meter) -> io::Result<()> {
+    let mut buffer = vec![0u8; 16 * 1024];
+    let mut network_open = !drain(connection, local, &mut buffer, meter)?;
+
+    loop {
+        let mut descriptors = [
+            libc::pollfd{ fd: network.as_raw_fd(), events: if network_open { libc::POLLIN } else { 0 }, revents: 0 },
+            libc::pollfd{ fd: local.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+        ];
+        if unsafe{ libc::poll(descriptors.as_mut_ptr(), 2, -1) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        // Client to door
+        if network_open && descriptors[0].revents != 0 {
+            match connection.read_tls(network) {
+                Ok(0) => {
+                    network_open = false;
+                    shutdown_write(local)?;
+                },
+                Ok(_) => {
+                    connection.process_new_packets().map_err(io::Error::other)?;
+                    if drain(connection, local, &mut buffer, meter)? {
+                        network_open = false;
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+
+        // Door to client
+        if descriptors[1].revents != 0 {
+            match local.read(&mut buffer)? {
+                0 => {
+                    connection.send_close_notify();
+                    connection.complete_io(network)?;
+                    return Ok(());
+                },
+                size => {
+                    connection.writer().write_all(&buffer[..size])?;
+                    meter.sent(size);
+                }
+            }
+        }
+
+        while connection.wants_write() {
+            connection.write_tls(network)?;
+        }
+    }
+}
+
+/// Move any decrypted bytes to the local socket. Returns true once the client has finished sending.
+fn drain<L: Write + AsRawFd>(connection: &mut Connection, local: &mut L, buffer: &mut [u8], meter: &Meter) -> io::Result<bool> {
+    loop {
+        match connection.reader().read(buffer) {
+            Ok(0) => {
+                shutdown_write(local)?;
+                return Ok(true);
+            },
+            Ok(size) => {
+                local.write_all(&buffer[..size])?;
+                meter.received(size);
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Tell the local side that nothing more is coming, whatever kind of socket it is.
+fn shutdown_write(local: &impl AsRawFd) -> io::Result<()> {
+    match unsafe{ libc::shutdown(local.as_raw_fd(), libc::SHUT_WR) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(())
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+
+    /// Write a self-signed certificate and key for `localhost` into the temp directory.
+    pub fn self_signed(name: &str) -> (PathBuf, PathBuf) {
+        let rcgen::CertifiedKey{ cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let mut certificate = std::env::temp_dir();
+        certificate.push(format!("{}.crt", name));
+        let mut key = std::env::temp_dir();
+        key.push(format!("{}.key", name));
+        std::fs::write(&certificate, cert.pem()).unwrap();
+        std::fs::write(&key, key_pair.serialize_pem()).unwrap();
+        (certificate, key)
+    }
+
+    /// A client configuration which trusts only the given certificate.
+    pub fn client_config(certificate: &Path) -> Arc<rustls::ClientConfig> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(certificate).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Arc::new(config)
+    }
+
+    /// Connect to a TLS server on localhost.
+    pub fn connect(address: net::SocketAddr, certificate: &Path, domain: &str) -> StreamOwned<rustls::ClientConnection, net::TcpStream> {
+        let name = rustls::pki_types::ServerName::try_from(domain.to_owned()).unwrap();
+        let connection = rustls::ClientConnection::new(client_config(certificate), name).unwrap();
+        let stream = net::TcpStream::connect(address).unwrap();
+        StreamOwned::new(connection, stream)
+    }
+
+    #[test]
+    fn refuses_missing_parameters() {
+        let config: Config = "forward tls 127.0.0.1:0 to /var/run/echo.door".parse().unwrap();
+        assert!(matches!(from_config(&config), Err(TlsError::MissingParameter(p)) if p == "certificate"));
+
+        let config: Config = "forward tcp 127.0.0.1:0 to /var/run/echo.door".parse().unwrap();
+        assert!(from_config(&config).unwrap().is_none());
+    }
+
+    #[test]
+    fn splices_plaintext_through_a_local_socket() {
+        let (certificate, key) = self_signed("portunusd_tls_test.6f1a09");
+        let config = server_config(&certificate, &key, "localhost", &[]).unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            let tls = accept(&config, stream).unwrap();
+            let (ours, mut theirs) = UnixStream::pair().unwrap();
+            splice(tls, ours, Arc::default());
+
+            // Pretend to be a door application: shout back whatever we hear
+            let mut request = [0u8; 5];
+            theirs.read_exact(&mut request).unwrap();
+            theirs.write_all(&request.to_ascii_uppercase()).unwrap();
+        });
+
+        let mut client = connect(address, &certificate, "localhost");
+        client.write_all(b"hello").unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"HELLO");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn refuses_other_domains() {
+        let (certificate, key) = self_signed("portunusd_tls_test.1be9d3");
+        let config = server_config(&certificate, &key, "portunusd.net", &[]).unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            assert!(accept(&config, stream).is_err());
+        });
+
+        let mut client = connect(address, &certificate, "localhost");
+        assert!(client.write_all(b"hello").and_then(|_| client.flush()).is_err());
+        server.join().unwrap();
+    }
+}